@@ -0,0 +1,151 @@
+//! Compares the previous `Vec` merge based book side with the ordered map
+//! based `order_book::Side` on Binance-like depth deltas.
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use market_data::common::{Level, Price, Qty};
+use market_data::lob::order_book::Side;
+use market_data::structure;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::cmp::Ordering;
+
+const DEPTH: usize = 1000;
+const DELTAS: usize = 1000;
+const TICK: i64 = 10_000_000; // 0.1
+const MID: i64 = 600_000 * TICK;
+
+/// Level storage used before the switch to `BTreeMap`, kept as a baseline.
+struct VecSide {
+    levels: Vec<Level>,
+    side: structure::Side,
+    limit: usize,
+}
+
+impl VecSide {
+    fn update_diff(&mut self, lvl: Vec<Level>) {
+        let cmp = |x: &Level, y: &Level| match self.side {
+            structure::Side::Buy => y.price.cmp(&x.price),
+            structure::Side::Sell => x.price.cmp(&y.price),
+        };
+        let mut it1 = self.levels.iter().peekable();
+        let mut it2 = lvl.iter().peekable();
+
+        let mut new_levels = Vec::new();
+        while let (Some(&l1), Some(&l2)) = (it1.peek(), it2.peek()) {
+            let (lvl, next1, next2) = match cmp(l1, l2) {
+                Ordering::Less => (l1, true, false),
+                Ordering::Equal => (l2, true, true),
+                Ordering::Greater => (l2, false, true),
+            };
+            if !lvl.qty.is_zero() {
+                new_levels.push(lvl.clone());
+            }
+            if next1 {
+                it1.next();
+            }
+            if next2 {
+                it2.next();
+            }
+        }
+        new_levels.extend(it1.cloned());
+        new_levels.extend(it2.filter(|x| !x.qty.is_zero()).cloned());
+        new_levels.truncate(self.limit);
+        self.levels = new_levels;
+    }
+}
+
+fn level(ticks: i64, rng: &mut StdRng) -> Level {
+    Level::new(
+        Price(ticks * TICK),
+        Qty(rng.gen_range(1..1_000) * 1_000_000),
+    )
+}
+
+fn initial_side(side: structure::Side, rng: &mut StdRng) -> Vec<Level> {
+    (1..=DEPTH as i64)
+        .map(|i| match side {
+            structure::Side::Buy => level(MID / TICK - i, rng),
+            structure::Side::Sell => level(MID / TICK + i, rng),
+        })
+        .collect()
+}
+
+/// Deltas concentrated near the top of the book with a drifting mid price,
+/// about a fifth of the levels are removals, as in recorded Binance streams.
+fn deltas(side: structure::Side, rng: &mut StdRng) -> Vec<Vec<Level>> {
+    let mut mid = MID / TICK;
+    (0..DELTAS)
+        .map(|_| {
+            mid += rng.gen_range(-1..=1);
+            let mut ticks: Vec<i64> = (0..rng.gen_range(1..20))
+                .map(|_| match side {
+                    structure::Side::Buy => mid - rng.gen_range(1..100),
+                    structure::Side::Sell => mid + rng.gen_range(1..100),
+                })
+                .collect();
+            ticks.sort_unstable();
+            ticks.dedup();
+            if side == structure::Side::Buy {
+                ticks.reverse();
+            }
+            ticks
+                .into_iter()
+                .map(|t| {
+                    let mut lvl = level(t, rng);
+                    if rng.gen_bool(0.2) {
+                        lvl.qty = Qty(0);
+                    }
+                    lvl
+                })
+                .collect()
+        })
+        .collect()
+}
+
+fn bench_side(c: &mut Criterion) {
+    let mut rng = StdRng::seed_from_u64(42);
+    for side in [structure::Side::Buy, structure::Side::Sell] {
+        let initial = initial_side(side.clone(), &mut rng);
+        let updates = deltas(side.clone(), &mut rng);
+        let mut group = c.benchmark_group(format!("side_update_{:?}", side));
+
+        group.bench_function("vec", |b| {
+            b.iter_batched(
+                || {
+                    let side = VecSide {
+                        levels: initial.clone(),
+                        side: side.clone(),
+                        limit: DEPTH,
+                    };
+                    (side, updates.clone())
+                },
+                |(mut book, updates)| {
+                    for upd in updates {
+                        book.update_diff(upd);
+                    }
+                    book
+                },
+                BatchSize::LargeInput,
+            )
+        });
+        group.bench_function("btree", |b| {
+            b.iter_batched(
+                || {
+                    let book = Side::from_vec(initial.clone(), side.clone(), Price(TICK), DEPTH)
+                        .expect("levels are on the tick grid");
+                    (book, updates.clone())
+                },
+                |(mut book, updates)| {
+                    for upd in updates {
+                        book.update_diff(upd).expect("levels are on the tick grid");
+                    }
+                    book
+                },
+                BatchSize::LargeInput,
+            )
+        });
+        group.finish();
+    }
+}
+
+criterion_group!(benches, bench_side);
+criterion_main!(benches);
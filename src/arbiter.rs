@@ -0,0 +1,189 @@
+use crate::structure::MDResponse;
+use log::info;
+use std::collections::{BTreeSet, HashMap};
+
+pub type ConnId = usize;
+
+/// Number of recent update ids remembered per instrument and stream.
+const WINDOW: usize = 1024;
+
+#[derive(Default, Debug, Clone)]
+pub struct ConnStats {
+    /// Updates received from the connection
+    pub received: u64,
+    /// Updates, for which the connection was the first one
+    pub won: u64,
+}
+
+/// Recently forwarded update ids of a single stream of an instrument.
+#[derive(Default)]
+struct SeenIds {
+    ids: BTreeSet<u64>,
+}
+
+impl SeenIds {
+    /// Returns true if id is seen for the first time.
+    fn insert(&mut self, id: u64) -> bool {
+        let too_old = self.ids.len() >= WINDOW && self.ids.first().is_some_and(|min| id < *min);
+        if too_old || !self.ids.insert(id) {
+            return false;
+        }
+        if self.ids.len() > WINDOW {
+            self.ids.pop_first();
+        }
+        true
+    }
+}
+
+/// Takes the first arrival of every update among redundant connections
+/// subscribed to the same streams and drops the rest.
+pub struct Arbiter {
+    deltas: HashMap<String, SeenIds>,
+    trades: HashMap<String, SeenIds>,
+    /// Connections, which delivered depth updates of an instrument since they reconnected
+    live: HashMap<String, BTreeSet<ConnId>>,
+    stats: Vec<ConnStats>,
+}
+
+impl Arbiter {
+    pub fn new(num_conn: usize) -> Arbiter {
+        Arbiter {
+            deltas: HashMap::new(),
+            trades: HashMap::new(),
+            live: HashMap::new(),
+            stats: vec![ConnStats::default(); num_conn],
+        }
+    }
+
+    fn is_first(seen: &mut HashMap<String, SeenIds>, raw: &String, id: u64) -> bool {
+        match seen.get_mut(raw) {
+            Some(ids) => ids.insert(id),
+            None => seen.entry(raw.clone()).or_default().insert(id),
+        }
+    }
+
+    /// Resync after reconnection is needed only if no other connection delivers the stream,
+    /// otherwise the book continues from their updates without a gap.
+    fn accept_resync(&mut self, conn: ConnId, raw: &String) -> bool {
+        match self.live.get_mut(raw) {
+            Some(live) => {
+                live.remove(&conn);
+                live.is_empty()
+            }
+            None => true,
+        }
+    }
+
+    /// Returns true if the update should be passed further.
+    pub fn accept(&mut self, conn: ConnId, resp: &MDResponse) -> bool {
+        let first = match resp {
+            MDResponse::Delta(delta) => {
+                let raw = delta.inst.to_raw_string();
+                match self.live.get_mut(raw) {
+                    Some(live) => live.insert(conn),
+                    None => self.live.entry(raw.clone()).or_default().insert(conn),
+                };
+                Self::is_first(&mut self.deltas, raw, delta.last.0)
+            }
+            MDResponse::Trade(trade) => {
+                Self::is_first(&mut self.trades, trade.inst.to_raw_string(), trade.last.0)
+            }
+            MDResponse::Resync(inst) => return self.accept_resync(conn, inst.to_raw_string()),
+            _ => return true,
+        };
+        if conn >= self.stats.len() {
+            self.stats.resize(conn + 1, ConnStats::default());
+        }
+        let stats = &mut self.stats[conn];
+        stats.received += 1;
+        if first {
+            stats.won += 1;
+        }
+        first
+    }
+
+    #[cfg(test)]
+    pub fn stats(&self) -> &[ConnStats] {
+        &self.stats
+    }
+
+    pub fn log_stats(&self) {
+        for (conn, stats) in self.stats.iter().enumerate() {
+            info!(
+                "Connection {}: won {} of {} updates",
+                conn, stats.won, stats.received
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::arbiter::Arbiter;
+    use crate::common::{Id, Level};
+    use crate::structure::{Delta, MDResponse, Side, Trade};
+    use crate::test_support::inst;
+
+    fn delta(last: u64) -> MDResponse {
+        MDResponse::Delta(Delta::new(
+            inst(0.01, 0.1),
+            vec![],
+            vec![],
+            Id(last - 1),
+            Id(last),
+            Id(last - 2),
+            0,
+        ))
+    }
+
+    #[test]
+    fn first_arrival_wins() {
+        let mut arbiter = Arbiter::new(2);
+        assert!(arbiter.accept(0, &delta(10)));
+        assert!(!arbiter.accept(1, &delta(10)));
+        assert!(arbiter.accept(1, &delta(12)));
+        // fills the gap of the faster connection
+        assert!(arbiter.accept(1, &delta(11)));
+        assert!(!arbiter.accept(0, &delta(11)));
+        assert!(!arbiter.accept(0, &delta(12)));
+
+        let trade = MDResponse::Trade(Trade::new(
+            inst(0.01, 0.1),
+            Level::default(),
+            Side::Buy,
+            Id(10),
+            Id(10),
+            0,
+        ));
+        assert!(arbiter.accept(0, &trade));
+        assert!(!arbiter.accept(1, &trade));
+        // connection 0 still delivers the stream
+        assert!(!arbiter.accept(1, &MDResponse::Resync(inst(0.01, 0.1))));
+
+        let stats = arbiter.stats();
+        assert_eq!((stats[0].won, stats[0].received), (2, 4));
+        assert_eq!((stats[1].won, stats[1].received), (2, 4));
+    }
+
+    #[test]
+    fn resync_without_live_connections() {
+        let resync = MDResponse::Resync(inst(0.01, 0.1));
+        let mut arbiter = Arbiter::new(2);
+        // no updates yet
+        assert!(arbiter.accept(0, &resync));
+
+        assert!(arbiter.accept(0, &delta(10)));
+        assert!(!arbiter.accept(1, &delta(10)));
+        // connection 1 reconnects, while connection 0 keeps streaming
+        assert!(!arbiter.accept(1, &resync));
+        assert!(arbiter.accept(0, &delta(11)));
+        assert!(arbiter.accept(0, &delta(12)));
+        // connection 0 reconnects too before connection 1 resumes
+        assert!(arbiter.accept(0, &resync));
+
+        assert!(!arbiter.accept(1, &delta(12)));
+        assert!(arbiter.accept(1, &delta(13)));
+        // the only one, which resumed, reconnects again
+        assert!(arbiter.accept(1, &resync));
+    }
+}
@@ -0,0 +1,354 @@
+use crate::common::{Id, Level, Price, Qty};
+use crate::config::AuditConfig;
+use crate::error::Result;
+use crate::lob::order_book::{OrderBook, Side};
+use crate::lob::orderbooks::DepthBookManager;
+use crate::runner::Runner;
+use crate::snapshot::HTTPExchanges;
+use crate::structure;
+use crate::structure::{Instrument, MDResponse, Snapshot};
+use log::{info, warn};
+use std::cmp::Ordering;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+
+/// Level, which quantity differs in the local book and the exchange snapshot.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mismatch {
+    pub side: structure::Side,
+    pub price: Price,
+    pub local: Qty,
+    pub exchange: Qty,
+}
+
+/// Levels of two sides, which differ within the price range covered by both of them,
+/// as the local side may miss levels pushed out of the depth limit earlier.
+pub fn mismatches(side: structure::Side, local: &Side, exchange: &Side) -> Vec<Mismatch> {
+    // Less if `a` is better than `b`
+    let order = |a: &Price, b: &Price| match side {
+        structure::Side::Buy => b.cmp(a),
+        structure::Side::Sell => a.cmp(b),
+    };
+    let (Some(local_worst), Some(exchange_worst)) = (local.iter().last(), exchange.iter().last())
+    else {
+        return vec![];
+    };
+    let bound = match order(&local_worst.price, &exchange_worst.price) {
+        Ordering::Less => local_worst.price,
+        _ => exchange_worst.price,
+    };
+    let within = |lvl: &&Level| order(&lvl.price, &bound) != Ordering::Greater;
+    let mut local = local.iter().take_while(within).peekable();
+    let mut exchange = exchange.iter().take_while(within).peekable();
+    let mut res = vec![];
+    let mut mismatch = |price: Price, local: Qty, exchange: Qty| {
+        res.push(Mismatch {
+            side: side.clone(),
+            price,
+            local,
+            exchange,
+        })
+    };
+    loop {
+        let ord = match (local.peek(), exchange.peek()) {
+            (None, None) => break,
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (Some(l), Some(e)) => order(&l.price, &e.price),
+        };
+        match ord {
+            Ordering::Less => {
+                let lvl = local.next().expect("level is peeked");
+                mismatch(lvl.price, lvl.qty, Qty(0));
+            }
+            Ordering::Greater => {
+                let lvl = exchange.next().expect("level is peeked");
+                mismatch(lvl.price, Qty(0), lvl.qty);
+            }
+            Ordering::Equal => {
+                let (l, e) = (local.next(), exchange.next());
+                let (l, e) = (l.expect("level is peeked"), e.expect("level is peeked"));
+                if l.qty != e.qty {
+                    mismatch(l.price, l.qty, e.qty);
+                }
+            }
+        }
+    }
+    res
+}
+
+/// Book of the audited instrument built from the REST snapshot
+/// and the same depth updates as the local one.
+struct Audit {
+    inst: Instrument,
+    shadow: OrderBook,
+    request: JoinHandle<()>,
+}
+
+pub enum AuditEvent {
+    /// Time to audit the next instrument
+    Due,
+    Snapshot(Result<Snapshot>),
+}
+
+/// Periodically compares books with REST snapshots one instrument at a time.
+/// Snapshot is aligned with the local book by applying the same depth updates,
+/// so that both books are compared at the same update id.
+pub struct Auditor {
+    cfg: AuditConfig,
+    exch: HTTPExchanges,
+    next_start: Instant,
+    /// Instruments are audited in turn ordered by raw symbol
+    last: Option<String>,
+    current: Option<Audit>,
+    sender: mpsc::Sender<Result<Snapshot>>,
+    snapshots: mpsc::Receiver<Result<Snapshot>>,
+}
+
+impl Auditor {
+    pub fn new(cfg: AuditConfig, exch: HTTPExchanges) -> Auditor {
+        let (sender, snapshots) = mpsc::channel(1);
+        Auditor {
+            next_start: Instant::now() + Duration::from_secs(cfg.interval_secs),
+            cfg,
+            exch,
+            last: None,
+            current: None,
+            sender,
+            snapshots,
+        }
+    }
+
+    pub async fn wait(&mut self) -> AuditEvent {
+        tokio::select! {
+            Some(snapshot) = self.snapshots.recv() => AuditEvent::Snapshot(snapshot),
+            _ = tokio::time::sleep_until(self.next_start) => AuditEvent::Due,
+        }
+    }
+
+    /// Returns the instrument to resync, if its book differs too much from the snapshot.
+    pub fn handle(&mut self, event: AuditEvent, books: &DepthBookManager) -> Option<Instrument> {
+        match event {
+            AuditEvent::Due => {
+                self.start(books);
+                None
+            }
+            AuditEvent::Snapshot(Err(_)) => {
+                self.abandon("snapshot request failed");
+                None
+            }
+            AuditEvent::Snapshot(Ok(snapshot)) => {
+                let audit = self.current.as_mut()?;
+                if snapshot.inst != audit.inst {
+                    return None;
+                }
+                let _ = audit.shadow.apply(MDResponse::Snapshot(snapshot));
+                if !audit.shadow.is_synced() {
+                    self.abandon("snapshot isn't covered by depth updates");
+                    return None;
+                }
+                let inst = audit.inst.clone();
+                self.check(&inst, books.get(&inst)?)
+            }
+        }
+    }
+
+    fn start(&mut self, books: &DepthBookManager) {
+        self.next_start = Instant::now() + Duration::from_secs(self.cfg.interval_secs);
+        self.abandon("snapshot isn't aligned in time");
+        while self.snapshots.try_recv().is_ok() {}
+
+        let mut synced: Vec<_> = books.iter().filter(|(_, b)| b.is_synced()).collect();
+        synced.sort_by(|(a, _), (b, _)| a.to_raw_string().cmp(b.to_raw_string()));
+        let next = synced
+            .iter()
+            .find(|(inst, _)| Some(inst.to_raw_string()) > self.last.as_ref())
+            .or(synced.first());
+        let Some((inst, _)) = next else {
+            return;
+        };
+        let Some((_, api)) = self.exch.iter().find(|(e, _)| &inst.exchange == e) else {
+            return;
+        };
+        let (api, sender, inst_cl) = (api.clone(), self.sender.clone(), (*inst).clone());
+        let request = tokio::spawn(async move {
+            let snapshot = Runner::request_snapshot(api.as_ref(), &inst_cl).await;
+            let _ = sender.send(snapshot).await;
+        });
+        self.last = Some(inst.to_raw_string().clone());
+        self.current = Some(Audit {
+            inst: (*inst).clone(),
+            shadow: books.empty_book(inst),
+            request,
+        });
+    }
+
+    fn abandon(&mut self, reason: &str) {
+        if let Some(audit) = self.current.take() {
+            warn!(
+                "Audit of {} abandoned: {}",
+                audit.inst.to_raw_string(),
+                reason
+            );
+            audit.request.abort();
+        }
+    }
+
+    /// Pass depth update to the book being audited, before it's applied to the local one.
+    pub fn feed(&mut self, inst: &Instrument, resp: &MDResponse) {
+        let (Some(audit), MDResponse::Delta(delta)) = (&mut self.current, resp) else {
+            return;
+        };
+        if &audit.inst == inst {
+            let _ = audit.shadow.apply(MDResponse::Delta(delta.clone()));
+        }
+    }
+
+    /// Compare the local book with the audited one once they are at the same update id,
+    /// returns the instrument to resync if there are too many mismatches.
+    pub fn check(&mut self, inst: &Instrument, book: &OrderBook) -> Option<Instrument> {
+        let audit = self.current.as_ref().filter(|audit| &audit.inst == inst)?;
+        if !book.is_synced() {
+            self.abandon("book is out of sync");
+            return None;
+        }
+        if !audit.shadow.is_synced()
+            || book.is_trade_adjusted()
+            || audit.shadow.last_applied() != book.last_applied()
+        {
+            return None;
+        }
+        let audit = self.current.take()?;
+        let mut found = mismatches(structure::Side::Buy, book.buy(), audit.shadow.buy());
+        found.extend(mismatches(
+            structure::Side::Sell,
+            book.sell(),
+            audit.shadow.sell(),
+        ));
+        report(inst, book.last_applied(), &found);
+        (found.len() > self.cfg.max_mismatches).then(|| inst.clone())
+    }
+}
+
+fn report(inst: &Instrument, id: &Id, found: &[Mismatch]) {
+    let raw = inst.to_raw_string();
+    if found.is_empty() {
+        info!("Audit of {} at {:?} passed", raw, id);
+        return;
+    }
+    warn!(
+        "Audit of {} at {:?}: {} mismatched levels",
+        raw,
+        id,
+        found.len()
+    );
+    for m in found {
+        warn!(
+            "{} {:?} {}: local {}, exchange {}",
+            raw, m.side, m.price, m.local, m.exchange
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::audit::{mismatches, AuditEvent, Auditor, Mismatch};
+    use crate::common::{Id, Level, Price, Qty};
+    use crate::config::{AuditConfig, BooksConfig};
+    use crate::error::Result;
+    use crate::lob::order_book::Side;
+    use crate::lob::orderbooks::DepthBookManager;
+    use crate::scheme::connector::HTTPApi;
+    use crate::structure;
+    use crate::structure::{Delta, Exchange, Instrument, MDResponse, Snapshot};
+    use crate::test_support::inst;
+    use async_trait::async_trait;
+    use std::sync::Arc;
+
+    /// Exchange, which book differs from the local one at 10
+    struct Api;
+
+    #[async_trait]
+    impl HTTPApi for Api {
+        async fn instrument_info(&self) -> Result<Vec<Instrument>> {
+            Ok(vec![])
+        }
+
+        async fn request_depth_shapshot(&self, inst: Instrument) -> Result<Snapshot> {
+            let buy = vec![Level::from_float_pair(10., 3.)];
+            Ok(Snapshot::new(inst, buy, vec![], Id(1005), 0))
+        }
+    }
+
+    fn side(side: structure::Side, levels: &[(f64, f64)]) -> Side {
+        let levels = levels
+            .iter()
+            .map(|(p, q)| Level::from_float_pair(*p, *q))
+            .collect();
+        Side::from_vec(levels, side, Price::from_f64(0.1), 10).unwrap()
+    }
+
+    #[test]
+    fn level_mismatches() {
+        let buy = structure::Side::Buy;
+        let local = side(buy.clone(), &[(10., 1.), (9.9, 2.), (9.7, 1.)]);
+        // the exchange has more levels than the local book keeps
+        let exchange = side(
+            buy.clone(),
+            &[(10., 1.), (9.9, 3.), (9.8, 1.), (9.7, 1.), (9.6, 5.)],
+        );
+        let mismatch = |price: f64, local: f64, exchange: f64| Mismatch {
+            side: buy.clone(),
+            price: Price::from_f64(price),
+            local: Qty::from_f64(local),
+            exchange: Qty::from_f64(exchange),
+        };
+        assert_eq!(
+            mismatches(buy.clone(), &local, &exchange),
+            vec![mismatch(9.9, 2., 3.), mismatch(9.8, 0., 1.)]
+        );
+
+        let sell = structure::Side::Sell;
+        let local = side(sell.clone(), &[(10.1, 1.), (10.2, 2.), (10.4, 1.)]);
+        let exchange = side(sell.clone(), &[(10.1, 1.), (10.2, 2.)]);
+        assert!(mismatches(sell.clone(), &local, &exchange).is_empty());
+        assert!(mismatches(sell.clone(), &side(sell, &[]), &exchange).is_empty());
+    }
+
+    #[tokio::test]
+    async fn audit_aligned_with_updates() {
+        let inst = inst(0.1, 0.1);
+        let delta = |first: u64, last: u64, buy: Vec<Level>| {
+            MDResponse::Delta(Delta::new(
+                inst.clone(),
+                buy,
+                vec![],
+                Id(first),
+                Id(last),
+                Id(first - 1),
+                0,
+            ))
+        };
+        let mut books = DepthBookManager::new(std::slice::from_ref(&inst), BooksConfig::default());
+        let _ = books.update(&inst, delta(1000, 1000, vec![]));
+        let buy = vec![Level::from_float_pair(10., 1.)];
+        let snapshot = Snapshot::new(inst.clone(), buy, vec![], Id(1000), 0);
+        assert!(books.update(&inst, MDResponse::Snapshot(snapshot)).is_ok());
+
+        let cfg = AuditConfig::default();
+        let mut auditor = Auditor::new(cfg, vec![(Exchange::BINANCE, Arc::new(Api))]);
+        assert_eq!(auditor.handle(AuditEvent::Due, &books), None);
+        // the exchange snapshot is in the middle of the update
+        let upd = delta(1001, 1010, vec![Level::from_float_pair(10.1, 2.)]);
+        auditor.feed(&inst, &upd);
+        let book = books.update(&inst, upd).unwrap();
+        assert_eq!(auditor.check(&inst, book), None);
+
+        let event = auditor.wait().await;
+        assert!(matches!(event, AuditEvent::Snapshot(Ok(_))));
+        assert_eq!(auditor.handle(event, &books), Some(inst));
+        assert!(auditor.current.is_none());
+    }
+}
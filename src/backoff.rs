@@ -0,0 +1,63 @@
+use rand::Rng;
+use std::time::Duration;
+
+/// Exponential backoff with equal jitter between attempts: the delay is
+/// drawn from the upper half of the current ceiling.
+#[derive(Debug)]
+pub(crate) struct Backoff {
+    initial: Duration,
+    max: Duration,
+    attempt: u32,
+}
+
+impl Backoff {
+    pub(crate) fn new(initial: Duration, max: Duration) -> Backoff {
+        Backoff {
+            initial,
+            max,
+            attempt: 0,
+        }
+    }
+
+    /// Upper bound of the next delay, doubles on every attempt up to `max`.
+    fn ceiling(&self) -> Duration {
+        self.initial
+            .saturating_mul(2_u32.saturating_pow(self.attempt))
+            .min(self.max)
+    }
+
+    pub(crate) fn next_delay(&mut self) -> Duration {
+        let ceiling = self.ceiling();
+        self.attempt = self.attempt.saturating_add(1);
+        ceiling.mul_f64(rand::thread_rng().gen_range(0.5..=1.0))
+    }
+
+    #[cfg_attr(not(feature = "binance"), allow(dead_code))]
+    pub(crate) fn reset(&mut self) {
+        self.attempt = 0;
+    }
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff::new(Duration::from_millis(500), Duration::from_secs(30))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backoff::Backoff;
+    use std::time::Duration;
+
+    #[test]
+    fn backoff() {
+        let mut backoff = Backoff::new(Duration::from_millis(100), Duration::from_secs(1));
+        for ceiling in [100, 200, 400, 800, 1000, 1000] {
+            let delay = backoff.next_delay();
+            assert!(delay <= Duration::from_millis(ceiling));
+            assert!(delay >= Duration::from_millis(ceiling / 2));
+        }
+        backoff.reset();
+        assert!(backoff.next_delay() <= Duration::from_millis(100));
+    }
+}
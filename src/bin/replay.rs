@@ -0,0 +1,72 @@
+use clap::{Parser, ValueEnum};
+use market_data::config::MDConfig;
+use market_data::output::OutputFormat;
+use market_data::replay::{read_capture, Replay};
+use market_data::scheme::binance::Api;
+use market_data::sink::{SinkFilter, StdoutSink, SINK_QUEUE_SIZE};
+use market_data::structure::Exchange;
+
+/// Format of stdout output
+#[derive(ValueEnum, Debug, Clone, Copy)]
+enum Output {
+    /// Human readable ladder
+    Text,
+    /// JSON line per update with all levels
+    Json,
+    /// CSV line per update with the best levels only
+    Csv,
+}
+
+impl From<Output> for OutputFormat {
+    fn from(output: Output) -> Self {
+        match output {
+            Output::Text => OutputFormat::Text,
+            Output::Json => OutputFormat::Json,
+            Output::Csv => OutputFormat::Csv,
+        }
+    }
+}
+
+/// Rebuild books from capture files instead of connecting to exchange
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+struct Args {
+    #[arg(
+        required = true,
+        help = "Capture files, records are replayed by receive time"
+    )]
+    captures: Vec<String>,
+    #[arg(short, long, default_value = "src/endpoints.toml")]
+    config_path: String,
+    #[arg(
+        long,
+        value_enum,
+        default_value = "text",
+        help = "Format of stdout output, used if no sinks are configured"
+    )]
+    output: Output,
+}
+
+// the replay pauses the clock, which requires a current thread runtime
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    env_logger::init();
+
+    let args = Args::parse();
+    let cfg = MDConfig::new(args.config_path.clone()).expect("Failed to parse");
+    let records = read_capture(&args.captures).expect("Failed to read capture");
+    let binance_cfg = cfg.get(Exchange::BINANCE).expect("Expected binance config");
+    let binance = Api::new(binance_cfg.clone()).expect("Failed to create binance api");
+
+    let no_sinks = cfg.sinks().is_empty();
+    let mut replay = Replay::new(&binance, cfg);
+    if no_sinks {
+        replay = replay.sink(
+            "stdout",
+            Box::new(StdoutSink::stdout(args.output.into())),
+            SinkFilter::default(),
+            SINK_QUEUE_SIZE,
+        );
+    }
+    replay.run(records).await.expect("Failed to replay");
+}
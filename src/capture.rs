@@ -0,0 +1,320 @@
+use crate::arbiter::ConnId;
+use crate::config::CaptureConfig;
+use crate::structure::Exchange;
+use log::{error, info, warn};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncWriteExt, BufWriter};
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+
+/// Number of records, which may wait for the disk before they are dropped.
+const CAPTURE_QUEUE_SIZE: usize = 65536;
+
+/// Origin of the captured message.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Source {
+    /// WebSocket frame received by the connection and its generation
+    Ws(ConnId, u64),
+    /// REST depth snapshot of the symbol
+    Snapshot(String),
+    /// REST description of instruments
+    ExchangeInfo(Exchange),
+    /// Records dropped before this one as the queue was full,
+    /// the payload is their number
+    Gap,
+    /// Book of the symbol was dropped to be rebuilt from a snapshot, the payload is the reason
+    Resync(String),
+}
+
+/// Raw message with local receive time.
+/// Stored as a line `<time us>\t<kind>\t<conn:generation|symbol|exchange|->\t<message>`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Record {
+    pub time_us: u64,
+    pub source: Source,
+    pub payload: String,
+}
+
+impl Record {
+    pub fn new(source: Source, payload: &str) -> Record {
+        let time_us = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |t| t.as_micros() as u64);
+        Record {
+            time_us,
+            source,
+            // messages are JSON, so line breaks are just whitespaces
+            payload: payload.replace(['\n', '\r'], " "),
+        }
+    }
+
+    pub fn to_line(&self) -> String {
+        let (kind, id) = match &self.source {
+            Source::Ws(conn, generation) => ("ws", format!("{}:{}", conn, generation)),
+            Source::Snapshot(symbol) => ("snapshot", symbol.clone()),
+            Source::ExchangeInfo(exchange) => ("info", format!("{:?}", exchange)),
+            Source::Gap => ("gap", "-".to_string()),
+            Source::Resync(symbol) => ("resync", symbol.clone()),
+        };
+        format!("{}\t{}\t{}\t{}\n", self.time_us, kind, id, self.payload)
+    }
+
+    pub fn parse(line: &str) -> Option<Record> {
+        let mut fields = line.trim_end_matches('\n').splitn(4, '\t');
+        let time_us = fields.next()?.parse().ok()?;
+        let (kind, id) = (fields.next()?, fields.next()?);
+        let source = match kind {
+            "ws" => {
+                let (conn, generation) = id.split_once(':')?;
+                Source::Ws(conn.parse().ok()?, generation.parse().ok()?)
+            }
+            "snapshot" => Source::Snapshot(id.to_string()),
+            "info" => match id {
+                "BINANCE" => Source::ExchangeInfo(Exchange::BINANCE),
+                _ => return None,
+            },
+            "gap" => Source::Gap,
+            "resync" => Source::Resync(id.to_string()),
+            _ => return None,
+        };
+        Some(Record {
+            time_us,
+            source,
+            payload: fields.next()?.to_string(),
+        })
+    }
+}
+
+/// Handle to capture raw messages, which are written to disk in background.
+#[derive(Clone)]
+pub struct Recorder {
+    sender: mpsc::Sender<Record>,
+    dropped: Arc<AtomicU64>,
+    /// Records dropped since the last gap marker
+    gap: Arc<AtomicU64>,
+}
+
+impl Recorder {
+    pub async fn start(cfg: &CaptureConfig) -> io::Result<(Recorder, JoinHandle<()>)> {
+        let mut writer = CaptureWriter::open(cfg).await?;
+        let (sender, mut receiver) = mpsc::channel::<Record>(CAPTURE_QUEUE_SIZE);
+        let handle = tokio::spawn(async move {
+            while let Some(record) = receiver.recv().await {
+                let mut res = writer.write(&record).await;
+                while let (true, Ok(record)) = (res.is_ok(), receiver.try_recv()) {
+                    res = writer.write(&record).await;
+                }
+                if let Err(err) = res.and(writer.flush().await) {
+                    error!("Failed to write capture: {}", err);
+                }
+            }
+            let _ = writer.flush().await;
+        });
+        Ok((Self::new(sender), handle))
+    }
+
+    fn new(sender: mpsc::Sender<Record>) -> Recorder {
+        Recorder {
+            sender,
+            dropped: Arc::new(AtomicU64::new(0)),
+            gap: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Never waits, the record is dropped if the disk doesn't keep up.
+    /// Dropped records are marked by a gap record before the next written one.
+    pub fn record(&self, source: Source, payload: &str) {
+        let gap = self.gap.load(Ordering::Relaxed);
+        if gap > 0 {
+            match self
+                .sender
+                .try_send(Record::new(Source::Gap, &gap.to_string()))
+            {
+                Ok(()) => {
+                    self.gap.fetch_sub(gap, Ordering::Relaxed);
+                }
+                Err(TrySendError::Full(_)) => return self.drop_record(),
+                Err(TrySendError::Closed(_)) => return,
+            }
+        }
+        match self.sender.try_send(Record::new(source, payload)) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => self.drop_record(),
+            Err(TrySendError::Closed(_)) => {}
+        }
+    }
+
+    fn drop_record(&self) {
+        let total = self.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+        if self.gap.fetch_add(1, Ordering::Relaxed) == 0 {
+            warn!(
+                "Capture queue is full, records are dropped, {} in total",
+                total
+            );
+        }
+    }
+
+    /// Number of records lost due to overflow.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+/// Appends records to `capture-<unix time>.tsv` files in the directory,
+/// a new file is started once the current one exceeds size or age limit.
+struct CaptureWriter {
+    dir: PathBuf,
+    max_bytes: Option<u64>,
+    max_age: Option<Duration>,
+    file: BufWriter<File>,
+    written: u64,
+    opened: Instant,
+}
+
+impl CaptureWriter {
+    async fn open(cfg: &CaptureConfig) -> io::Result<CaptureWriter> {
+        let dir = PathBuf::from(&cfg.dir);
+        tokio::fs::create_dir_all(&dir).await?;
+        Ok(CaptureWriter {
+            file: Self::new_file(&dir).await?,
+            dir,
+            max_bytes: cfg.max_bytes,
+            max_age: cfg.rotate_secs.map(Duration::from_secs),
+            written: 0,
+            opened: Instant::now(),
+        })
+    }
+
+    async fn new_file(dir: &Path) -> io::Result<BufWriter<File>> {
+        let start = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |t| t.as_secs());
+        // several files could be started within a second
+        let mut num = 0;
+        let path = loop {
+            let name = match num {
+                0 => format!("capture-{}.tsv", start),
+                _ => format!("capture-{}-{}.tsv", start, num),
+            };
+            let path = dir.join(name);
+            if !tokio::fs::try_exists(&path).await? {
+                break path;
+            }
+            num += 1;
+        };
+        info!("Capture to {}", path.display());
+        let file = OpenOptions::new()
+            .create_new(true)
+            .append(true)
+            .open(path)
+            .await?;
+        Ok(BufWriter::new(file))
+    }
+
+    fn should_rotate(&self) -> bool {
+        self.max_bytes.is_some_and(|max| self.written >= max)
+            || self.max_age.is_some_and(|age| self.opened.elapsed() >= age)
+    }
+
+    async fn write(&mut self, record: &Record) -> io::Result<()> {
+        if self.should_rotate() {
+            self.file.flush().await?;
+            self.file = Self::new_file(&self.dir).await?;
+            self.written = 0;
+            self.opened = Instant::now();
+        }
+        let line = record.to_line();
+        self.file.write_all(line.as_bytes()).await?;
+        self.written += line.len() as u64;
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> io::Result<()> {
+        self.file.flush().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::capture::{Record, Recorder, Source};
+    use crate::config::CaptureConfig;
+    use crate::structure::Exchange;
+    use tokio::sync::mpsc;
+
+    #[test]
+    fn record_line() {
+        let ws = Record::new(Source::Ws(2, 1), "{\"e\":\n\"depthUpdate\"}");
+        let line = ws.to_line();
+        assert_eq!(line.lines().count(), 1);
+        assert_eq!(Record::parse(&line), Some(ws));
+
+        let snapshot = Record::new(Source::Snapshot("BTCUSDT".into()), "{\"a\":\t1}");
+        assert_eq!(Record::parse(&snapshot.to_line()), Some(snapshot));
+        let info = Record::new(Source::ExchangeInfo(Exchange::BINANCE), "{}");
+        assert_eq!(Record::parse(&info.to_line()), Some(info));
+        let gap = Record::new(Source::Gap, "3");
+        assert_eq!(Record::parse(&gap.to_line()), Some(gap));
+        let resync = Record::new(Source::Resync("BTCUSDT".into()), "audit");
+        assert_eq!(Record::parse(&resync.to_line()), Some(resync));
+        assert_eq!(Record::parse("1\tunknown\t1\t{}"), None);
+    }
+
+    #[test]
+    fn gap_marker() {
+        let (sender, mut receiver) = mpsc::channel(2);
+        let recorder = Recorder::new(sender);
+        for id in 0..4 {
+            recorder.record(Source::Ws(0, 0), &id.to_string());
+        }
+        receiver.try_recv().unwrap();
+        // only the marker fits
+        recorder.record(Source::Ws(0, 0), "4");
+        receiver.try_recv().unwrap();
+        receiver.try_recv().unwrap();
+        recorder.record(Source::Ws(0, 0), "5");
+
+        let mut records = vec![];
+        while let Ok(record) = receiver.try_recv() {
+            records.push((record.source, record.payload));
+        }
+        assert_eq!(
+            records,
+            vec![(Source::Gap, "1".into()), (Source::Ws(0, 0), "5".into())]
+        );
+        assert_eq!(recorder.dropped(), 3);
+    }
+
+    #[tokio::test]
+    async fn rotation() {
+        let dir = std::env::temp_dir().join(format!("market_data_capture_{}", std::process::id()));
+        let cfg = CaptureConfig {
+            dir: dir.to_string_lossy().to_string(),
+            max_bytes: Some(10),
+            rotate_secs: None,
+        };
+        let (recorder, handle) = Recorder::start(&cfg).await.unwrap();
+        for id in 0..3 {
+            recorder.record(Source::Ws(0, 0), &format!("{{\"u\":{}}}", id));
+        }
+        drop(recorder);
+        handle.await.unwrap();
+
+        let mut records = vec![];
+        for entry in std::fs::read_dir(&dir).unwrap() {
+            let data = std::fs::read_to_string(entry.unwrap().path()).unwrap();
+            assert_eq!(data.lines().count(), 1);
+            records.extend(data.lines().filter_map(Record::parse));
+        }
+        records.sort_by(|a, b| a.payload.cmp(&b.payload));
+        let payloads: Vec<_> = records.iter().map(|r| r.payload.as_str()).collect();
+        assert_eq!(payloads, vec!["{\"u\":0}", "{\"u\":1}", "{\"u\":2}"]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
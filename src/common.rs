@@ -1,31 +1,155 @@
-use derive_more::{Add, Display, Mul, Sub};
+use derive_more::{Add, Sub};
 use derive_new::new;
-use std::num::ParseFloatError;
+use std::fmt;
+use std::fmt::{Display, Formatter};
 use std::str::FromStr;
 
-#[derive(Default, Display, Debug, Clone, PartialEq, PartialOrd, Sub, Mul, new)]
-pub struct Price(pub f32);
+/// Number of decimal digits kept by fixed-point values.
+/// Binance never sends more than 8 digits after the point.
+pub const DECIMALS: u32 = 8;
+pub const SCALE: i64 = 10_i64.pow(DECIMALS);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseDecimalError {
+    Empty,
+    InvalidDigit,
+    TooManyDecimals,
+    Overflow,
+}
+
+impl Display for ParseDecimalError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseDecimalError::Empty => write!(f, "empty decimal string"),
+            ParseDecimalError::InvalidDigit => write!(f, "invalid digit in decimal string"),
+            ParseDecimalError::TooManyDecimals => {
+                write!(f, "more than {DECIMALS} digits after decimal point")
+            }
+            ParseDecimalError::Overflow => write!(f, "decimal value out of range"),
+        }
+    }
+}
+
+impl std::error::Error for ParseDecimalError {}
+
+/// Value which can't be expressed in whole ticks or lots.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TickError {
+    /// Tick or step size isn't positive
+    InvalidTick,
+    /// Value isn't a multiple of the tick or step size
+    OffTick,
+}
+
+impl Display for TickError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            TickError::InvalidTick => write!(f, "tick size isn't positive"),
+            TickError::OffTick => write!(f, "value isn't a multiple of tick size"),
+        }
+    }
+}
+
+impl std::error::Error for TickError {}
+
+/// Exact number of `unit`s in `value`.
+fn whole_units(value: i64, unit: i64) -> Result<i64, TickError> {
+    if unit <= 0 {
+        return Err(TickError::InvalidTick);
+    }
+    if value % unit != 0 {
+        return Err(TickError::OffTick);
+    }
+    Ok(value / unit)
+}
+
+/// Parse decimal string like "-123.4500" into integer scaled by `SCALE` without rounding.
+fn parse_fixed(s: &str) -> Result<i64, ParseDecimalError> {
+    let (negative, digits) = match s.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, s),
+    };
+    let (int_part, frac_part) = digits.split_once('.').unwrap_or((digits, ""));
+    if int_part.is_empty() && frac_part.is_empty() {
+        return Err(ParseDecimalError::Empty);
+    }
+    let frac_part = frac_part.trim_end_matches('0');
+    if frac_part.len() > DECIMALS as usize {
+        return Err(ParseDecimalError::TooManyDecimals);
+    }
+
+    let mut value: i64 = 0;
+    for c in int_part.chars().chain(frac_part.chars()) {
+        let digit = c.to_digit(10).ok_or(ParseDecimalError::InvalidDigit)?;
+        value = value
+            .checked_mul(10)
+            .and_then(|v| v.checked_add(digit as i64))
+            .ok_or(ParseDecimalError::Overflow)?;
+    }
+    value = value
+        .checked_mul(10_i64.pow(DECIMALS - frac_part.len() as u32))
+        .ok_or(ParseDecimalError::Overflow)?;
+    Ok(if negative { -value } else { value })
+}
+
+fn fmt_fixed(value: i64, f: &mut Formatter<'_>) -> fmt::Result {
+    let sign = if value < 0 { "-" } else { "" };
+    let abs = value.unsigned_abs();
+    let (int_part, frac_part) = (abs / SCALE as u64, abs % SCALE as u64);
+    if frac_part == 0 {
+        return write!(f, "{sign}{int_part}");
+    }
+    let frac = format!("{:0width$}", frac_part, width = DECIMALS as usize);
+    write!(f, "{sign}{int_part}.{}", frac.trim_end_matches('0'))
+}
+
+/// Price as fixed-point number with `DECIMALS` digits, i.e. 1.5 is stored as `150_000_000`.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Add, Sub, new)]
+pub struct Price(pub i64);
 
 impl Price {
     pub fn abs(self) -> Self {
         Price(self.0.abs())
     }
 
-    pub fn same_tick(self, tick: &Price) -> bool {
-        self.abs() * 4. < *tick
+    pub fn from_f64(value: f64) -> Self {
+        Price((value * SCALE as f64).round() as i64)
+    }
+
+    pub fn as_f64(&self) -> f64 {
+        self.0 as f64 / SCALE as f64
+    }
+
+    /// Number of ticks of `tick` size in the price, which must be on the tick grid.
+    pub fn ticks(&self, tick: &Price) -> Result<i64, TickError> {
+        whole_units(self.0, tick.0)
     }
 }
 
-#[derive(Default, Display, Debug, Clone, Sub, Mul, PartialEq, PartialOrd, new)]
-pub struct Qty(pub f32);
+/// Quantity as fixed-point number with `DECIMALS` digits, same scale as `Price`.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Add, Sub, new)]
+pub struct Qty(pub i64);
 
 impl Qty {
     pub fn abs(self) -> Self {
         Qty(self.0.abs())
     }
 
-    pub fn same_tick(self, tick: Qty) -> bool {
-        self.abs() * 4. < tick
+    pub fn is_zero(&self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn from_f64(value: f64) -> Self {
+        Qty((value * SCALE as f64).round() as i64)
+    }
+
+    pub fn as_f64(&self) -> f64 {
+        self.0 as f64 / SCALE as f64
+    }
+
+    /// Number of lots of `step` size in the quantity, which must be a multiple of `step`.
+    pub fn lots(&self, step: &Qty) -> Result<i64, TickError> {
+        whole_units(self.0, step.0)
     }
 }
 
@@ -36,39 +160,97 @@ pub struct Precision {
 }
 
 impl FromStr for Qty {
-    type Err = ParseFloatError;
+    type Err = ParseDecimalError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        Ok(Qty::new(s.parse()?))
+        Ok(Qty::new(parse_fixed(s)?))
     }
 }
 impl FromStr for Price {
-    type Err = ParseFloatError;
+    type Err = ParseDecimalError;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        Ok(Price::new(s.parse()?))
+        Ok(Price::new(parse_fixed(s)?))
+    }
+}
+
+impl Display for Price {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        fmt_fixed(self.0, f)
+    }
+}
+
+impl Display for Qty {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        fmt_fixed(self.0, f)
     }
 }
 
 #[derive(Clone, Default, Debug, PartialEq, Eq, PartialOrd, Ord, Add)]
 pub struct Id(pub u64);
 
-#[derive(Default, Debug, Clone, new)]
+#[derive(Default, Debug, Clone, PartialEq, Eq, new)]
 pub struct Level {
     pub price: Price,
     pub qty: Qty,
 }
 
 impl Level {
-    pub fn from_str_pair((p, q): &(String, String)) -> Option<Self> {
-        Some(Level::new(p.parse().ok()?, q.parse().ok()?))
+    pub fn from_str_pair((p, q): &(String, String)) -> Result<Self, ParseDecimalError> {
+        Ok(Level::new(p.parse()?, q.parse()?))
+    }
+
+    pub fn from_float_pair(p: f64, q: f64) -> Self {
+        Level::new(Price::from_f64(p), Qty::from_f64(q))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::common::{Level, ParseDecimalError, Price, Qty, TickError};
+
+    #[test]
+    fn parse_decimal() {
+        assert_eq!("64123.10".parse::<Price>(), Ok(Price(6_412_310_000_000)));
+        assert_eq!("0.00000001".parse::<Qty>(), Ok(Qty(1)));
+        assert_eq!("-1.5".parse::<Price>(), Ok(Price(-150_000_000)));
+        assert_eq!(".5".parse::<Qty>(), Ok(Qty(50_000_000)));
+        assert_eq!("3".parse::<Qty>(), Ok(Qty(300_000_000)));
+        assert_eq!(
+            "0.000000001".parse::<Qty>(),
+            Err(ParseDecimalError::TooManyDecimals)
+        );
+        assert_eq!("1.2a".parse::<Qty>(), Err(ParseDecimalError::InvalidDigit));
+        assert_eq!("".parse::<Qty>(), Err(ParseDecimalError::Empty));
+    }
+
+    #[test]
+    fn display_decimal() {
+        assert_eq!(Price(6_412_310_000_000).to_string(), "64123.1");
+        assert_eq!(Qty(1).to_string(), "0.00000001");
+        assert_eq!(Price(-150_000_000).to_string(), "-1.5");
+        assert_eq!(Qty(0).to_string(), "0");
     }
 
-    pub fn from_float_pair(p: f32, q: f32) -> Self {
-        Level::new(Price::new(p.clone()), Qty::new(q.clone()))
+    #[test]
+    fn exact_level() {
+        let lvl = Level::from_str_pair(&("16777217.01".into(), "0.003".into())).unwrap();
+        assert_eq!(lvl, Level::from_float_pair(16777217.01, 0.003));
+        assert_ne!(lvl.price, "16777217.02".parse().unwrap());
+        assert_eq!(lvl.price.ticks(&"0.01".parse().unwrap()), Ok(1_677_721_701));
     }
 
-    pub fn eq(&self, rhs: Level, precision: &Precision) -> bool {
-        (rhs.price - self.price.clone()).same_tick(&precision.price)
-            && (rhs.qty - self.qty.clone()).abs() * 4. < precision.qty
+    #[test]
+    fn whole_ticks() {
+        let tick = Price::from_f64(0.1);
+        assert_eq!(Price::from_f64(10.2).ticks(&tick), Ok(102));
+        assert_eq!(Price::from_f64(-0.3).ticks(&tick), Ok(-3));
+        // 10.25 and 10.27 would share the key of 10.2 with truncation
+        assert_eq!(Price::from_f64(10.25).ticks(&tick), Err(TickError::OffTick));
+        assert_eq!(
+            Price::from_f64(10.).ticks(&Price(0)),
+            Err(TickError::InvalidTick)
+        );
+        assert_eq!(Qty::from_f64(0.003).lots(&Qty::from_f64(0.001)), Ok(3));
+        assert_eq!(Qty::from_f64(1.).lots(&Qty(0)), Err(TickError::InvalidTick));
     }
 }
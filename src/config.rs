@@ -1,6 +1,42 @@
+use crate::lob::analytics::AnalyticsConfig;
+use crate::output::OutputFormat;
+use crate::sink::SINK_QUEUE_SIZE;
 use crate::structure::Exchange;
 use config::{Config, ConfigError, File};
 use serde::Deserialize;
+use std::collections::HashMap;
+
+/// Settings of HTTP client used for REST requests
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct HttpConfig {
+    pub connect_timeout_ms: u64,
+    pub timeout_ms: u64,
+    /// Retries of transient failures
+    pub retries: u32,
+    pub min_backoff_ms: u64,
+    pub max_backoff_ms: u64,
+    pub user_agent: Option<String>,
+    /// Proxy for all requests, e.g. "http://127.0.0.1:3128"
+    pub proxy: Option<String>,
+    /// Request weight allowed per minute, 0 disables the limit
+    pub weight_limit: u32,
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        HttpConfig {
+            connect_timeout_ms: 3_000,
+            timeout_ms: 10_000,
+            retries: 3,
+            min_backoff_ms: 100,
+            max_backoff_ms: 5_000,
+            user_agent: None,
+            proxy: None,
+            weight_limit: 2_400,
+        }
+    }
+}
 
 #[derive(Deserialize, Clone)]
 pub struct ExchangeConfig {
@@ -9,22 +45,372 @@ pub struct ExchangeConfig {
     pub exchange_info: String,
     pub snapshot: String,
     pub wss_api: String,
+    #[serde(default)]
+    pub http: HttpConfig,
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum SinkKind {
+    Stdout,
+    File {
+        path: String,
+        /// File is rotated once it exceeds the size
+        max_bytes: Option<u64>,
+        /// Number of rotated files to keep
+        #[serde(default = "default_keep")]
+        keep: usize,
+    },
+    /// Listen on the address and broadcast to all connected clients
+    Tcp {
+        addr: String,
+    },
+    /// Send datagrams to the address
+    Udp {
+        addr: String,
+    },
+}
+
+fn default_keep() -> usize {
+    5
+}
+
+fn default_queue_size() -> usize {
+    SINK_QUEUE_SIZE
+}
+
+/// Destination of book updates along with its filter
+#[derive(Deserialize, Clone)]
+pub struct SinkConfig {
+    #[serde(flatten)]
+    pub kind: SinkKind,
+    #[serde(default)]
+    pub format: OutputFormat,
+    /// Raw symbols, all instruments if not set
+    pub instruments: Option<Vec<String>>,
+    /// Maximum number of levels on each side
+    pub depth: Option<usize>,
+    /// Minimum interval between updates of the same instrument, the latest one is sent
+    pub throttle_ms: Option<u64>,
+    /// Updates are dropped for the sink once the queue is full
+    #[serde(default = "default_queue_size")]
+    pub queue_size: usize,
+    /// Emit analytics along with levels
+    pub analytics: Option<AnalyticsConfig>,
+}
+
+impl SinkConfig {
+    pub fn stdout(format: OutputFormat) -> SinkConfig {
+        SinkConfig {
+            kind: SinkKind::Stdout,
+            format,
+            instruments: None,
+            depth: None,
+            throttle_ms: None,
+            queue_size: SINK_QUEUE_SIZE,
+            analytics: None,
+        }
+    }
+}
+
+/// Capture of raw messages for later replay
+#[derive(Deserialize, Clone)]
+pub struct CaptureConfig {
+    /// Directory for capture files
+    pub dir: String,
+    /// Start a new file once the current one exceeds the size
+    pub max_bytes: Option<u64>,
+    /// Start a new file once the current one is open for this long
+    pub rotate_secs: Option<u64>,
+}
+
+/// Update interval of the depth stream
+#[derive(Deserialize, Default, Debug, Clone, Copy, PartialEq)]
+pub enum StreamSpeed {
+    #[serde(rename = "100ms")]
+    Ms100,
+    /// Default of the exchange
+    #[default]
+    #[serde(rename = "250ms")]
+    Ms250,
+    #[serde(rename = "500ms")]
+    Ms500,
+}
+
+/// Depth limits accepted by the snapshot endpoint
+pub const SNAPSHOT_LIMITS: [u32; 7] = [5, 10, 20, 50, 100, 500, 1000];
+
+/// Settings of a single book
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct BookConfig {
+    /// Number of levels kept on every side
+    pub depth_limit: usize,
+    /// Number of levels requested in REST snapshot
+    pub snapshot_limit: u32,
+    /// Maximum number of missed ids, after which snapshot is requested
+    pub skip_limit: u64,
+    pub speed: StreamSpeed,
+    /// Book is stale and resubscribed if there are no depth updates for this long
+    pub max_silence_ms: Option<u64>,
+}
+
+impl BookConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.depth_limit == 0 {
+            return Err("depth_limit must be positive".into());
+        }
+        if !SNAPSHOT_LIMITS.contains(&self.snapshot_limit) {
+            return Err(format!(
+                "snapshot_limit {} is not one of {:?}",
+                self.snapshot_limit, SNAPSHOT_LIMITS
+            ));
+        }
+        if (self.snapshot_limit as usize) < self.depth_limit {
+            return Err(format!(
+                "snapshot_limit {} is less than depth_limit {}",
+                self.snapshot_limit, self.depth_limit
+            ));
+        }
+        if self.max_silence_ms == Some(0) {
+            return Err("max_silence_ms must be positive".into());
+        }
+        Ok(())
+    }
+}
+
+impl Default for BookConfig {
+    fn default() -> Self {
+        BookConfig {
+            depth_limit: 20,
+            snapshot_limit: 500,
+            skip_limit: 100,
+            speed: StreamSpeed::default(),
+            max_silence_ms: None,
+        }
+    }
+}
+
+/// `BookConfig` fields set for an instrument, the rest are the global ones
+#[derive(Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct BookOverride {
+    pub depth_limit: Option<usize>,
+    pub snapshot_limit: Option<u32>,
+    pub skip_limit: Option<u64>,
+    pub speed: Option<StreamSpeed>,
+    pub max_silence_ms: Option<u64>,
+}
+
+/// Global settings of books and overrides by raw symbol
+#[derive(Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct BooksConfig {
+    #[serde(flatten)]
+    pub global: BookConfig,
+    #[serde(default)]
+    pub instrument: HashMap<String, BookOverride>,
+}
+
+impl BooksConfig {
+    /// Settings of the raw symbol, which is matched ignoring case.
+    pub fn get(&self, raw: &str) -> BookConfig {
+        let global = &self.global;
+        let custom = self
+            .instrument
+            .iter()
+            .find(|(symbol, _)| symbol.eq_ignore_ascii_case(raw));
+        match custom.map(|(_, custom)| custom) {
+            None => global.clone(),
+            Some(custom) => BookConfig {
+                depth_limit: custom.depth_limit.unwrap_or(global.depth_limit),
+                snapshot_limit: custom.snapshot_limit.unwrap_or(global.snapshot_limit),
+                skip_limit: custom.skip_limit.unwrap_or(global.skip_limit),
+                speed: custom.speed.unwrap_or(global.speed),
+                max_silence_ms: custom.max_silence_ms.or(global.max_silence_ms),
+            },
+        }
+    }
+
+    /// Validate global settings and the ones of every instrument.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        self.global
+            .validate()
+            .map_err(|err| ConfigError::Message(format!("book: {}", err)))?;
+        for symbol in self.instrument.keys() {
+            self.get(symbol)
+                .validate()
+                .map_err(|err| ConfigError::Message(format!("book of {}: {}", symbol, err)))?;
+        }
+        Ok(())
+    }
+}
+
+/// Periodic comparison of books with REST snapshots
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct AuditConfig {
+    /// Interval between audits, instruments are audited in turn
+    pub interval_secs: u64,
+    /// Book is resynced once the number of mismatched levels exceeds it
+    pub max_mismatches: usize,
+}
+
+impl Default for AuditConfig {
+    fn default() -> Self {
+        AuditConfig {
+            interval_secs: 60,
+            max_mismatches: 0,
+        }
+    }
 }
 
 #[derive(Deserialize)]
 pub struct MDConfig {
     endpoint: Vec<ExchangeConfig>,
+    #[serde(default)]
+    sink: Vec<SinkConfig>,
+    pub capture: Option<CaptureConfig>,
+    #[serde(default)]
+    pub book: BooksConfig,
+    pub audit: Option<AuditConfig>,
 }
 
 impl MDConfig {
     pub fn new(config_path: String) -> Result<Self, ConfigError> {
-        Config::builder()
+        let cfg: MDConfig = Config::builder()
             .add_source(File::with_name(config_path.as_ref()))
             .build()?
-            .try_deserialize()
+            .try_deserialize()?;
+        cfg.validate()?;
+        Ok(cfg)
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        self.book.validate()?;
+        if self
+            .audit
+            .as_ref()
+            .is_some_and(|audit| audit.interval_secs == 0)
+        {
+            return Err(ConfigError::Message(
+                "audit: interval_secs must be positive".into(),
+            ));
+        }
+        Ok(())
     }
 
     pub fn get(&self, exch: Exchange) -> Option<&ExchangeConfig> {
         self.endpoint.iter().find(|x| x.exchange == exch)
     }
+
+    pub fn sinks(&self) -> &[SinkConfig] {
+        &self.sink
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{BookConfig, MDConfig, SinkKind, StreamSpeed};
+    use crate::output::OutputFormat;
+    use crate::sink::SINK_QUEUE_SIZE;
+    use crate::structure::Exchange;
+    use config::{Config, File, FileFormat};
+
+    #[test]
+    fn default_endpoints() {
+        let cfg = MDConfig::new("src/endpoints.toml".into()).unwrap();
+        let binance = cfg.get(Exchange::BINANCE).unwrap();
+        assert_eq!(binance.http.retries, 3);
+        assert!(binance.http.proxy.is_none());
+        assert_eq!(binance.http.weight_limit, 2400);
+        assert!(cfg.sinks().is_empty());
+        assert!(cfg.capture.is_none());
+        assert!(cfg.audit.is_none());
+        assert_eq!(cfg.book.get("BTCUSDT"), BookConfig::default());
+    }
+
+    #[test]
+    fn books() {
+        let cfg: MDConfig = Config::builder()
+            .add_source(File::from_str(
+                r#"
+                endpoint = []
+                [book]
+                depth_limit = 50
+                speed = "100ms"
+                max_silence_ms = 5000
+                [book.instrument.ETHUSDT]
+                depth_limit = 10
+                snapshot_limit = 100
+                max_silence_ms = 60000
+                "#,
+                FileFormat::Toml,
+            ))
+            .build()
+            .unwrap()
+            .try_deserialize()
+            .unwrap();
+        let btc = cfg.book.get("BTCUSDT");
+        assert_eq!((btc.depth_limit, btc.snapshot_limit), (50, 500));
+        assert_eq!(btc.speed, StreamSpeed::Ms100);
+        assert_eq!(btc.max_silence_ms, Some(5000));
+        let eth = cfg.book.get("ethusdt");
+        assert_eq!((eth.depth_limit, eth.snapshot_limit), (10, 100));
+        assert_eq!((eth.skip_limit, eth.speed), (100, StreamSpeed::Ms100));
+        assert_eq!(eth.max_silence_ms, Some(60000));
+        assert!(cfg.book.validate().is_ok());
+
+        let mut books = cfg.book.clone();
+        books.global.snapshot_limit = 200;
+        assert!(books.validate().is_err());
+        let mut books = cfg.book;
+        books.instrument.get_mut("ethusdt").unwrap().depth_limit = Some(101);
+        assert!(books.validate().is_err());
+        books.instrument.get_mut("ethusdt").unwrap().snapshot_limit = Some(500);
+        assert!(books.validate().is_ok());
+        books.instrument.get_mut("ethusdt").unwrap().depth_limit = Some(0);
+        assert!(books.validate().is_err());
+        books.instrument.get_mut("ethusdt").unwrap().depth_limit = None;
+        books.global.max_silence_ms = Some(0);
+        assert!(books.validate().is_err());
+    }
+
+    #[test]
+    fn sinks() {
+        let cfg: MDConfig = Config::builder()
+            .add_source(File::from_str(
+                r#"
+                endpoint = []
+                [[sink]]
+                kind = "file"
+                path = "books.jsonl"
+                format = "json"
+                max_bytes = 1000000
+                [[sink]]
+                kind = "udp"
+                addr = "255.255.255.255:9000"
+                format = "csv"
+                instruments = ["BTCUSDT"]
+                depth = 1
+                throttle_ms = 100
+                "#,
+                FileFormat::Toml,
+            ))
+            .build()
+            .unwrap()
+            .try_deserialize()
+            .unwrap();
+        let sinks = cfg.sinks();
+        assert_eq!(
+            sinks[0].kind,
+            SinkKind::File {
+                path: "books.jsonl".into(),
+                max_bytes: Some(1000000),
+                keep: 5
+            }
+        );
+        assert_eq!(sinks[0].format, OutputFormat::Json);
+        assert_eq!(sinks[0].queue_size, SINK_QUEUE_SIZE);
+        assert_eq!(sinks[1].depth, Some(1));
+        assert_eq!(sinks[1].throttle_ms, Some(100));
+    }
 }
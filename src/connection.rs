@@ -1,33 +1,575 @@
+use crate::arbiter::ConnId;
+use crate::backoff::Backoff;
+use crate::capture::{Recorder, Source};
+use crate::common::Id;
+use crate::runner::Runner;
+use crate::scheme::connector::{AliasInstrument, MarketQueries, WssStream};
+use crate::structure::{Ack, Delta, Instrument, MDResponse};
+use crate::subscription::{Command, COMMAND_QUEUE_SIZE};
+use derive_new::new;
 use futures_util::{SinkExt, StreamExt};
-use log::debug;
+use log::{debug, error, info, warn};
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
 use tokio::net::TcpStream;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::mpsc::{Receiver, Sender};
+use tokio::sync::{broadcast, mpsc};
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
 use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
 use tungstenite::Message;
 
+/// Connection is replaced this long before the exchange drops it.
+const ROTATION_MARGIN: Duration = Duration::from_secs(30 * 60);
+/// Old connection is retired after this time even if streams didn't overlap,
+/// e.g. there were no updates for some instruments.
+const ROTATION_TIMEOUT: Duration = Duration::from_secs(60);
+const ROTATION_QUEUE_SIZE: usize = 100;
+
+pub(crate) enum WsEvent {
+    Message(String),
+    /// Connection was lost and established again, subscriptions should be sent again
+    /// and updates sent in between are missed.
+    Reconnected,
+}
+
+/// WebSocket client, which reconnects on failures.
 pub(crate) struct WsClient {
-    socket: WebSocketStream<MaybeTlsStream<TcpStream>>,
+    path: String,
+    socket: Option<WebSocketStream<MaybeTlsStream<TcpStream>>>,
+    backoff: Backoff,
 }
 
 impl WsClient {
     pub(crate) async fn connect_to(path: &str) -> WsClient {
-        let (socket, _) = connect_async(path)
-            .await
-            .expect(format!("Failed to connect to {path}").as_str());
-        WsClient { socket }
+        let mut client = WsClient {
+            path: path.to_string(),
+            socket: None,
+            backoff: Backoff::default(),
+        };
+        client.reconnect().await;
+        client
+    }
+
+    async fn reconnect(&mut self) {
+        loop {
+            match connect_async(self.path.as_str()).await {
+                Ok((socket, _)) => {
+                    info!("Connected to {}", self.path);
+                    self.socket = Some(socket);
+                    self.backoff.reset();
+                    return;
+                }
+                Err(err) => {
+                    let delay = self.backoff.next_delay();
+                    error!(
+                        "Failed to connect to {}: {}, retry in {:?}",
+                        self.path, err, delay
+                    );
+                    tokio::time::sleep(delay).await;
+                }
+            }
+        }
     }
 
     pub(crate) async fn send(&mut self, s: String) {
         debug!("Send to ws: {}", s);
-        self.socket
-            .send(Message::Text(s))
-            .await
-            .expect("Failed to send message to websocket stream");
+        let Some(socket) = self.socket.as_mut() else {
+            warn!("Not connected, drop message {}", s);
+            return;
+        };
+        if let Err(err) = socket.send(Message::Text(s)).await {
+            warn!("Failed to send message to {}: {}", self.path, err);
+            self.socket = None;
+        }
+    }
+
+    pub(crate) async fn wait(&mut self) -> WsEvent {
+        loop {
+            let Some(socket) = self.socket.as_mut() else {
+                self.reconnect().await;
+                return WsEvent::Reconnected;
+            };
+            match socket.next().await {
+                Some(Ok(Message::Text(msg))) => return WsEvent::Message(msg),
+                Some(Ok(Message::Close(frame))) => {
+                    warn!("Connection to {} closed: {:?}", self.path, frame);
+                    self.socket = None;
+                }
+                Some(Ok(_)) => {}
+                Some(Err(err)) => {
+                    warn!("Connection to {} failed: {}", self.path, err);
+                    self.socket = None;
+                }
+                None => {
+                    warn!("Connection to {} terminated", self.path);
+                    self.socket = None;
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Method {
+    Subscribe,
+    Unsubscribe,
+}
+
+struct Pending {
+    method: Method,
+    insts: Vec<Instrument>,
+}
+
+/// Instruments subscribed by a single connection and requests waiting for ack.
+/// Updates of unsubscribed instrument are still parsed until the exchange confirms it.
+#[derive(Default)]
+pub(crate) struct Subscriptions {
+    insts: Vec<Instrument>,
+    insts_map: AliasInstrument,
+    pending: HashMap<u64, Pending>,
+    last_id: u64,
+}
+
+impl Subscriptions {
+    pub(crate) fn instruments(&self) -> &[Instrument] {
+        &self.insts
+    }
+
+    pub(crate) fn insts_map(&self) -> &AliasInstrument {
+        &self.insts_map
+    }
+
+    fn request(
+        &mut self,
+        method: Method,
+        insts: Vec<Instrument>,
+    ) -> Option<(u64, Vec<Instrument>)> {
+        if insts.is_empty() {
+            return None;
+        }
+        self.last_id += 1;
+        let pending = Pending {
+            method,
+            insts: insts.clone(),
+        };
+        self.pending.insert(self.last_id, pending);
+        Some((self.last_id, insts))
+    }
+
+    /// Returns request id and instruments, which are not subscribed yet.
+    pub(crate) fn subscribe(&mut self, insts: &[Instrument]) -> Option<(u64, Vec<Instrument>)> {
+        let mut added = vec![];
+        for inst in insts {
+            if !self.insts.contains(inst) && !added.contains(inst) {
+                added.push(inst.clone());
+            }
+        }
+        for inst in &added {
+            self.insts.push(inst.clone());
+            self.insts_map
+                .insert(inst.to_raw_string().clone(), inst.clone());
+        }
+        self.request(Method::Subscribe, added)
+    }
+
+    /// Returns request id and instruments, which were subscribed.
+    pub(crate) fn unsubscribe(&mut self, insts: &[Instrument]) -> Option<(u64, Vec<Instrument>)> {
+        let removed: Vec<Instrument> = self
+            .insts
+            .iter()
+            .filter(|inst| insts.contains(inst))
+            .cloned()
+            .collect();
+        self.insts.retain(|inst| !removed.contains(inst));
+        self.request(Method::Unsubscribe, removed)
+    }
+
+    /// Subscribe all instruments again on a new connection, previous requests are forgotten.
+    pub(crate) fn resubscribe(&mut self) -> Option<(u64, Vec<Instrument>)> {
+        self.pending.clear();
+        self.insts_map = self
+            .insts
+            .iter()
+            .map(|inst| (inst.to_raw_string().clone(), inst.clone()))
+            .collect();
+        self.request(Method::Subscribe, self.insts.clone())
+    }
+
+    pub(crate) fn on_ack(&mut self, ack: &Ack) {
+        let Some(pending) = self.pending.remove(&ack.id) else {
+            warn!("Unexpected response to request {}", ack.id);
+            return;
+        };
+        match (&ack.error, pending.method) {
+            (None, Method::Subscribe) => {
+                info!("Subscribed to {} instruments", pending.insts.len())
+            }
+            (None, Method::Unsubscribe) => {
+                for inst in &pending.insts {
+                    if !self.insts.contains(inst) {
+                        self.insts_map.remove(inst.to_raw_string());
+                    }
+                }
+                info!("Unsubscribed from {} instruments", pending.insts.len())
+            }
+            (Some(err), Method::Subscribe) => {
+                error!("Failed to subscribe to {:?}: {}", pending.insts, err);
+                self.insts.retain(|inst| !pending.insts.contains(inst));
+                for inst in &pending.insts {
+                    self.insts_map.remove(inst.to_raw_string());
+                }
+            }
+            (Some(err), Method::Unsubscribe) => {
+                error!("Failed to unsubscribe from {:?}: {}", pending.insts, err)
+            }
+        }
+    }
+}
+
+/// Tracks replacement of a connection: the new one is synchronized once
+/// for every instrument its depth stream continues the stream of the old one.
+struct Rotation {
+    generation: u64,
+    started: Instant,
+    /// Last update id received from the old connection
+    last_old: HashMap<Instrument, Id>,
+    pending: HashSet<Instrument>,
+}
+
+impl Rotation {
+    fn new(generation: u64, insts: &[Instrument]) -> Rotation {
+        Rotation {
+            generation,
+            started: Instant::now(),
+            last_old: HashMap::new(),
+            pending: insts.iter().cloned().collect(),
+        }
+    }
+
+    fn is_expired(&self) -> bool {
+        if self.started.elapsed() < ROTATION_TIMEOUT {
+            return false;
+        }
+        warn!(
+            "Streams didn't overlap for {:?}, retire old connection anyway",
+            self.pending
+        );
+        true
+    }
+
+    /// Returns true once the new connection is synchronized.
+    fn on_delta(&mut self, generation: u64, delta: &Delta) -> bool {
+        if generation < self.generation {
+            self.last_old.insert(delta.inst.clone(), delta.last.clone());
+        } else if let Some(last) = self.last_old.get(&delta.inst) {
+            if &delta.last_stream <= last {
+                self.pending.remove(&delta.inst);
+            }
+        }
+        self.pending.is_empty() || self.is_expired()
+    }
+
+    /// Unsubscribed instruments aren't awaited anymore.
+    fn on_command(&mut self, cmd: &Command) {
+        if let Command::Unsubscribe(insts) = cmd {
+            self.pending.retain(|inst| !insts.contains(inst));
+        }
     }
+}
 
-    pub(crate) async fn wait(&mut self) -> Option<String> {
-        match self.socket.next().await {
-            Some(msg) => msg.ok()?.into_text().ok(),
-            None => None,
+/// Running WebSocket client and queue of its subscription commands.
+#[derive(new)]
+struct Client {
+    handle: JoinHandle<()>,
+    commands: Sender<Command>,
+}
+
+impl Client {
+    /// Never waits, as the client could be blocked forwarding updates to the caller.
+    fn send(&self, cmd: Command) {
+        match self.commands.try_send(cmd) {
+            Ok(()) => {}
+            Err(TrySendError::Full(cmd)) => {
+                error!("Command queue of connection is full, drop {:?}", cmd)
+            }
+            Err(TrySendError::Closed(_)) => {
+                warn!("Connection is closed, drop subscription command")
+            }
         }
     }
+
+    fn abort(&self) {
+        self.handle.abort();
+    }
+}
+
+impl Runner {
+    fn get_streams() -> Vec<WssStream> {
+        vec![WssStream::Depth, WssStream::Trade]
+    }
+
+    /// Single WebSocket connection, which forwards parsed updates tagged with `generation`.
+    fn spawn_client(
+        exch: Arc<dyn MarketQueries + Send + Sync>,
+        sender: Sender<(u64, MDResponse)>,
+        insts: Vec<Instrument>,
+        mut commands: Receiver<Command>,
+        (conn, generation): (ConnId, u64),
+        recorder: Option<Recorder>,
+    ) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut subs = Subscriptions::default();
+            let mut client = WsClient::connect_to(exch.connect_uri()).await;
+            Self::apply_command(
+                &mut client,
+                &mut subs,
+                exch.as_ref(),
+                Command::Subscribe(insts),
+            )
+            .await;
+            loop {
+                let event = tokio::select! {
+                    cmd = commands.recv() => match cmd {
+                        None => return,
+                        Some(cmd) => {
+                            Self::apply_command(&mut client, &mut subs, exch.as_ref(), cmd).await;
+                            continue;
+                        }
+                    },
+                    event = client.wait() => event,
+                };
+                let (res, received) = match event {
+                    WsEvent::Message(msg) => {
+                        if let Some(recorder) = &recorder {
+                            recorder.record(Source::Ws(conn, generation), &msg);
+                        }
+                        (msg, Instant::now())
+                    }
+                    WsEvent::Reconnected => {
+                        warn!("Reconnected to {}, resync books", exch.connect_uri());
+                        if let Some((id, insts)) = subs.resubscribe() {
+                            client
+                                .send(exch.subscribe(id, &insts, &Self::get_streams()))
+                                .await;
+                        }
+                        for inst in subs.instruments() {
+                            Runner::record_resync(&recorder, inst, "reconnect");
+                            let resync = MDResponse::Resync(inst.clone());
+                            if sender.send((generation, resync)).await.is_err() {
+                                return;
+                            }
+                        }
+                        continue;
+                    }
+                };
+                // debug!("Receive: {:?}", res);
+                let opt_result = exch.handle_response(&res, subs.insts_map());
+                match opt_result {
+                    None => info!("Couldn't parse {}", res),
+                    Some(MDResponse::Ping) => client.send(exch.pong().into()).await,
+                    Some(MDResponse::Ack(ack)) => subs.on_ack(&ack),
+                    Some(mut resp) => {
+                        if let MDResponse::Delta(delta) = &mut resp {
+                            delta.received = Some(received);
+                        }
+                        if sender.send((generation, resp)).await.is_err() {
+                            return;
+                        }
+                    }
+                }
+            }
+        })
+    }
+
+    async fn apply_command(
+        client: &mut WsClient,
+        subs: &mut Subscriptions,
+        exch: &(dyn MarketQueries + Send + Sync),
+        cmd: Command,
+    ) {
+        let streams = Self::get_streams();
+        let subscribe = |subs: &mut Subscriptions, insts: &[Instrument]| {
+            subs.subscribe(insts)
+                .map(|(id, insts)| exch.subscribe(id, &insts, &streams))
+        };
+        let requests = match &cmd {
+            Command::Subscribe(insts) => vec![subscribe(subs, insts)],
+            Command::Unsubscribe(insts) => vec![subs
+                .unsubscribe(insts)
+                .map(|(id, insts)| exch.unsubscribe(id, &insts, &streams))],
+            Command::Resubscribe(insts) => {
+                // only the ones of this connection
+                let insts: Vec<Instrument> = subs
+                    .instruments()
+                    .iter()
+                    .filter(|inst| insts.contains(inst))
+                    .cloned()
+                    .collect();
+                let unsubscribe = subs
+                    .unsubscribe(&insts)
+                    .map(|(id, insts)| exch.unsubscribe(id, &insts, &streams));
+                vec![unsubscribe, subscribe(subs, &insts)]
+            }
+        };
+        for request in requests.into_iter().flatten() {
+            client.send(request).await;
+        }
+    }
+
+    /// Connection to the exchange, which is replaced by a new one in advance
+    /// if the exchange limits connection lifetime.
+    pub fn create_connection(
+        conn: ConnId,
+        exch: Arc<dyn MarketQueries + Send + Sync>,
+        sender: Sender<(ConnId, MDResponse)>,
+        mut insts: Vec<Instrument>,
+        mut commands: broadcast::Receiver<Command>,
+        recorder: Option<Recorder>,
+    ) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            let (tx, mut rx) = mpsc::channel(ROTATION_QUEUE_SIZE);
+            let spawn = |generation: u64, insts: &[Instrument]| {
+                let (cmd_tx, cmd_rx) = mpsc::channel(COMMAND_QUEUE_SIZE);
+                let handle = Self::spawn_client(
+                    exch.clone(),
+                    tx.clone(),
+                    insts.to_vec(),
+                    cmd_rx,
+                    (conn, generation),
+                    recorder.clone(),
+                );
+                Client::new(handle, cmd_tx)
+            };
+            let rotate_in = exch
+                .connection_lifetime()
+                .map(|lifetime| lifetime.saturating_sub(ROTATION_MARGIN));
+
+            let mut generation = 0;
+            let mut current = spawn(generation, &insts);
+            let mut rotate_at = rotate_in.map(|d| Instant::now() + d);
+            let mut rotation: Option<(Rotation, Client)> = None;
+            let mut commands_open = true;
+            loop {
+                let (source, resp) = tokio::select! {
+                    _ = Self::sleep_until(rotate_at), if rotation.is_none() => {
+                        info!("Open replacement connection to {}", exch.connect_uri());
+                        let next = spawn(generation + 1, &insts);
+                        rotation = Some((Rotation::new(generation + 1, &insts), next));
+                        continue;
+                    }
+                    cmd = commands.recv(), if commands_open => {
+                        match cmd {
+                            Ok(cmd) => {
+                                cmd.apply(&mut insts);
+                                if let Some((rot, next)) = rotation.as_mut() {
+                                    rot.on_command(&cmd);
+                                    next.send(cmd.clone());
+                                }
+                                current.send(cmd);
+                            }
+                            Err(RecvError::Lagged(num)) => {
+                                error!("{} subscription commands are lost", num)
+                            }
+                            Err(RecvError::Closed) => commands_open = false,
+                        }
+                        continue;
+                    }
+                    msg = rx.recv() => match msg {
+                        None => break,
+                        Some(msg) => msg,
+                    }
+                };
+                if source < generation {
+                    continue;
+                }
+                let synchronized = match (&resp, rotation.as_mut()) {
+                    (MDResponse::Delta(delta), Some((rot, _))) => rot.on_delta(source, delta),
+                    (_, Some((rot, _))) => rot.is_expired(),
+                    _ => false,
+                };
+                let wait = matches!(resp, MDResponse::Resync(..));
+                if !Self::forward(&sender, (conn, resp), wait).await {
+                    break;
+                }
+                if synchronized {
+                    let (_, next) = rotation.take().expect("rotation is in progress");
+                    info!("Retire connection {} to {}", generation, exch.connect_uri());
+                    current.abort();
+                    current = next;
+                    generation += 1;
+                    rotate_at = rotate_in.map(|d| Instant::now() + d);
+                }
+            }
+            current.abort();
+            if let Some((_, next)) = rotation {
+                next.abort();
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::common::Id;
+    use crate::connection::{Rotation, Subscriptions};
+    use crate::structure::{Ack, Delta, Instrument};
+    use crate::test_support::{inst, symbol};
+
+    fn delta(inst: &Instrument, first: u64, last: u64) -> Delta {
+        Delta::new(
+            inst.clone(),
+            vec![],
+            vec![],
+            Id(first),
+            Id(last),
+            Id(first - 1),
+            0,
+        )
+    }
+
+    #[test]
+    fn rotation() {
+        let (btc, eth) = (inst(0.01, 0.1), symbol("ETHUSDT", 0.01, 0.1));
+        let mut rotation = Rotation::new(1, &[btc.clone(), eth.clone()]);
+
+        assert!(!rotation.on_delta(0, &delta(&btc, 10, 20)));
+        assert!(!rotation.on_delta(0, &delta(&eth, 10, 20)));
+        // gap between old and new streams
+        assert!(!rotation.on_delta(1, &delta(&btc, 30, 40)));
+        assert!(!rotation.on_delta(0, &delta(&btc, 21, 30)));
+        assert!(!rotation.on_delta(1, &delta(&btc, 31, 40)));
+        assert!(rotation.on_delta(1, &delta(&eth, 21, 30)));
+    }
+
+    #[test]
+    fn track_acks() {
+        let (btc, eth) = (vec![inst(0.01, 0.1)], vec![symbol("ETHUSDT", 0.01, 0.1)]);
+        let mut subs = Subscriptions::default();
+        let (id, added) = subs
+            .subscribe(&[btc.clone(), eth.clone()].concat())
+            .unwrap();
+        assert_eq!((id, added.len()), (1, 2));
+        assert!(subs.subscribe(&btc).is_none());
+        subs.on_ack(&Ack::new(1, None));
+
+        let (id, removed) = subs.unsubscribe(&eth).unwrap();
+        assert_eq!((id, removed), (2, eth.clone()));
+        assert_eq!(subs.instruments(), btc.as_slice());
+        // updates are parsed until unsubscription is confirmed
+        assert!(subs.insts_map().contains_key("ETHUSDT"));
+        subs.on_ack(&Ack::new(2, None));
+        assert!(!subs.insts_map().contains_key("ETHUSDT"));
+
+        subs.subscribe(&eth).unwrap();
+        subs.on_ack(&Ack::new(3, Some("Invalid request".into())));
+        assert_eq!(subs.instruments(), btc.as_slice());
+        assert_eq!(subs.insts_map().len(), 1);
+
+        let (id, insts) = subs.resubscribe().unwrap();
+        assert_eq!((id, insts), (4, btc));
+    }
 }
@@ -0,0 +1,352 @@
+use crate::capture::Recorder;
+use crate::common::Level;
+use crate::lob::order_book::OrderBook;
+use crate::lob::orderbooks::DepthBookManager;
+use crate::output::BookUpdate;
+use crate::runner::Runner;
+use crate::snapshot::SnapshotFetcher;
+use crate::structure::{Instrument, MDResponse};
+use crate::subscription::SubscriptionControl;
+use log::{error, info, warn};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::os::unix::fs::FileTypeExt;
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::mpsc::Sender;
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+
+/// Query to the main loop, which owns the order books.
+#[derive(Debug)]
+pub enum Query {
+    List(oneshot::Sender<Value>),
+    Book(Instrument, oneshot::Sender<Value>),
+    Resync(Instrument, oneshot::Sender<Value>),
+}
+
+/// Command line sent to the control socket, e.g. `subscribe BTCUSDT ETHUSDT`.
+#[derive(Debug, PartialEq)]
+enum Request {
+    List,
+    Subscribe(Vec<String>),
+    Unsubscribe(Vec<String>),
+    Resync(String),
+    Book(String),
+}
+
+impl Request {
+    fn parse(line: &str) -> Result<Request, String> {
+        let mut words = line.split_whitespace();
+        let cmd = words.next().unwrap_or_default();
+        let symbols: Vec<String> = words.map(|s| s.to_uppercase()).collect();
+        let single = |symbols: Vec<String>| match symbols.as_slice() {
+            [symbol] => Ok(symbol.clone()),
+            _ => Err(format!("{} expects a single symbol", cmd)),
+        };
+        match cmd {
+            "list" => Ok(Request::List),
+            "subscribe" | "unsubscribe" if symbols.is_empty() => {
+                Err(format!("{} expects symbols", cmd))
+            }
+            "subscribe" => Ok(Request::Subscribe(symbols)),
+            "unsubscribe" => Ok(Request::Unsubscribe(symbols)),
+            "resync" => Ok(Request::Resync(single(symbols)?)),
+            "book" => Ok(Request::Book(single(symbols)?)),
+            _ => Err(format!("unknown command '{}'", cmd)),
+        }
+    }
+}
+
+fn level_json(lvl: Option<&Level>) -> Value {
+    match lvl {
+        Some(lvl) => json!([lvl.price.to_string(), lvl.qty.to_string()]),
+        None => Value::Null,
+    }
+}
+
+fn status_json(inst: &Instrument, book: &OrderBook) -> Value {
+    json!({
+        "symbol": inst.to_raw_string(),
+        "synced": book.is_synced(),
+        "last_update_id": book.last_applied().0,
+        "trade_adjusted": book.is_trade_adjusted(),
+        "stale": book.is_stale(),
+        "corruptions": book.corruptions(),
+        "bid": level_json(book.buy().best()),
+        "ask": level_json(book.sell().best()),
+    })
+}
+
+fn unknown(inst: &Instrument) -> Value {
+    json!({"error": format!("{} is not subscribed", inst.to_raw_string())})
+}
+
+/// Answer the query in the main loop.
+pub fn serve(
+    query: Query,
+    books: &DepthBookManager,
+    snapshots: &mut SnapshotFetcher,
+    recorder: &Option<Recorder>,
+) {
+    let (reply, resp) = match query {
+        Query::List(reply) => {
+            let mut books: Vec<_> = books.iter().collect();
+            books.sort_by(|(a, _), (b, _)| a.to_raw_string().cmp(b.to_raw_string()));
+            let list: Vec<Value> = books.iter().map(|(i, b)| status_json(i, b)).collect();
+            (reply, json!({ "instruments": list }))
+        }
+        Query::Book(inst, reply) => {
+            let resp = match books.get(&inst) {
+                Some(book) => {
+                    let mut resp = BookUpdate::new(&inst, book).to_json();
+                    resp["synced"] = book.is_synced().into();
+                    resp
+                }
+                None => unknown(&inst),
+            };
+            (reply, resp)
+        }
+        Query::Resync(inst, reply) => {
+            let resp = match books.get(&inst) {
+                Some(_) => {
+                    info!("Forced resync of {}", inst.to_raw_string());
+                    snapshots.reset(&inst);
+                    Runner::record_resync(recorder, &inst, "control");
+                    json!({"resync": inst.to_raw_string()})
+                }
+                None => unknown(&inst),
+            };
+            (reply, resp)
+        }
+    };
+    // requester could disconnect meanwhile
+    let _ = reply.send(resp);
+}
+
+/// Local control interface, which accepts text commands on a Unix socket
+/// and replies with a JSON line per command.
+pub struct ControlServer {
+    /// All instruments of exchanges, which could be subscribed
+    instruments: Arc<HashMap<String, Instrument>>,
+    control: SubscriptionControl,
+    queries: Sender<Query>,
+    /// Resync is passed along with market data, so that it's ordered with updates
+    updates: Sender<MDResponse>,
+}
+
+impl ControlServer {
+    pub fn new(
+        instruments: &[Instrument],
+        control: SubscriptionControl,
+        queries: Sender<Query>,
+        updates: Sender<MDResponse>,
+    ) -> ControlServer {
+        ControlServer {
+            instruments: Arc::new(
+                instruments
+                    .iter()
+                    .map(|inst| (inst.to_raw_string().clone(), inst.clone()))
+                    .collect(),
+            ),
+            control,
+            queries,
+            updates,
+        }
+    }
+
+    /// Socket left behind by a killed process is replaced,
+    /// any other file at `path` is an error, as it's likely a typo.
+    fn remove_stale_socket(path: &str) -> std::io::Result<()> {
+        match std::fs::symlink_metadata(path) {
+            Ok(meta) if meta.file_type().is_socket() => std::fs::remove_file(path),
+            Ok(_) => Err(std::io::Error::new(
+                ErrorKind::AlreadyExists,
+                format!("{} exists and isn't a socket", path),
+            )),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(()),
+            Err(err) => Err(err),
+        }
+    }
+
+    pub fn spawn(self, path: String) -> std::io::Result<JoinHandle<()>> {
+        Self::remove_stale_socket(&path)?;
+        let listener = UnixListener::bind(&path)?;
+        info!("Control socket listens on {}", path);
+        let server = Arc::new(self);
+        Ok(tokio::spawn(async move {
+            loop {
+                match listener.accept().await {
+                    Ok((stream, _)) => {
+                        tokio::spawn(server.clone().handle_client(stream));
+                    }
+                    Err(err) => error!("Failed to accept control connection: {}", err),
+                }
+            }
+        }))
+    }
+
+    async fn handle_client(self: Arc<Self>, stream: UnixStream) {
+        let (reader, mut writer) = stream.into_split();
+        let mut lines = BufReader::new(reader).lines();
+        while let Ok(Some(line)) = lines.next_line().await {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let resp = match Request::parse(&line) {
+                Ok(req) => self.execute(req).await,
+                Err(err) => json!({ "error": err }),
+            };
+            if writer
+                .write_all(format!("{}\n", resp).as_bytes())
+                .await
+                .is_err()
+            {
+                return;
+            }
+        }
+    }
+
+    fn resolve(&self, symbol: &str) -> Result<Instrument, Value> {
+        self.instruments
+            .get(symbol)
+            .cloned()
+            .ok_or_else(|| json!({"error": format!("unknown symbol {}", symbol)}))
+    }
+
+    fn resolve_all(&self, symbols: &[String]) -> Result<Vec<Instrument>, Value> {
+        symbols.iter().map(|s| self.resolve(s)).collect()
+    }
+
+    async fn query<F>(&self, query: F) -> Value
+    where
+        F: FnOnce(oneshot::Sender<Value>) -> Query,
+    {
+        let (tx, rx) = oneshot::channel();
+        if self.queries.send(query(tx)).await.is_err() {
+            return json!({"error": "main loop is stopped"});
+        }
+        rx.await
+            .unwrap_or_else(|_| json!({"error": "query is dropped"}))
+    }
+
+    async fn execute(&self, req: Request) -> Value {
+        let result = match req {
+            Request::List => return self.query(Query::List).await,
+            Request::Book(symbol) => match self.resolve(&symbol) {
+                Ok(inst) => return self.query(|tx| Query::Book(inst, tx)).await,
+                Err(err) => Err(err),
+            },
+            Request::Resync(symbol) => match self.resolve(&symbol) {
+                Ok(inst) => {
+                    let resp = self.query(|tx| Query::Resync(inst.clone(), tx)).await;
+                    if resp.get("error").is_none()
+                        && self.updates.send(MDResponse::Resync(inst)).await.is_err()
+                    {
+                        warn!("Main loop is stopped, resync is dropped");
+                    }
+                    return resp;
+                }
+                Err(err) => Err(err),
+            },
+            Request::Subscribe(symbols) => self.resolve_all(&symbols).map(|insts| {
+                self.control.subscribe(insts);
+                json!({ "subscribe": symbols })
+            }),
+            Request::Unsubscribe(symbols) => self.resolve_all(&symbols).map(|insts| {
+                self.control.unsubscribe(insts);
+                json!({ "unsubscribe": symbols })
+            }),
+        };
+        result.unwrap_or_else(|err| err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::control::{ControlServer, Query, Request};
+
+    use crate::subscription::{Command, SubscriptionControl};
+    use crate::test_support::inst;
+    use serde_json::{json, Value};
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::UnixStream;
+    use tokio::sync::mpsc;
+
+    #[test]
+    fn parse_request() {
+        assert_eq!(Request::parse("list"), Ok(Request::List));
+        assert_eq!(
+            Request::parse("subscribe btcusdt ETHUSDT"),
+            Ok(Request::Subscribe(vec!["BTCUSDT".into(), "ETHUSDT".into()]))
+        );
+        assert_eq!(
+            Request::parse(" book  BTCUSDT "),
+            Ok(Request::Book("BTCUSDT".into()))
+        );
+        assert!(Request::parse("resync").is_err());
+        assert!(Request::parse("unsubscribe").is_err());
+        assert!(Request::parse("restart").is_err());
+    }
+
+    #[tokio::test]
+    async fn control_socket() {
+        let path = std::env::temp_dir()
+            .join(format!("market_data_control_{}.sock", std::process::id()))
+            .to_string_lossy()
+            .to_string();
+        let control = SubscriptionControl::default();
+        let mut commands = control.listen();
+        let (query_tx, mut query_rx) = mpsc::channel(10);
+        let (update_tx, _update_rx) = mpsc::channel(10);
+        let server = ControlServer::new(&[inst(0.01, 0.1)], control, query_tx, update_tx);
+        let handle = server.spawn(path.clone()).unwrap();
+
+        tokio::spawn(async move {
+            while let Some(query) = query_rx.recv().await {
+                if let Query::List(reply) = query {
+                    reply.send(json!({"instruments": []})).unwrap();
+                }
+            }
+        });
+
+        let stream = UnixStream::connect(&path).await.unwrap();
+        let (reader, mut writer) = stream.into_split();
+        let mut lines = BufReader::new(reader).lines();
+        writer
+            .write_all(b"list\nsubscribe BTCUSDT\nsubscribe DOGEUSDT\n")
+            .await
+            .unwrap();
+        let mut resp = vec![];
+        for _ in 0..3 {
+            let line = lines.next_line().await.unwrap().unwrap();
+            resp.push(serde_json::from_str::<Value>(&line).unwrap());
+        }
+        assert_eq!(resp[0], json!({"instruments": []}));
+        assert_eq!(resp[1], json!({"subscribe": ["BTCUSDT"]}));
+        assert!(resp[2].get("error").is_some());
+
+        let Command::Subscribe(insts) = commands.recv().await.unwrap() else {
+            panic!("subscribe expected");
+        };
+        assert_eq!(insts, vec![inst(0.01, 0.1)]);
+        handle.abort();
+
+        // stale socket is replaced, but regular file is kept
+        let (query_tx, _) = mpsc::channel(10);
+        let (update_tx, _) = mpsc::channel(10);
+        let server = ControlServer::new(&[], SubscriptionControl::default(), query_tx, update_tx);
+        server.spawn(path.clone()).unwrap().abort();
+        std::fs::remove_file(&path).unwrap();
+        std::fs::write(&path, "data").unwrap();
+        let (query_tx, _) = mpsc::channel(10);
+        let (update_tx, _) = mpsc::channel(10);
+        let server = ControlServer::new(&[], SubscriptionControl::default(), query_tx, update_tx);
+        assert!(server.spawn(path.clone()).is_err());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "data");
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,96 @@
+use std::fmt;
+use std::fmt::{Display, Formatter};
+
+#[derive(Debug)]
+pub enum Error {
+    /// Request couldn't be sent or response couldn't be received
+    #[cfg(feature = "binance")]
+    Network(reqwest_middleware::Error),
+    /// Unsuccessful HTTP status without error payload
+    Status {
+        status: u16,
+        body: String,
+    },
+    /// Error reported by exchange in response payload
+    Exchange {
+        status: u16,
+        code: i64,
+        msg: String,
+    },
+    /// Response has unexpected format
+    Decode(String),
+    /// Instrument description lacks required filter
+    MissingFilter {
+        symbol: String,
+        filter: String,
+    },
+    InvalidUrl(String),
+    /// Local resource, e.g. sink or capture file, couldn't be opened
+    Io(std::io::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    /// Whether the same request may succeed later.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            #[cfg(feature = "binance")]
+            Error::Network(_) => true,
+            Error::Status { status, .. } | Error::Exchange { status, .. } => {
+                *status >= 500 || *status == 429
+            }
+            Error::Decode(_)
+            | Error::MissingFilter { .. }
+            | Error::InvalidUrl(_)
+            | Error::Io(_) => false,
+        }
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            #[cfg(feature = "binance")]
+            Error::Network(err) => write!(f, "network error: {err}"),
+            Error::Status { status, body } => write!(f, "HTTP status {status}: {body}"),
+            Error::Exchange { status, code, msg } => {
+                write!(f, "exchange error {code} (HTTP status {status}): {msg}")
+            }
+            Error::Decode(msg) => write!(f, "failed to decode response: {msg}"),
+            Error::MissingFilter { symbol, filter } => {
+                write!(f, "filter {filter} not found for {symbol}")
+            }
+            Error::InvalidUrl(msg) => write!(f, "invalid url: {msg}"),
+            Error::Io(err) => write!(f, "io error: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+#[cfg(feature = "binance")]
+impl From<reqwest_middleware::Error> for Error {
+    fn from(err: reqwest_middleware::Error) -> Self {
+        Error::Network(err)
+    }
+}
+
+#[cfg(feature = "binance")]
+impl From<reqwest::Error> for Error {
+    fn from(err: reqwest::Error) -> Self {
+        Error::Network(reqwest_middleware::Error::Reqwest(err))
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(err: serde_json::Error) -> Self {
+        Error::Decode(err.to_string())
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Self {
+        Error::Io(err)
+    }
+}
@@ -0,0 +1,310 @@
+use crate::arbiter::{Arbiter, ConnId};
+use crate::audit::Auditor;
+use crate::capture::Recorder;
+use crate::config::MDConfig;
+use crate::control::ControlServer;
+use crate::error::Result;
+use crate::hub::BookHub;
+use crate::lob::orderbooks::DepthBookManager;
+use crate::output::BookUpdate;
+use crate::runner::Runner;
+#[cfg(feature = "binance")]
+use crate::scheme::binance::Api;
+use crate::scheme::connector::MarketQueries;
+use crate::sink::{BookSink, SinkFilter, Sinks, SINK_QUEUE_SIZE};
+use crate::snapshot::HTTPExchanges;
+#[cfg(feature = "binance")]
+use crate::structure::Exchange;
+use crate::structure::{Instrument, MDResponse};
+use crate::subscription::SubscriptionControl;
+use async_trait::async_trait;
+use futures_util::future::join_all;
+use futures_util::Stream;
+use log::{debug, error};
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+type WssExchanges = Vec<Arc<dyn MarketQueries + Send + Sync>>;
+
+/// Settings of the feed handler, sinks of the config are opened on start.
+pub struct FeedBuilder {
+    cfg: MDConfig,
+    instruments: Vec<String>,
+    num_conn: usize,
+    control_socket: Option<String>,
+    sinks: Sinks,
+}
+
+impl FeedBuilder {
+    pub fn new(cfg: MDConfig) -> FeedBuilder {
+        FeedBuilder {
+            cfg,
+            instruments: vec![],
+            num_conn: 1,
+            control_socket: None,
+            sinks: Sinks::default(),
+        }
+    }
+
+    /// Raw symbols subscribed on start, e.g. "BTCUSDT".
+    pub fn instruments<I, S>(mut self, insts: I) -> FeedBuilder
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.instruments = insts.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Number of redundant connections to every exchange.
+    pub fn connections(mut self, num_conn: usize) -> FeedBuilder {
+        self.num_conn = num_conn;
+        self
+    }
+
+    /// Unix socket for control commands.
+    pub fn control_socket(mut self, path: impl Into<String>) -> FeedBuilder {
+        self.control_socket = Some(path.into());
+        self
+    }
+
+    /// Additional sink, see `Sinks::add`.
+    pub fn sink(
+        mut self,
+        name: &str,
+        sink: Box<dyn BookSink>,
+        filter: SinkFilter,
+        queue_size: usize,
+    ) -> FeedBuilder {
+        self.sinks.add(name, sink, filter, queue_size);
+        self
+    }
+
+    /// APIs of configured exchanges, which are enabled by features.
+    #[cfg_attr(not(feature = "binance"), allow(unused_variables, unused_mut))]
+    fn exchanges(
+        cfg: &MDConfig,
+        recorder: Option<&Recorder>,
+    ) -> Result<(WssExchanges, HTTPExchanges)> {
+        let mut wss: WssExchanges = vec![];
+        let mut http: HTTPExchanges = vec![];
+        #[cfg(feature = "binance")]
+        if let Some(binance_cfg) = cfg.get(Exchange::BINANCE) {
+            let mut binance = Api::new(binance_cfg.clone())?.with_books(cfg.book.clone());
+            if let Some(recorder) = recorder {
+                binance = binance.with_recorder(recorder.clone());
+            }
+            let binance = Arc::new(binance);
+            wss.push(binance.clone());
+            http.push((Exchange::BINANCE, binance));
+        }
+        Ok((wss, http))
+    }
+
+    /// `num_conn` redundant connections to every exchange, which pass updates to `sender`.
+    #[cfg_attr(not(feature = "binance"), allow(unused_variables, unused_mut))]
+    fn connect(
+        exchanges: &WssExchanges,
+        num_conn: usize,
+        sender: mpsc::Sender<(ConnId, MDResponse)>,
+        insts: &[Instrument],
+        control: &SubscriptionControl,
+        recorder: Option<&Recorder>,
+    ) -> Vec<JoinHandle<()>> {
+        let mut handles = vec![];
+        #[cfg(feature = "binance")]
+        for exch in exchanges {
+            for _ in 0..num_conn {
+                handles.push(Runner::create_connection(
+                    handles.len(),
+                    exch.clone(),
+                    sender.clone(),
+                    insts.to_vec(),
+                    control.listen(),
+                    recorder.cloned(),
+                ));
+            }
+        }
+        handles
+    }
+
+    pub async fn start(mut self) -> Result<Feed> {
+        let mut handles: Vec<JoinHandle<()>> = vec![];
+        let recorder = match &self.cfg.capture {
+            Some(capture_cfg) => {
+                let (recorder, handle) = Recorder::start(capture_cfg).await?;
+                handles.push(handle);
+                Some(recorder)
+            }
+            None => None,
+        };
+        let (wss_exchanges, http_exchanges) = Self::exchanges(&self.cfg, recorder.as_ref())?;
+
+        let instruments: Vec<Instrument> =
+            join_all(http_exchanges.iter().map(|(exchange, exch)| async move {
+                match Runner::instrument_info(exch.as_ref()).await {
+                    Ok(insts) => insts,
+                    Err(err) => {
+                        error!("Failed to get instruments of {:?}: {}", exchange, err);
+                        vec![]
+                    }
+                }
+            }))
+            .await
+            .into_iter()
+            .flatten()
+            .collect();
+        let subscribed: Vec<Instrument> = instruments
+            .iter()
+            .filter(|inst| self.instruments.contains(inst.to_raw_string()))
+            .cloned()
+            .collect();
+        debug!("{:?}", &subscribed);
+
+        let (tx, rx) = mpsc::channel(100);
+        let control = SubscriptionControl::default();
+        let (conn_tx, conn_rx) = mpsc::channel(100);
+        let connections = Self::connect(
+            &wss_exchanges,
+            self.num_conn,
+            conn_tx,
+            &subscribed,
+            &control,
+            recorder.as_ref(),
+        );
+        let num_conn = connections.len();
+        handles.extend(connections);
+        handles.push(Runner::spawn_arbiter(
+            conn_rx,
+            tx.clone(),
+            Arbiter::new(num_conn),
+        ));
+
+        for sink_cfg in self.cfg.sinks() {
+            self.sinks.open(sink_cfg).await?;
+        }
+
+        let (query_tx, query_rx) = mpsc::channel(10);
+        if let Some(path) = self.control_socket {
+            let server = ControlServer::new(&instruments, control.clone(), query_tx, tx.clone());
+            handles.push(server.spawn(path)?);
+        }
+
+        let hub = BookHub::default();
+        let auditor = self
+            .cfg
+            .audit
+            .clone()
+            .map(|audit_cfg| Auditor::new(audit_cfg, http_exchanges.clone()));
+        handles.push(Runner::spawn_main_loop(
+            http_exchanges,
+            tx,
+            rx,
+            DepthBookManager::new(&subscribed, self.cfg.book.clone()),
+            control.clone(),
+            query_rx,
+            (self.sinks, hub.clone(), auditor, recorder),
+        ));
+        Ok(Feed {
+            instruments,
+            subscribed,
+            control,
+            hub,
+            handles,
+        })
+    }
+
+    /// Start the feed and receive its updates as a stream,
+    /// updates are dropped if the stream isn't polled fast enough.
+    pub async fn stream(self) -> Result<BookStream> {
+        let (sender, updates) = mpsc::channel(SINK_QUEUE_SIZE);
+        let feed = self
+            .sink(
+                "stream",
+                Box::new(ChannelSink(sender)),
+                SinkFilter::default(),
+                SINK_QUEUE_SIZE,
+            )
+            .start()
+            .await?;
+        Ok(BookStream { feed, updates })
+    }
+}
+
+/// Running feed handler, its tasks are stopped once it's dropped.
+pub struct Feed {
+    instruments: Vec<Instrument>,
+    subscribed: Vec<Instrument>,
+    control: SubscriptionControl,
+    hub: BookHub,
+    handles: Vec<JoinHandle<()>>,
+}
+
+impl Feed {
+    /// All instruments of the exchanges
+    pub fn instruments(&self) -> &[Instrument] {
+        &self.instruments
+    }
+
+    /// Instruments subscribed on start
+    pub fn subscribed(&self) -> &[Instrument] {
+        &self.subscribed
+    }
+
+    /// Handle to change subscriptions at runtime.
+    pub fn control(&self) -> &SubscriptionControl {
+        &self.control
+    }
+
+    /// Handle to subscribe to book updates from any number of tasks.
+    pub fn books(&self) -> &BookHub {
+        &self.hub
+    }
+
+    /// Wait until all tasks are finished.
+    pub async fn join(mut self) {
+        join_all(std::mem::take(&mut self.handles)).await;
+    }
+}
+
+impl Drop for Feed {
+    fn drop(&mut self) {
+        self.handles.iter().for_each(JoinHandle::abort);
+    }
+}
+
+struct ChannelSink(mpsc::Sender<BookUpdate>);
+
+#[async_trait]
+impl BookSink for ChannelSink {
+    async fn write(&mut self, update: &BookUpdate) -> io::Result<()> {
+        self.0
+            .send(update.clone())
+            .await
+            .map_err(|_| io::ErrorKind::BrokenPipe.into())
+    }
+}
+
+/// Book updates of a running feed.
+pub struct BookStream {
+    feed: Feed,
+    updates: mpsc::Receiver<BookUpdate>,
+}
+
+impl BookStream {
+    pub fn feed(&self) -> &Feed {
+        &self.feed
+    }
+}
+
+impl Stream for BookStream {
+    type Item = BookUpdate;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<BookUpdate>> {
+        self.updates.poll_recv(cx)
+    }
+}
@@ -0,0 +1,264 @@
+use crate::common::{Id, Level, Price, Qty};
+use crate::lob::order_book::OrderBook;
+use crate::output::BookUpdate;
+use crate::structure::Instrument;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::{broadcast, watch};
+
+/// Number of level changes a subscriber may fall behind before it lags.
+pub const CHANGES_QUEUE_SIZE: usize = 1024;
+
+/// Best levels of a book.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TopOfBook {
+    pub last_update_id: Id,
+    pub time: u64,
+    pub stale: bool,
+    pub bid: Option<Level>,
+    pub ask: Option<Level>,
+}
+
+impl From<&BookUpdate> for TopOfBook {
+    fn from(update: &BookUpdate) -> Self {
+        TopOfBook {
+            last_update_id: update.last_update_id.clone(),
+            time: update.time,
+            stale: update.stale,
+            bid: update.bids.first().cloned(),
+            ask: update.asks.first().cloned(),
+        }
+    }
+}
+
+/// Levels changed by an update relative to the previously published book,
+/// zero quantity means the level is removed, e.g. pushed out of the depth limit.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LevelChanges {
+    pub last_update_id: Id,
+    pub time: u64,
+    /// From the best to the worst
+    pub bids: Vec<Level>,
+    pub asks: Vec<Level>,
+}
+
+impl LevelChanges {
+    fn between(prev: Option<&BookUpdate>, next: &BookUpdate) -> LevelChanges {
+        let (bids, asks) = prev.map_or((&[][..], &[][..]), |prev| (&prev.bids, &prev.asks));
+        LevelChanges {
+            last_update_id: next.last_update_id.clone(),
+            time: next.time,
+            bids: Self::diff(bids, &next.bids, |a, b| b.cmp(a)),
+            asks: Self::diff(asks, &next.asks, |a, b| a.cmp(b)),
+        }
+    }
+
+    /// Merge of two sides ordered from the best level by `order`.
+    fn diff<F>(prev: &[Level], next: &[Level], order: F) -> Vec<Level>
+    where
+        F: Fn(&Price, &Price) -> Ordering,
+    {
+        let (mut prev, mut next) = (prev.iter().peekable(), next.iter().peekable());
+        let mut changes = vec![];
+        loop {
+            let ord = match (prev.peek(), next.peek()) {
+                (None, None) => return changes,
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (Some(p), Some(n)) => order(&p.price, &n.price),
+            };
+            match ord {
+                Ordering::Less => {
+                    let removed = prev.next().expect("level is peeked");
+                    changes.push(Level::new(removed.price, Qty(0)));
+                }
+                Ordering::Greater => changes.push(next.next().expect("level is peeked").clone()),
+                Ordering::Equal => {
+                    let (p, n) = (prev.next(), next.next().expect("level is peeked"));
+                    if p.map(|p| p.qty) != Some(n.qty) {
+                        changes.push(n.clone());
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bids.is_empty() && self.asks.is_empty()
+    }
+}
+
+struct Channels {
+    book: watch::Sender<Option<Arc<BookUpdate>>>,
+    top: watch::Sender<Option<TopOfBook>>,
+    changes: broadcast::Sender<Arc<LevelChanges>>,
+}
+
+impl Channels {
+    fn new() -> Channels {
+        Channels {
+            book: watch::channel(None).0,
+            top: watch::channel(None).0,
+            changes: broadcast::channel(CHANGES_QUEUE_SIZE).0,
+        }
+    }
+
+    fn is_unused(&self) -> bool {
+        self.book.receiver_count() == 0
+            && self.top.receiver_count() == 0
+            && self.changes.receiver_count() == 0
+    }
+}
+
+/// Handle to receive book updates in the same process. Publishing never waits
+/// for subscribers: the book and top of book keep only the latest value,
+/// a subscriber of level changes lags once it falls behind by `CHANGES_QUEUE_SIZE`
+/// and should start over from the latest book.
+#[derive(Clone, Default)]
+pub struct BookHub {
+    channels: Arc<Mutex<HashMap<Instrument, Channels>>>,
+}
+
+impl BookHub {
+    fn subscribe<T, F>(&self, inst: &Instrument, f: F) -> T
+    where
+        F: FnOnce(&Channels) -> T,
+    {
+        let mut channels = self.channels.lock().unwrap();
+        f(channels.entry(inst.clone()).or_insert_with(Channels::new))
+    }
+
+    /// Latest state of the book, `None` until the book is updated after the first subscription.
+    pub fn book(&self, inst: &Instrument) -> watch::Receiver<Option<Arc<BookUpdate>>> {
+        self.subscribe(inst, |ch| ch.book.subscribe())
+    }
+
+    /// Best levels, changed only if the best price or quantity of either side changes.
+    pub fn top(&self, inst: &Instrument) -> watch::Receiver<Option<TopOfBook>> {
+        self.subscribe(inst, |ch| ch.top.subscribe())
+    }
+
+    /// Levels changed by every update after the subscription.
+    pub fn changes(&self, inst: &Instrument) -> broadcast::Receiver<Arc<LevelChanges>> {
+        self.subscribe(inst, |ch| ch.changes.subscribe())
+    }
+
+    /// Pass the state of the book to subscribers, does nothing if there are none.
+    pub fn publish(&self, inst: &Instrument, book: &OrderBook) {
+        let mut channels = self.channels.lock().unwrap();
+        let Some(ch) = channels.get(inst) else {
+            return;
+        };
+        if ch.is_unused() {
+            channels.remove(inst);
+            return;
+        }
+        let update = Arc::new(BookUpdate::new(inst, book));
+        if ch.changes.receiver_count() > 0 {
+            let changes = LevelChanges::between(ch.book.borrow().as_deref(), &update);
+            if !changes.is_empty() {
+                let _ = ch.changes.send(Arc::new(changes));
+            }
+        }
+        let top = TopOfBook::from(update.as_ref());
+        ch.top.send_if_modified(|current| match current {
+            Some(current)
+                if (current.stale, &current.bid, &current.ask)
+                    == (top.stale, &top.bid, &top.ask) =>
+            {
+                false
+            }
+            _ => {
+                *current = Some(top);
+                true
+            }
+        });
+        ch.book.send_replace(Some(update));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::common::{Id, Level};
+    use crate::hub::{BookHub, CHANGES_QUEUE_SIZE};
+    use crate::lob::order_book::OrderBook;
+    use crate::structure::{Delta, Instrument, MDResponse, Snapshot};
+    use crate::test_support::inst;
+    use tokio::sync::broadcast::error::TryRecvError;
+
+    fn delta(inst: &Instrument, id: u64, buy: Vec<Level>, sell: Vec<Level>) -> MDResponse {
+        MDResponse::Delta(Delta::new(
+            inst.clone(),
+            buy,
+            sell,
+            Id(id),
+            Id(id),
+            Id(id - 1),
+            id,
+        ))
+    }
+
+    #[tokio::test]
+    async fn subscriptions() {
+        let inst = inst(0.01, 0.1);
+        let hub = BookHub::default();
+        let mut book = OrderBook::new(inst.precision.clone(), 2);
+        let (mut top, mut changes, full) = (hub.top(&inst), hub.changes(&inst), hub.book(&inst));
+        let buy = vec![
+            Level::from_float_pair(10., 1.),
+            Level::from_float_pair(9.9, 2.),
+        ];
+        let sell = vec![Level::from_float_pair(10.1, 1.)];
+        let _ = book.apply(delta(&inst, 1000, vec![], vec![]));
+        let snapshot = Snapshot::new(inst.clone(), buy, sell, Id(1000), 1);
+        hub.publish(&inst, book.apply(MDResponse::Snapshot(snapshot)).unwrap());
+        assert!(top.has_changed().unwrap());
+        top.mark_unchanged();
+
+        // new level pushes the worst one out of the depth limit
+        let upd = delta(&inst, 1001, vec![Level::from_float_pair(9.95, 3.)], vec![]);
+        hub.publish(&inst, book.apply(upd).unwrap());
+        assert!(!top.has_changed().unwrap());
+        assert_eq!(full.borrow().as_ref().unwrap().last_update_id, Id(1001));
+
+        assert_eq!(changes.try_recv().unwrap().bids.len(), 2);
+        let last = changes.try_recv().unwrap();
+        assert_eq!(
+            last.bids,
+            vec![
+                Level::from_float_pair(9.95, 3.),
+                Level::from_float_pair(9.9, 0.)
+            ]
+        );
+        assert!(last.asks.is_empty());
+
+        let upd = delta(&inst, 1002, vec![], vec![Level::from_float_pair(10.1, 2.)]);
+        hub.publish(&inst, book.apply(upd).unwrap());
+        let best_ask = top.borrow_and_update().as_ref().unwrap().ask.clone();
+        assert_eq!(best_ask, Some(Level::from_float_pair(10.1, 2.)));
+    }
+
+    #[tokio::test]
+    async fn slow_subscriber() {
+        let inst = inst(0.01, 0.1);
+        let hub = BookHub::default();
+        let mut book = OrderBook::new(inst.precision.clone(), 10);
+        let mut changes = hub.changes(&inst);
+        let _ = book.apply(delta(&inst, 1000, vec![], vec![]));
+        let snapshot = Snapshot::new(inst.clone(), vec![], vec![], Id(1000), 1);
+        let _ = book.apply(MDResponse::Snapshot(snapshot));
+        let last = 1000 + CHANGES_QUEUE_SIZE as u64 + 10;
+        for id in 1001..=last {
+            let qty = Level::from_float_pair(10., id as f64);
+            hub.publish(
+                &inst,
+                book.apply(delta(&inst, id, vec![qty], vec![])).unwrap(),
+            );
+        }
+        assert!(matches!(changes.try_recv(), Err(TryRecvError::Lagged(_))));
+        // latest book is always available to start over
+        let latest = hub.book(&inst);
+        assert_eq!(latest.borrow().as_ref().unwrap().last_update_id, Id(last));
+    }
+}
@@ -0,0 +1,44 @@
+//! Local order books of Binance futures maintained from WebSocket depth streams.
+//!
+//! `FeedBuilder` starts the whole pipeline and passes book updates to sinks
+//! or a `Stream`, lower level parts can be used on their own, e.g. `OrderBook`
+//! fed by own transport.
+
+pub(crate) mod arbiter;
+pub mod audit;
+mod backoff;
+// frames are recorded only by exchange connections
+#[cfg_attr(not(feature = "binance"), allow(dead_code))]
+pub(crate) mod capture;
+pub mod common;
+pub mod config;
+#[cfg(feature = "binance")]
+mod connection;
+pub(crate) mod control;
+pub mod error;
+pub mod feed;
+pub mod hub;
+pub mod lob;
+#[cfg(feature = "mock")]
+pub mod mock;
+pub mod output;
+#[cfg(feature = "replay")]
+pub mod replay;
+pub(crate) mod runner;
+pub mod scheme;
+pub mod sink;
+pub(crate) mod snapshot;
+pub mod structure;
+pub(crate) mod subscription;
+#[cfg(test)]
+pub(crate) mod test_support;
+
+pub use error::{Error, Result};
+pub use feed::{BookStream, Feed, FeedBuilder};
+pub use hub::BookHub;
+pub use lob::order_book::OrderBook;
+pub use lob::orderbooks::DepthBookManager;
+pub use output::BookUpdate;
+pub use scheme::connector::{HTTPApi, MarketQueries};
+pub use structure::Instrument;
+pub use subscription::{Command, SubscriptionControl};
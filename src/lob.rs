@@ -1,2 +1,4 @@
-pub(crate) mod order_book;
+pub mod analytics;
+pub mod impact;
+pub mod order_book;
 pub mod orderbooks;
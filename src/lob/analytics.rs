@@ -0,0 +1,246 @@
+use crate::common::{Level, Price, Qty};
+use crate::lob::order_book::OrderBook;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::fmt::{Display, Formatter};
+
+const BPS: f64 = 10_000.;
+
+/// Parameters of analytics attached to book updates.
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct AnalyticsConfig {
+    /// Number of best levels of each side used for imbalance
+    pub levels: usize,
+    /// Distance from mid for cumulative depth
+    pub depth_bps: f64,
+}
+
+impl Default for AnalyticsConfig {
+    fn default() -> Self {
+        AnalyticsConfig {
+            levels: 5,
+            depth_bps: 10.,
+        }
+    }
+}
+
+fn volume<'a>(levels: impl Iterator<Item = &'a Level>) -> Qty {
+    levels.fold(Qty(0), |sum, lvl| sum + lvl.qty)
+}
+
+/// Measures derived from the current levels, trade adjustments included.
+/// Values relative to the best prices of both sides are `None` if either side is empty,
+/// imbalance is `None` only if both are.
+impl OrderBook {
+    pub fn best_bid(&self) -> Option<&Level> {
+        self.buy().best()
+    }
+
+    pub fn best_ask(&self) -> Option<&Level> {
+        self.sell().best()
+    }
+
+    fn best(&self) -> Option<(&Level, &Level)> {
+        Some((self.best_bid()?, self.best_ask()?))
+    }
+
+    /// Middle of the best prices, may be between ticks.
+    pub fn mid(&self) -> Option<Price> {
+        let (bid, ask) = self.best()?;
+        Some(Price((bid.price.0 + ask.price.0) / 2))
+    }
+
+    pub fn spread(&self) -> Option<Price> {
+        let (bid, ask) = self.best()?;
+        Some(ask.price - bid.price)
+    }
+
+    pub fn spread_ticks(&self) -> Option<i64> {
+        self.spread()?.ticks(&self.precision().price).ok()
+    }
+
+    pub fn spread_bps(&self) -> Option<f64> {
+        Some(self.spread()?.as_f64() / self.mid()?.as_f64() * BPS)
+    }
+
+    /// Mid weighted by the best quantities, so that it leans to the side with less liquidity.
+    pub fn microprice(&self) -> Option<f64> {
+        let (bid, ask) = self.best()?;
+        let (bid_qty, ask_qty) = (bid.qty.as_f64(), ask.qty.as_f64());
+        Some((bid.price.as_f64() * ask_qty + ask.price.as_f64() * bid_qty) / (bid_qty + ask_qty))
+    }
+
+    /// Difference of bid and ask volume over `levels` best levels relative to their sum,
+    /// from -1 when only asks are present to 1 when only bids are.
+    pub fn imbalance(&self, levels: usize) -> Option<f64> {
+        let bids = volume(self.buy().iter().take(levels)).as_f64();
+        let asks = volume(self.sell().iter().take(levels)).as_f64();
+        if bids + asks == 0. {
+            return None;
+        }
+        Some((bids - asks) / (bids + asks))
+    }
+
+    /// Cumulative bid and ask quantity priced within `bps` of mid.
+    pub fn depth_within_bps(&self, bps: f64) -> Option<(Qty, Qty)> {
+        let tick = &self.precision().price;
+        let (bid, ask) = self.best()?;
+        // in half ticks, as mid may be between ticks
+        let mid = bid.price.ticks(tick).ok()? + ask.price.ticks(tick).ok()?;
+        let distance = (mid as f64 * bps / BPS) as i64;
+        let half_ticks = |lvl: &Level| lvl.price.ticks(tick).map(|ticks| 2 * ticks);
+        let bids = self
+            .buy()
+            .iter()
+            .take_while(|l| half_ticks(l).is_ok_and(|price| price >= mid - distance));
+        let asks = self
+            .sell()
+            .iter()
+            .take_while(|l| half_ticks(l).is_ok_and(|price| price <= mid + distance));
+        Some((volume(bids), volume(asks)))
+    }
+}
+
+/// Snapshot of analytics, which is passed to sinks along with the book.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Analytics {
+    pub mid: Option<Price>,
+    pub spread_ticks: Option<i64>,
+    pub spread_bps: Option<f64>,
+    pub microprice: Option<f64>,
+    pub imbalance: Option<f64>,
+    pub bid_depth: Option<Qty>,
+    pub ask_depth: Option<Qty>,
+}
+
+impl Analytics {
+    pub fn new(book: &OrderBook, cfg: &AnalyticsConfig) -> Analytics {
+        let depth = book.depth_within_bps(cfg.depth_bps);
+        Analytics {
+            mid: book.mid(),
+            spread_ticks: book.spread_ticks(),
+            spread_bps: book.spread_bps(),
+            microprice: book.microprice(),
+            imbalance: book.imbalance(cfg.levels),
+            bid_depth: depth.map(|(bids, _)| bids),
+            ask_depth: depth.map(|(_, asks)| asks),
+        }
+    }
+
+    /// Prices and quantities are decimal strings as in levels.
+    pub fn to_json(&self) -> Value {
+        let decimal = |value: Option<String>| value.map_or(Value::Null, Value::from);
+        json!({
+            "mid": decimal(self.mid.map(|p| p.to_string())),
+            "spread_ticks": self.spread_ticks,
+            "spread_bps": self.spread_bps,
+            "microprice": self.microprice,
+            "imbalance": self.imbalance,
+            "bid_depth": decimal(self.bid_depth.map(|q| q.to_string())),
+            "ask_depth": decimal(self.ask_depth.map(|q| q.to_string())),
+        })
+    }
+
+    /// Values in the order of `CSV_COLUMNS`, empty if not available.
+    pub fn to_csv(&self) -> String {
+        fn field<T: ToString>(value: Option<T>) -> String {
+            value.map_or(String::new(), |v| v.to_string())
+        }
+        [
+            field(self.mid),
+            field(self.spread_ticks),
+            field(self.spread_bps),
+            field(self.microprice),
+            field(self.imbalance),
+            field(self.bid_depth),
+            field(self.ask_depth),
+        ]
+        .join(",")
+    }
+
+    pub const CSV_COLUMNS: &'static str =
+        "mid,spread_ticks,spread_bps,microprice,imbalance,bid_depth,ask_depth";
+}
+
+impl Display for Analytics {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        fn field<T: Display>(value: &Option<T>) -> String {
+            value.as_ref().map_or("-".to_string(), |v| v.to_string())
+        }
+        write!(
+            f,
+            "mid {} spread {} ticks {} bps microprice {} imbalance {} depth {}/{}",
+            field(&self.mid),
+            field(&self.spread_ticks),
+            field(&self.spread_bps.map(|bps| format!("{:.2}", bps))),
+            field(&self.microprice),
+            field(&self.imbalance),
+            field(&self.bid_depth),
+            field(&self.ask_depth),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::common::{Level, Price, Qty};
+    use crate::lob::analytics::{Analytics, AnalyticsConfig};
+    use crate::test_support::{inst, synced_book};
+
+    #[test]
+    fn analytics() {
+        let inst = inst(0.5, 0.1);
+        let book = synced_book(
+            &inst,
+            10,
+            vec![
+                Level::from_float_pair(100., 1.),
+                Level::from_float_pair(99.5, 2.),
+                Level::from_float_pair(99., 4.),
+            ],
+            vec![
+                Level::from_float_pair(101., 3.),
+                Level::from_float_pair(102., 1.),
+            ],
+        );
+        assert_eq!(book.best_bid(), Some(&Level::from_float_pair(100., 1.)));
+        assert_eq!(book.mid(), Some(Price::from_f64(100.5)));
+        assert_eq!(book.spread_ticks(), Some(2));
+        assert!((book.spread_bps().unwrap() - 99.502).abs() < 1e-3);
+        // closer to bid, as there's less liquidity
+        assert_eq!(book.microprice(), Some(100.25));
+        assert_eq!(book.imbalance(1), Some(-0.5));
+        assert_eq!(book.imbalance(10), Some(3. / 11.));
+        assert_eq!(
+            book.depth_within_bps(100.),
+            Some((Qty::from_f64(3.), Qty::from_f64(3.)))
+        );
+        // 100 and 101 are 49.75 bps from mid
+        assert_eq!(
+            book.depth_within_bps(50.),
+            Some((Qty::from_f64(1.), Qty::from_f64(3.)))
+        );
+        assert_eq!(book.depth_within_bps(49.5), Some((Qty(0), Qty(0))));
+
+        let cfg = AnalyticsConfig::default();
+        let analytics = Analytics::new(&book, &cfg);
+        let csv = analytics.to_csv();
+        assert!(csv.starts_with("100.5,2,99.502"));
+        assert!(csv.ends_with(",100.25,0.2727272727272727,0,0"));
+        assert_eq!(
+            Analytics::CSV_COLUMNS.split(',').count(),
+            analytics.to_csv().split(',').count()
+        );
+
+        let one_sided = Analytics::new(
+            &synced_book(&inst, 10, vec![Level::from_float_pair(100., 1.)], vec![]),
+            &cfg,
+        );
+        assert_eq!(one_sided.mid, None);
+        assert_eq!(one_sided.bid_depth, None);
+        // doesn't need the other side
+        assert_eq!(one_sided.imbalance, Some(1.));
+        assert_eq!(one_sided.to_csv(), ",,,,1,,");
+    }
+}
@@ -0,0 +1,138 @@
+use crate::common::{Level, Price, Qty, SCALE};
+use crate::lob::order_book::{OrderBook, Side};
+use crate::structure;
+
+/// Result of walking the levels of a book with a market order.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Fill {
+    /// Quantity available in the walked levels, at most the requested one
+    pub qty: Qty,
+    /// Volume weighted average price of `qty`
+    pub avg_price: f64,
+    /// Price of the last level touched
+    pub worst_price: Price,
+    /// Number of levels touched
+    pub levels: usize,
+    /// All visible levels are consumed, the book keeps at most `depth_limit`
+    /// levels, so there may be more liquidity on the exchange
+    pub insufficient: bool,
+}
+
+impl Fill {
+    /// Walk `levels` from the best one taking at most `qty` while `accept` allows the price.
+    fn walk<'a, I, F>(levels: I, qty: Option<Qty>, accept: F) -> Option<Fill>
+    where
+        I: Iterator<Item = &'a Level>,
+        F: Fn(&Price) -> bool,
+    {
+        let mut levels = levels.peekable();
+        let (mut filled, mut notional, mut worst, mut count) = (Qty(0), 0_i128, None, 0);
+        while let Some(lvl) = levels.next_if(|lvl| accept(&lvl.price)) {
+            let take = match qty {
+                Some(qty) => Qty((qty - filled).0.min(lvl.qty.0)),
+                None => lvl.qty,
+            };
+            filled = filled + take;
+            notional += lvl.price.0 as i128 * take.0 as i128;
+            worst = Some(lvl.price);
+            count += 1;
+            if qty.is_some_and(|qty| filled == qty) {
+                break;
+            }
+        }
+        let insufficient = levels.peek().is_none() && qty.is_none_or(|qty| filled < qty);
+        Some(Fill {
+            qty: filled,
+            avg_price: notional as f64 / filled.0 as f64 / SCALE as f64,
+            worst_price: worst?,
+            levels: count,
+            insufficient,
+        })
+    }
+}
+
+/// Market impact against the local book, `side` is the side of the taker,
+/// i.e. buying walks asks. `None` if there are no levels to trade with.
+impl OrderBook {
+    fn taker_levels(&self, side: &structure::Side) -> &Side {
+        match side {
+            structure::Side::Buy => self.sell(),
+            structure::Side::Sell => self.buy(),
+        }
+    }
+
+    /// Average price to trade `qty`, partial if the visible depth is insufficient.
+    pub fn vwap_for_qty(&self, side: structure::Side, qty: Qty) -> Option<Fill> {
+        if qty.0 <= 0 {
+            return None;
+        }
+        Fill::walk(self.taker_levels(&side).iter(), Some(qty), |_| true)
+    }
+
+    /// Quantity tradable without moving the price more than `bps` from the best level,
+    /// `insufficient` means that all visible levels are within the bound.
+    pub fn qty_within_bps(&self, side: structure::Side, bps: f64) -> Option<Fill> {
+        let levels = self.taker_levels(&side);
+        let best = levels.best()?.price;
+        let distance = Price((best.0 as f64 * bps / 10_000.) as i64);
+        Fill::walk(levels.iter(), None, |price| match side {
+            structure::Side::Buy => *price <= best + distance,
+            structure::Side::Sell => *price >= best - distance,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::common::{Level, Price, Qty};
+    use crate::structure::Side;
+    use crate::test_support::{inst, synced_book};
+
+    #[test]
+    fn market_impact() {
+        let inst = inst(0.5, 0.1);
+        let book = synced_book(
+            &inst,
+            3,
+            vec![
+                Level::from_float_pair(100., 1.),
+                Level::from_float_pair(99.5, 2.),
+            ],
+            vec![
+                Level::from_float_pair(101., 1.),
+                Level::from_float_pair(102., 1.),
+                Level::from_float_pair(104., 2.),
+                // beyond the depth limit
+                Level::from_float_pair(105., 10.),
+            ],
+        );
+
+        let fill = book.vwap_for_qty(Side::Buy, Qty::from_f64(1.5)).unwrap();
+        assert_eq!(fill.qty, Qty::from_f64(1.5));
+        assert!((fill.avg_price - 101.333_333).abs() < 1e-6);
+        assert_eq!(fill.worst_price, Price::from_f64(102.));
+        assert_eq!(fill.levels, 2);
+        assert!(!fill.insufficient);
+
+        // exactly the whole visible depth
+        let fill = book.vwap_for_qty(Side::Sell, Qty::from_f64(3.)).unwrap();
+        assert!((fill.avg_price - 99.666_666).abs() < 1e-6);
+        assert!(!fill.insufficient);
+
+        let fill = book.vwap_for_qty(Side::Buy, Qty::from_f64(10.)).unwrap();
+        assert_eq!(fill.qty, Qty::from_f64(4.));
+        assert_eq!(fill.avg_price, 102.75);
+        assert!(fill.insufficient);
+
+        let fill = book.qty_within_bps(Side::Buy, 100.).unwrap();
+        assert_eq!(fill.qty, Qty::from_f64(2.));
+        assert!(!fill.insufficient);
+        let fill = book.qty_within_bps(Side::Sell, 100.).unwrap();
+        assert_eq!(fill.qty, Qty::from_f64(3.));
+        assert!(fill.insufficient);
+
+        let empty = synced_book(&inst, 3, vec![Level::from_float_pair(100., 1.)], vec![]);
+        assert_eq!(empty.vwap_for_qty(Side::Buy, Qty::from_f64(1.)), None);
+        assert_eq!(empty.qty_within_bps(Side::Buy, 10.), None);
+    }
+}
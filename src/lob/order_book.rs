@@ -1,103 +1,180 @@
-use crate::common::{Id, Level, Precision, Price, Qty};
+use crate::backoff::Backoff;
+use crate::common::{Id, Level, Precision, Price, Qty, TickError};
 use crate::structure;
 use crate::structure::{Delta, MDResponse, Snapshot, Trade};
 use log::{debug, info, warn};
 use std::cmp::Ordering;
 use std::collections::BTreeMap;
 use std::fmt::{Display, Formatter};
+use std::time::Duration;
+use tokio::time::Instant;
 
-type LevelsT = Vec<Level>;
-
-#[derive(Default, Debug)]
-struct Side {
-    pub levels: LevelsT,
+/// One side of the book keyed by signed number of ticks, so that iteration
+/// order goes from the best level to the worst one for both buy and sell.
+#[derive(Debug)]
+pub struct Side {
+    levels: BTreeMap<i64, Level>,
+    /// Original levels changed by trades since the last depth update
+    trade_adjusted: BTreeMap<i64, Level>,
+    best: Option<Level>,
+    side: structure::Side,
+    tick: Price,
     limit: usize,
 }
 
 impl Side {
-    pub fn from_vec(levels: Vec<Level>, limit: usize) -> Self {
-        Side { levels, limit }
+    pub fn new(side: structure::Side, tick: Price, limit: usize) -> Self {
+        Side {
+            levels: BTreeMap::new(),
+            trade_adjusted: BTreeMap::new(),
+            best: None,
+            side,
+            tick,
+            limit,
+        }
     }
 
-    fn get_level_id(&self, price: Price, tick_sz: &Precision) -> Option<usize> {
-        if self.levels.len() < 2 {
-            return None;
-        }
+    pub fn from_vec(
+        levels: Vec<Level>,
+        side: structure::Side,
+        tick: Price,
+        limit: usize,
+    ) -> Result<Self, Corruption> {
+        let mut res = Self::new(side, tick, limit);
+        res.update_diff(levels)?;
+        Ok(res)
+    }
+
+    /// Off-tick prices are rejected, as truncation would merge distinct levels.
+    fn key(&self, price: &Price) -> Result<i64, TickError> {
+        let ticks = price.ticks(&self.tick)?;
+        Ok(match self.side {
+            structure::Side::Buy => -ticks,
+            structure::Side::Sell => ticks,
+        })
+    }
+
+    pub fn best(&self) -> Option<&Level> {
+        self.best.as_ref()
+    }
+
+    pub fn get(&self, price: &Price) -> Option<&Level> {
+        self.levels.get(&self.key(price).ok()?)
+    }
+
+    pub fn len(&self) -> usize {
+        self.levels.len()
+    }
 
-        let (idx, _lvl) =
-            self.levels.iter().enumerate().find(|(_idx, lvl)| {
-                ((&lvl).price.clone() - price.clone()).same_tick(&tick_sz.price)
-            })?;
-        Some(idx)
+    pub fn is_empty(&self) -> bool {
+        self.levels.is_empty()
     }
 
-    fn push_level(lvls: &mut LevelsT, lvl: Level, tick: &Qty) -> bool {
-        let need_insert = !lvl.qty.clone().same_tick(tick.clone());
-        if need_insert {
-            lvls.push(lvl);
+    /// Levels from the best price to the worst one.
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = &Level> {
+        self.levels.values()
+    }
+
+    pub fn is_trade_adjusted(&self) -> bool {
+        !self.trade_adjusted.is_empty()
+    }
+
+    fn update_best(&mut self) {
+        self.best = self.levels.first_key_value().map(|(_, lvl)| lvl.clone());
+    }
+
+    /// Provisionally remove liquidity taken by a trade at `price`: levels better
+    /// than the trade price are gone and the level itself is reduced by `qty`.
+    pub fn consume(&mut self, price: &Price, qty: Qty) -> Result<(), TickError> {
+        let trade_key = self.key(price)?;
+        while let Some(entry) = self.levels.first_entry() {
+            if *entry.key() >= trade_key {
+                break;
+            }
+            let (key, lvl) = entry.remove_entry();
+            self.trade_adjusted.entry(key).or_insert(lvl);
         }
-        need_insert
+        if let Some(lvl) = self.levels.get_mut(&trade_key) {
+            self.trade_adjusted
+                .entry(trade_key)
+                .or_insert_with(|| lvl.clone());
+            lvl.qty = lvl.qty - qty;
+            if lvl.qty <= Qty(0) {
+                self.levels.remove(&trade_key);
+            }
+        }
+        self.update_best();
+        Ok(())
     }
 
-    pub fn update_diff(
-        &mut self,
-        lvl: Vec<Level>,
-        side: structure::Side,
-        prec: &Precision,
-    ) -> &Self {
-        let (x_more_y, y_more_x) = match side {
-            structure::Side::Buy => (Ordering::Less, Ordering::Greater),
-            structure::Side::Sell => (Ordering::Greater, Ordering::Less),
-        };
-        let cmp = |x: &Level, y: &Level, tick: &Price| {
-            if (x.price.clone() - y.price.clone()).same_tick(tick) {
-                Ordering::Equal
-            } else if x.price.clone() > y.price.clone() {
-                x_more_y
+    /// Roll back trade adjustments, depth updates are the source of truth.
+    fn restore(&mut self) {
+        for (key, lvl) in std::mem::take(&mut self.trade_adjusted) {
+            self.levels.insert(key, lvl);
+        }
+    }
+
+    /// Apply levels of a depth update, nothing is changed if any of them is invalid.
+    /// Zero quantity removes the level.
+    pub fn update_diff(&mut self, lvl: Vec<Level>) -> Result<&Self, Corruption> {
+        if lvl.iter().any(|level| level.qty < Qty(0)) {
+            return Err(Corruption::NegativeQty);
+        }
+        let keyed = lvl
+            .into_iter()
+            .map(|level| Ok((self.key(&level.price)?, level)))
+            .collect::<Result<Vec<_>, TickError>>()
+            .map_err(|_| Corruption::OffTick)?;
+        self.restore();
+        for (key, level) in keyed {
+            if level.qty.is_zero() {
+                self.levels.remove(&key);
             } else {
-                y_more_x
-            }
-        };
-        let mut it1 = self.levels.iter().peekable();
-        let mut it2 = lvl.iter().peekable();
-
-        let mut new_levels = LevelsT::new();
-        while it1.peek().is_some() && it2.peek().is_some() {
-            let (l1, l2) = (
-                (*it1.peek().unwrap()).clone(),
-                (*it2.peek().unwrap()).clone(),
-            );
-            match cmp(&l1, &l2, &prec.price) {
-                Ordering::Less => {
-                    Self::push_level(&mut new_levels, l1, &prec.qty);
-                    it1.next();
-                }
-                Ordering::Equal => {
-                    Self::push_level(&mut new_levels, l2, &prec.qty);
-                    it1.next();
-                    it2.next();
-                }
-                Ordering::Greater => {
-                    Self::push_level(&mut new_levels, l2, &prec.qty);
-                    it2.next();
-                }
+                self.levels.insert(key, level);
             }
         }
-        new_levels.extend(it1.map(|x| x.clone()));
-        new_levels.extend(it2.map(|x| x.clone()));
-        new_levels.truncate(self.limit);
-        self.levels = new_levels;
-        self
+        while self.levels.len() > self.limit {
+            self.levels.pop_last();
+        }
+        self.update_best();
+        Ok(self)
     }
 }
 
+/// Broken invariant of a book, which means that updates are applied wrongly.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Corruption {
+    /// Best bid is above best ask
+    Crossed,
+    /// Best bid is equal to best ask
+    Locked,
+    NegativeQty,
+    /// Price isn't a multiple of the tick size or the tick size is invalid
+    OffTick,
+}
+
 #[derive(Debug, PartialEq)]
-pub(crate) enum DepthUpdateError {
+pub enum DepthUpdateError {
     DepthStale,
     MissedUpdate,
     WaitSnapshot,
     StaleUpdate,
     UnknownInstrument,
+    /// Book is dropped and waits for a new snapshot
+    Corrupted(Corruption),
+    /// Trade price isn't on the tick grid, the trade is ignored
+    OffTickTrade,
+}
+
+/// Longest interval between resubscriptions of a stale book.
+const MAX_RESUBSCRIBE_DELAY: Duration = Duration::from_secs(300);
+
+/// Silence of the depth stream, which is resubscribed with backoff until it resumes.
+#[derive(Debug)]
+struct Stale {
+    since: Instant,
+    retry_at: Instant,
+    backoff: Backoff,
 }
 
 #[derive(Debug)]
@@ -105,22 +182,40 @@ pub struct OrderBook {
     buy: Side,
     sell: Side,
     scheduled: BTreeMap<Id, Delta>,
+    /// Trades newer than the last applied depth update
+    trades: Vec<Trade>,
     snapshot_requested: bool,
     last_applied: Id,
+    /// Transaction time of the last applied depth update
+    last_time: u64,
+    /// Event time of the last applied depth update
+    last_event_time: u64,
+    /// Local time the last depth update was received, applied or not
+    last_received: Option<Instant>,
+    created: Instant,
+    /// Set once the book is silent for longer than `max_silence`, until it's synced again
+    stale: Option<Stale>,
+    max_silence: Option<Duration>,
     skip_limit: Id,
     depth_limit: usize,
     precision: Precision,
+    /// Number of times the book was found corrupted
+    corruptions: u64,
 }
 
 impl Display for OrderBook {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(f, "depthbook updated: \n")?;
-        for lvl in self.sell.levels.iter().rev() {
-            write!(f, "{} - {}\n", lvl.price, lvl.qty)?;
+        if self.is_trade_adjusted() {
+            writeln!(f, "depthbook updated (trade adjusted): ")?;
+        } else {
+            writeln!(f, "depthbook updated: ")?;
+        }
+        for lvl in self.sell.iter().rev() {
+            writeln!(f, "{} - {}", lvl.price, lvl.qty)?;
         }
-        write!(f, "=======================\n")?;
-        for lvl in &self.buy.levels {
-            write!(f, "{} - {}\n", lvl.price, lvl.qty)?;
+        writeln!(f, "=======================")?;
+        for lvl in self.buy.iter() {
+            writeln!(f, "{} - {}", lvl.price, lvl.qty)?;
         }
         Ok(())
     }
@@ -129,17 +224,141 @@ impl Display for OrderBook {
 impl OrderBook {
     pub fn new(precision: Precision, depth_limit: usize) -> OrderBook {
         OrderBook {
-            buy: Default::default(),
-            sell: Default::default(),
+            buy: Side::new(structure::Side::Buy, precision.price, depth_limit),
+            sell: Side::new(structure::Side::Sell, precision.price, depth_limit),
             scheduled: BTreeMap::new(),
+            trades: Vec::new(),
             snapshot_requested: false,
             last_applied: Id(0),
+            last_time: 0,
+            last_event_time: 0,
+            last_received: None,
+            created: Instant::now(),
+            stale: None,
+            max_silence: None,
             skip_limit: Id(100),
             depth_limit,
             precision,
+            corruptions: 0,
         }
     }
 
+    /// Maximum number of missed ids, after which the book is resynced from a snapshot.
+    pub fn with_skip_limit(mut self, skip_limit: u64) -> OrderBook {
+        self.skip_limit = Id(skip_limit);
+        self
+    }
+
+    /// Book without depth updates for longer than `max_silence` is considered stale.
+    pub fn with_max_silence(mut self, max_silence: Option<Duration>) -> OrderBook {
+        self.max_silence = max_silence;
+        self
+    }
+
+    /// Whether the book is built from a snapshot and follows the depth stream.
+    pub fn is_synced(&self) -> bool {
+        self.last_applied != Id(0) && !self.snapshot_requested
+    }
+
+    /// Id of the last depth update applied to the book
+    pub fn last_applied(&self) -> &Id {
+        &self.last_applied
+    }
+
+    /// Transaction time of the last applied update
+    pub fn last_time(&self) -> u64 {
+        self.last_time
+    }
+
+    /// Event time of the last applied update, when exchange sent it
+    pub fn last_event_time(&self) -> u64 {
+        self.last_event_time
+    }
+
+    /// Local time the last depth update was received
+    pub fn last_received(&self) -> Option<Instant> {
+        self.last_received
+    }
+
+    /// Whether the book was dropped after silence and isn't synced again yet.
+    pub fn is_stale(&self) -> bool {
+        self.stale.is_some()
+    }
+
+    /// Local time the book became stale
+    pub fn stale_since(&self) -> Option<Instant> {
+        self.stale.as_ref().map(|stale| stale.since)
+    }
+
+    /// Whether the depth stream should be resubscribed by `now`: no updates are received
+    /// for longer than `max_silence` since the last one or since the book was created,
+    /// or the next retry of the stale book is due.
+    pub fn is_silent(&self, now: Instant) -> bool {
+        let Some(max_silence) = self.max_silence else {
+            return false;
+        };
+        let last = self.last_received.unwrap_or(self.created);
+        match &self.stale {
+            Some(stale) if last <= stale.since => now >= stale.retry_at,
+            _ => now.saturating_duration_since(last) > max_silence,
+        }
+    }
+
+    /// Drop synchronization state of the silent book and schedule the next resubscription,
+    /// returns false if the book was already stale. Unlike resync, snapshot isn't requested
+    /// until the stream resumes, as it couldn't be aligned with depth updates anyway.
+    pub fn mark_stale(&mut self, now: Instant) -> bool {
+        let last = self.last_received.unwrap_or(self.created);
+        if let Some(stale) = self.stale.as_mut().filter(|stale| last <= stale.since) {
+            stale.retry_at = now + stale.backoff.next_delay();
+            return false;
+        }
+        warn!(
+            "Book is stale, last update {:?} sent at {}",
+            self.last_applied, self.last_event_time
+        );
+        let max_silence = self.max_silence.unwrap_or_default();
+        let mut backoff = Backoff::new(max_silence, MAX_RESUBSCRIBE_DELAY.max(max_silence));
+        self.stale = Some(Stale {
+            since: now,
+            retry_at: now + backoff.next_delay(),
+            backoff,
+        });
+        self.scheduled.clear();
+        self.trades.clear();
+        self.last_applied = Id(0);
+        self.snapshot_requested = false;
+        true
+    }
+
+    pub fn buy(&self) -> &Side {
+        &self.buy
+    }
+
+    pub fn sell(&self) -> &Side {
+        &self.sell
+    }
+
+    /// Number of levels kept on every side
+    pub fn depth_limit(&self) -> usize {
+        self.depth_limit
+    }
+
+    pub fn precision(&self) -> &Precision {
+        &self.precision
+    }
+
+    /// Number of times the book was found corrupted and resynced
+    pub fn corruptions(&self) -> u64 {
+        self.corruptions
+    }
+
+    /// Whether trades are applied on top of the last depth update
+    /// and not yet confirmed by the depth stream.
+    pub fn is_trade_adjusted(&self) -> bool {
+        self.buy.is_trade_adjusted() || self.sell.is_trade_adjusted()
+    }
+
     pub fn apply(&mut self, upd: structure::MDResponse) -> Result<&Self, DepthUpdateError> {
         match upd {
             MDResponse::Trade(trade) => self.apply_trade(trade),
@@ -149,13 +368,50 @@ impl OrderBook {
                     "Id: {:?} {:?} {:?}",
                     delta.last, delta.first, delta.last_stream
                 );
+                let received = delta.received.unwrap_or_else(Instant::now);
+                self.last_received = self.last_received.max(Some(received));
                 self.scheduled.insert(delta.first.clone(), delta);
                 self.try_apply_scheduled()
             }
-            MDResponse::Ping => unreachable!(),
+            MDResponse::Resync(_) => self.resync(),
+            MDResponse::Ping | MDResponse::Ack(_) => unreachable!(),
+        }
+    }
+
+    /// Drop synchronization state, so that the book is rebuilt from the next snapshot.
+    fn resync(&mut self) -> Result<&Self, DepthUpdateError> {
+        self.scheduled.clear();
+        self.trades.clear();
+        self.last_applied = Id(0);
+        if self.snapshot_requested {
+            Err(DepthUpdateError::WaitSnapshot)
+        } else {
+            self.snapshot_requested = true;
+            Err(DepthUpdateError::DepthStale)
+        }
+    }
+
+    /// Sides are kept sorted by keys, so only the best prices could break the invariants.
+    fn check(&self) -> Option<Corruption> {
+        let (bid, ask) = (self.buy.best()?, self.sell.best()?);
+        match bid.price.cmp(&ask.price) {
+            Ordering::Less => None,
+            Ordering::Equal => Some(Corruption::Locked),
+            Ordering::Greater => Some(Corruption::Crossed),
         }
     }
 
+    /// Drop the corrupted book, so that the next snapshot rebuilds it.
+    fn corrupted(&mut self, corruption: Corruption) -> DepthUpdateError {
+        warn!("Book is corrupted: {:?}", corruption);
+        self.corruptions += 1;
+        self.scheduled.clear();
+        self.trades.clear();
+        self.last_applied = Id(0);
+        self.snapshot_requested = true;
+        DepthUpdateError::Corrupted(corruption)
+    }
+
     fn find_first_id(snap_id: Id, events: &BTreeMap<Id, Delta>) -> Option<Id> {
         for (k, v) in events {
             if v.last < snap_id {
@@ -179,8 +435,31 @@ impl OrderBook {
             warn!("Received snapshot, although depth is not stale");
             return Ok(self);
         }
-        self.buy = Side::from_vec(snapshot.buy, self.depth_limit);
-        self.sell = Side::from_vec(snapshot.sell, self.depth_limit);
+        let sides = Side::from_vec(
+            snapshot.buy,
+            structure::Side::Buy,
+            self.precision.price,
+            self.depth_limit,
+        )
+        .and_then(|buy| {
+            let sell = Side::from_vec(
+                snapshot.sell,
+                structure::Side::Sell,
+                self.precision.price,
+                self.depth_limit,
+            )?;
+            Ok((buy, sell))
+        });
+        match sides {
+            Ok((buy, sell)) => (self.buy, self.sell) = (buy, sell),
+            Err(corruption) => return Err(self.corrupted(corruption)),
+        }
+        if let Some(corruption) = self.check() {
+            return Err(self.corrupted(corruption));
+        }
+        self.trades.clear();
+        self.last_time = snapshot.time;
+        self.last_event_time = snapshot.event_time;
         match Self::find_first_id(snapshot.last, &self.scheduled) {
             Some(x) => {
                 self.last_applied = x;
@@ -194,30 +473,44 @@ impl OrderBook {
         }
     }
 
-    fn match_lvl(&mut self, lvl: Level, side: structure::Side) -> Option<(usize, Qty)> {
-        let direction = match side {
-            structure::Side::Buy => &self.buy,
-            structure::Side::Sell => &self.sell,
+    fn consume(&mut self, trade: &Trade) -> Result<(), TickError> {
+        let side = match trade.side {
+            structure::Side::Buy => &mut self.sell,
+            structure::Side::Sell => &mut self.buy,
         };
-        let lvl_id = direction.get_level_id(lvl.price, &self.precision)?;
-        Some((lvl_id, (&direction.levels[lvl_id].qty).clone()))
+        side.consume(&trade.info.price, trade.info.qty)
     }
 
-    fn apply_trade(&mut self, _upd: Trade) -> Result<&Self, DepthUpdateError> {
-        unimplemented!()
-        // if self.last_snapshot.is_none() {
-        //     self.buffered.push(MDResponse::Trade(upd));
-        //     Err(DepthStale)
-        // } else {
-        // }
+    fn apply_trade(&mut self, trade: Trade) -> Result<&Self, DepthUpdateError> {
+        if self.last_applied == Id(0) {
+            return Err(DepthUpdateError::WaitSnapshot);
+        }
+        if trade.time <= self.last_time {
+            return Err(DepthUpdateError::StaleUpdate);
+        }
+        if self.consume(&trade).is_err() {
+            return Err(DepthUpdateError::OffTickTrade);
+        }
+        self.trades.push(trade);
+        Ok(self)
     }
 
-    fn add_diff(&mut self, delta: Delta) {
-        self.sell
-            .update_diff(delta.sell, structure::Side::Sell, &self.precision);
-        self.buy
-            .update_diff(delta.buy, structure::Side::Buy, &self.precision);
+    fn add_diff(&mut self, delta: Delta) -> Result<(), Corruption> {
+        self.sell.update_diff(delta.sell)?;
+        self.buy.update_diff(delta.buy)?;
         self.last_applied = delta.last.clone();
+        self.last_time = delta.time;
+        self.last_event_time = delta.event_time;
+        self.stale = None;
+
+        // trades after the delta are not reflected in it yet
+        let trades = std::mem::take(&mut self.trades);
+        for trade in trades.into_iter().filter(|t| t.time > delta.time) {
+            // trades were on the grid when applied first
+            let _ = self.consume(&trade);
+            self.trades.push(trade);
+        }
+        Ok(())
     }
 
     fn match_id(&self, id: Id) -> Ordering {
@@ -248,10 +541,10 @@ impl OrderBook {
     fn try_apply_delta(&mut self, delta: Delta) -> Option<DepthUpdateError> {
         match self.match_id(delta.last_stream.clone()) {
             Ordering::Less => Some(DepthUpdateError::StaleUpdate),
-            Ordering::Equal => {
-                self.add_diff(delta);
-                None
-            }
+            Ordering::Equal => match self.add_diff(delta) {
+                Ok(()) => self.check().map(|corruption| self.corrupted(corruption)),
+                Err(corruption) => Some(self.corrupted(corruption)),
+            },
             Ordering::Greater => {
                 let id = delta.first.clone();
                 self.scheduled.insert(id.clone(), delta);
@@ -272,48 +565,45 @@ impl OrderBook {
 
 #[cfg(test)]
 mod tests {
-    use crate::common::{Id, Level, Precision, Price, Qty};
-    use crate::lob::order_book::{DepthUpdateError, OrderBook, Side};
+    use crate::common::{Id, Level, Price, Qty, TickError};
+    use crate::lob::order_book::{Corruption, DepthUpdateError, OrderBook, Side};
     use crate::structure;
-    use crate::structure::{Coin, Delta, Exchange, Feed, Instrument, MDResponse, Snapshot};
+    use crate::structure::{Delta, Instrument, MDResponse, Snapshot, Trade};
+    use crate::test_support::inst;
     use std::iter::zip;
+    use std::time::Duration;
+    use tokio::time::Instant;
 
-    fn any_inst(tick_sz: f32) -> Instrument {
-        Instrument::new(
-            Coin("BTC".into()),
-            Coin("USD".into()),
-            Feed::PERP,
-            Exchange::BINANCE,
-            gen_prec(tick_sz, tick_sz),
-            "BTCUSD".into(),
-        )
-    }
-
-    fn gen_prec(price: f32, qty: f32) -> Precision {
-        Precision::new(Price(price), Qty(qty))
-    }
-
-    fn compare_lvls(lvl1: &Vec<Level>, lvl2: &Vec<Level>, precision: &Precision) {
+    fn compare_lvls(lvl1: &[Level], lvl2: &[Level]) {
+        assert_eq!(lvl1.len(), lvl2.len());
         for (v1, v2) in zip(lvl1, lvl2) {
-            assert!(v1.eq(v2.clone(), precision));
+            assert_eq!(v1, v2);
         }
     }
 
-    fn compare(depth: &OrderBook, buy: Vec<Level>, sell: Vec<Level>, precision: &Precision) {
-        compare_lvls(&buy, &depth.buy.levels, &precision);
-        compare_lvls(&sell, &depth.sell.levels, &precision);
+    fn side_levels(side: &Side) -> Vec<Level> {
+        side.iter().cloned().collect()
+    }
+
+    fn compare(depth: &OrderBook, buy: Vec<Level>, sell: Vec<Level>) {
+        compare_lvls(&buy, &side_levels(&depth.buy));
+        compare_lvls(&sell, &side_levels(&depth.sell));
     }
 
     #[test]
     fn update_diff() {
+        let tick = Price::from_f64(0.01);
         let mut buy_prev = Side::from_vec(
             vec![
                 Level::from_float_pair(10., 10.),
                 Level::from_float_pair(11., 10.),
                 Level::from_float_pair(12., 5.),
             ],
+            structure::Side::Sell,
+            tick,
             3,
-        );
+        )
+        .unwrap();
 
         let buy_new = vec![
             Level::from_float_pair(11., 5.),
@@ -321,22 +611,11 @@ mod tests {
             Level::from_float_pair(13., 6.),
         ];
 
-        let tick = 0.01;
-        let precision = Precision::new(Price::new(tick), Qty::new(tick));
-        buy_prev.update_diff(buy_new, structure::Side::Sell, &precision);
-        let mut it = buy_prev.levels.iter();
-        assert!(it
-            .next()
-            .unwrap()
-            .eq(Level::from_float_pair(10., 10.), &precision));
-        assert!(it
-            .next()
-            .unwrap()
-            .eq(Level::from_float_pair(11., 5.), &precision));
-        assert!(it
-            .next()
-            .unwrap()
-            .eq(Level::from_float_pair(13., 6.), &precision));
+        buy_prev.update_diff(buy_new).unwrap();
+        let mut it = buy_prev.iter();
+        assert_eq!(it.next(), Some(&Level::from_float_pair(10., 10.)));
+        assert_eq!(it.next(), Some(&Level::from_float_pair(11., 5.)));
+        assert_eq!(it.next(), Some(&Level::from_float_pair(13., 6.)));
         assert!(it.next().is_none());
 
         let mut sell_prev = Side::from_vec(
@@ -345,27 +624,21 @@ mod tests {
                 Level::from_float_pair(11., 10.),
                 Level::from_float_pair(10., 10.),
             ],
+            structure::Side::Buy,
+            tick,
             3,
-        );
+        )
+        .unwrap();
         let sell_new = vec![
             Level::from_float_pair(13., 6.),
             Level::from_float_pair(12., 0.),
             Level::from_float_pair(11., 5.),
         ];
-        sell_prev.update_diff(sell_new, structure::Side::Buy, &precision);
-        let mut it = sell_prev.levels.iter();
-        assert!(it
-            .next()
-            .unwrap()
-            .eq(Level::from_float_pair(13., 6.), &precision));
-        assert!(it
-            .next()
-            .unwrap()
-            .eq(Level::from_float_pair(11., 5.), &precision));
-        assert!(it
-            .next()
-            .unwrap()
-            .eq(Level::from_float_pair(10., 10.), &precision));
+        sell_prev.update_diff(sell_new).unwrap();
+        let mut it = sell_prev.iter();
+        assert_eq!(it.next(), Some(&Level::from_float_pair(13., 6.)));
+        assert_eq!(it.next(), Some(&Level::from_float_pair(11., 5.)));
+        assert_eq!(it.next(), Some(&Level::from_float_pair(10., 10.)));
         assert!(it.next().is_none());
     }
 
@@ -376,20 +649,23 @@ mod tests {
             Level::from_float_pair(11., 10.),
             Level::from_float_pair(12., 5.),
         ];
-        let mut buy_prev = Side::from_vec(levels.clone(), 3);
-        let prec = gen_prec(0.01, 0.01);
-        buy_prev.update_diff(
-            vec![Level::from_float_pair(9., 0.)],
+        let mut buy_prev = Side::from_vec(
+            levels.clone(),
             structure::Side::Sell,
-            &prec,
-        );
-        compare_lvls(&buy_prev.levels, &levels, &prec)
+            Price::from_f64(0.01),
+            3,
+        )
+        .unwrap();
+        buy_prev
+            .update_diff(vec![Level::from_float_pair(9., 0.)])
+            .unwrap();
+        compare_lvls(&side_levels(&buy_prev), &levels)
     }
 
     #[test]
     fn simple_update() {
-        const TICK_SZ: f32 = 0.01;
-        let inst: Instrument = any_inst(TICK_SZ);
+        const TICK_SZ: f64 = 0.01;
+        let inst: Instrument = inst(TICK_SZ, TICK_SZ);
         const FINAL_SZ: usize = 4;
 
         let mut book = OrderBook::new(inst.precision.clone(), FINAL_SZ);
@@ -399,8 +675,8 @@ mod tests {
             Level::from_float_pair(11., 10.),
         ];
         let sell_prev = vec![
-            Level::from_float_pair(9.99, 100.),
-            Level::from_float_pair(9.98, 100.),
+            Level::from_float_pair(109.99, 100.),
+            Level::from_float_pair(109.98, 100.),
         ];
         let delta1 = MDResponse::Delta(Delta::new(
             inst.clone(),
@@ -409,6 +685,7 @@ mod tests {
             book.skip_limit.clone() + Id(99),
             book.skip_limit.clone() + Id(101),
             book.skip_limit.clone() + Id(98),
+            1,
         ));
 
         let buy_post = vec![
@@ -416,8 +693,8 @@ mod tests {
             Level::from_float_pair(102., 10.),
         ];
         let sell_post = vec![
-            Level::from_float_pair(99.99, 10.),
-            Level::from_float_pair(99.98, 10.),
+            Level::from_float_pair(109.99, 10.),
+            Level::from_float_pair(109.98, 10.),
         ];
         let delta2 = MDResponse::Delta(Delta::new(
             inst.clone(),
@@ -426,6 +703,7 @@ mod tests {
             book.skip_limit.clone() + Id(102),
             book.skip_limit.clone() + Id(110),
             book.skip_limit.clone() + Id(101),
+            2,
         ));
 
         let buy = vec![
@@ -433,10 +711,10 @@ mod tests {
             Level::from_float_pair(101., 10.),
         ];
         let sell = vec![
-            Level::from_float_pair(99.99, 100.),
-            Level::from_float_pair(99.98, 100.),
+            Level::from_float_pair(109.99, 100.),
+            Level::from_float_pair(109.98, 100.),
         ];
-        let false_snapshot = MDResponse::Snapshot(Snapshot::new(
+        let _false_snapshot = MDResponse::Snapshot(Snapshot::new(
             inst.clone(),
             buy.clone(),
             sell.clone(),
@@ -458,15 +736,378 @@ mod tests {
         ); // wait snapshot
         compare(
             book.apply(snapshot).unwrap(),
-            Side::from_vec(buy, FINAL_SZ + 1)
-                .update_diff(buy_post, structure::Side::Buy, &inst.precision)
-                .levels
-                .clone(),
-            Side::from_vec(sell, FINAL_SZ + 1)
-                .update_diff(sell_post, structure::Side::Sell, &inst.precision)
-                .levels
-                .clone(),
-            &inst.precision,
+            side_levels(
+                Side::from_vec(
+                    buy,
+                    structure::Side::Buy,
+                    inst.precision.price,
+                    FINAL_SZ + 1,
+                )
+                .unwrap()
+                .update_diff(buy_post)
+                .unwrap(),
+            ),
+            side_levels(
+                Side::from_vec(
+                    sell,
+                    structure::Side::Sell,
+                    inst.precision.price,
+                    FINAL_SZ + 1,
+                )
+                .unwrap()
+                .update_diff(sell_post)
+                .unwrap(),
+            ),
         ); // receive snapshot, apply updates
     }
+
+    #[test]
+    fn best_level_and_limit() {
+        let tick = Price::from_f64(0.1);
+        let mut buy = Side::new(structure::Side::Buy, tick, 2);
+        assert!(buy.best().is_none());
+
+        buy.update_diff(vec![
+            Level::from_float_pair(10.1, 1.),
+            Level::from_float_pair(10.3, 2.),
+            Level::from_float_pair(10.2, 3.),
+        ])
+        .unwrap();
+        assert_eq!(buy.len(), 2);
+        assert_eq!(buy.best(), Some(&Level::from_float_pair(10.3, 2.)));
+        assert!(buy.get(&Price::from_f64(10.1)).is_none());
+
+        buy.update_diff(vec![Level::from_float_pair(10.3, 0.)])
+            .unwrap();
+        assert_eq!(buy.best(), Some(&Level::from_float_pair(10.2, 3.)));
+
+        let mut sell = Side::new(structure::Side::Sell, tick, 2);
+        sell.update_diff(vec![
+            Level::from_float_pair(10.5, 1.),
+            Level::from_float_pair(10.4, 2.),
+        ])
+        .unwrap();
+        assert_eq!(sell.best(), Some(&Level::from_float_pair(10.4, 2.)));
+        sell.update_diff(vec![
+            Level::from_float_pair(10.4, 0.),
+            Level::from_float_pair(10.5, 0.),
+        ])
+        .unwrap();
+        assert!(sell.best().is_none());
+        assert!(sell.is_empty());
+    }
+
+    #[test]
+    fn off_tick() {
+        let tick = Price::from_f64(0.1);
+        let mut buy = Side::new(structure::Side::Buy, tick, 10);
+        buy.update_diff(vec![Level::from_float_pair(10.2, 1.)])
+            .unwrap();
+        // 10.25 would overwrite 10.2 if truncated, the whole update is rejected
+        assert_eq!(
+            buy.update_diff(vec![
+                Level::from_float_pair(10.3, 1.),
+                Level::from_float_pair(10.25, 2.),
+            ])
+            .err(),
+            Some(Corruption::OffTick)
+        );
+        compare_lvls(&side_levels(&buy), &[Level::from_float_pair(10.2, 1.)]);
+        assert!(buy.get(&Price::from_f64(10.25)).is_none());
+        assert_eq!(
+            buy.consume(&Price::from_f64(10.25), Qty(1)),
+            Err(TickError::OffTick)
+        );
+
+        let mut zero = Side::new(structure::Side::Sell, Price(0), 10);
+        assert_eq!(
+            zero.update_diff(vec![Level::from_float_pair(10., 1.)])
+                .err(),
+            Some(Corruption::OffTick)
+        );
+    }
+
+    #[test]
+    fn trade_adjusted() {
+        let inst = inst(0.01, 0.01);
+        let mut book = OrderBook::new(inst.precision.clone(), 10);
+        let trade = |price: f64, qty: f64, side: structure::Side, time: u64| {
+            MDResponse::Trade(Trade::new(
+                inst.clone(),
+                Level::from_float_pair(price, qty),
+                side,
+                Id(1),
+                Id(1),
+                time,
+            ))
+        };
+        let delta = |first: u64, last: u64, buy: Vec<Level>, time: u64| {
+            MDResponse::Delta(Delta::new(
+                inst.clone(),
+                buy,
+                vec![],
+                Id(first),
+                Id(last),
+                Id(first - 1),
+                time,
+            ))
+        };
+
+        assert_eq!(
+            book.apply(trade(10.02, 1., structure::Side::Sell, 5)).err(),
+            Some(DepthUpdateError::WaitSnapshot)
+        );
+        assert_eq!(
+            book.apply(delta(200, 210, vec![], 10)).err(),
+            Some(DepthUpdateError::DepthStale)
+        );
+        let snapshot = MDResponse::Snapshot(Snapshot::new(
+            inst.clone(),
+            vec![
+                Level::from_float_pair(10.02, 1.),
+                Level::from_float_pair(10.01, 2.),
+            ],
+            vec![
+                Level::from_float_pair(10.03, 1.),
+                Level::from_float_pair(10.04, 2.),
+            ],
+            Id(205),
+            9,
+        ));
+        assert!(book.apply(snapshot).is_ok());
+        assert!(!book.is_trade_adjusted());
+
+        assert_eq!(
+            book.apply(trade(10.01, 0.5, structure::Side::Sell, 10))
+                .err(),
+            Some(DepthUpdateError::StaleUpdate)
+        );
+        let book_ref = book
+            .apply(trade(10.01, 0.5, structure::Side::Sell, 11))
+            .unwrap();
+        assert!(book_ref.is_trade_adjusted());
+        assert_eq!(
+            book_ref.buy.best(),
+            Some(&Level::from_float_pair(10.01, 1.5))
+        );
+        let book_ref = book
+            .apply(trade(10.04, 2., structure::Side::Buy, 12))
+            .unwrap();
+        assert!(book_ref.sell.is_empty());
+
+        // confirms the first trade only, the second one is reapplied
+        let book_ref = book
+            .apply(delta(211, 215, vec![Level::from_float_pair(10.02, 0.)], 11))
+            .unwrap();
+        assert_eq!(
+            book_ref.buy.best(),
+            Some(&Level::from_float_pair(10.01, 2.))
+        );
+        assert!(!book_ref.buy.is_trade_adjusted());
+        assert!(book_ref.sell.is_empty());
+        assert!(book_ref.is_trade_adjusted());
+
+        let book_ref = book.apply(delta(216, 220, vec![], 13)).unwrap();
+        assert!(!book_ref.is_trade_adjusted());
+        assert_eq!(
+            side_levels(&book_ref.sell),
+            vec![
+                Level::from_float_pair(10.03, 1.),
+                Level::from_float_pair(10.04, 2.),
+            ]
+        );
+    }
+
+    #[test]
+    fn resync() {
+        let inst = inst(0.01, 0.01);
+        let mut book = OrderBook::new(inst.precision.clone(), 10);
+        let delta = |first: u64, last: u64| {
+            MDResponse::Delta(Delta::new(
+                inst.clone(),
+                vec![Level::from_float_pair(10., 1.)],
+                vec![],
+                Id(first),
+                Id(last),
+                Id(first - 1),
+                0,
+            ))
+        };
+        let snapshot = |last: u64| {
+            MDResponse::Snapshot(Snapshot::new(inst.clone(), vec![], vec![], Id(last), 0))
+        };
+
+        assert_eq!(
+            book.apply(delta(200, 210)).err(),
+            Some(DepthUpdateError::DepthStale)
+        );
+        assert!(book.apply(snapshot(205)).is_ok());
+        assert!(book.apply(delta(211, 220)).is_ok());
+
+        assert_eq!(
+            book.apply(MDResponse::Resync(inst.clone())).err(),
+            Some(DepthUpdateError::DepthStale)
+        );
+        assert_eq!(
+            book.apply(MDResponse::Resync(inst.clone())).err(),
+            Some(DepthUpdateError::WaitSnapshot)
+        );
+        assert_eq!(
+            book.apply(delta(300, 310)).err(),
+            Some(DepthUpdateError::WaitSnapshot)
+        );
+        assert!(book.apply(snapshot(305)).is_ok());
+        assert_eq!(book.last_applied, Id(310));
+    }
+
+    #[test]
+    fn corruption() {
+        let inst = inst(0.01, 0.01);
+        let mut book = OrderBook::new(inst.precision.clone(), 10);
+        let delta = |first: u64, last: u64, buy: Vec<Level>, sell: Vec<Level>| {
+            MDResponse::Delta(Delta::new(
+                inst.clone(),
+                buy,
+                sell,
+                Id(first),
+                Id(last),
+                Id(first - 1),
+                0,
+            ))
+        };
+        let snapshot = |last: u64| {
+            MDResponse::Snapshot(Snapshot::new(
+                inst.clone(),
+                vec![Level::from_float_pair(10., 1.)],
+                vec![Level::from_float_pair(10.02, 1.)],
+                Id(last),
+                0,
+            ))
+        };
+
+        let _ = book.apply(delta(200, 210, vec![], vec![]));
+        assert!(book.apply(snapshot(205)).is_ok());
+        let locked = delta(211, 220, vec![Level::from_float_pair(10.02, 1.)], vec![]);
+        assert_eq!(
+            book.apply(locked).err(),
+            Some(DepthUpdateError::Corrupted(Corruption::Locked))
+        );
+        assert!(!book.is_synced());
+        assert_eq!(book.corruptions(), 1);
+        assert_eq!(
+            book.apply(delta(221, 230, vec![], vec![])).err(),
+            Some(DepthUpdateError::WaitSnapshot)
+        );
+        assert!(book.apply(snapshot(225)).is_ok());
+        assert!(book.is_synced());
+
+        let crossed = delta(231, 240, vec![], vec![Level::from_float_pair(9.99, 1.)]);
+        assert_eq!(
+            book.apply(crossed).err(),
+            Some(DepthUpdateError::Corrupted(Corruption::Crossed))
+        );
+        let _ = book.apply(delta(241, 250, vec![], vec![]));
+        assert!(book.apply(snapshot(245)).is_ok());
+
+        let negative = delta(
+            251,
+            260,
+            vec![Level::new(Price::from_f64(9.9), Qty(-1))],
+            vec![],
+        );
+        assert_eq!(
+            book.apply(negative).err(),
+            Some(DepthUpdateError::Corrupted(Corruption::NegativeQty))
+        );
+        assert_eq!(book.corruptions(), 3);
+
+        // checked even if no buffered update follows the snapshot
+        let locked = MDResponse::Snapshot(Snapshot::new(
+            inst.clone(),
+            vec![Level::from_float_pair(10., 1.)],
+            vec![Level::from_float_pair(10., 1.)],
+            Id(275),
+            0,
+        ));
+        assert_eq!(
+            book.apply(locked).err(),
+            Some(DepthUpdateError::Corrupted(Corruption::Locked))
+        );
+        assert_eq!(book.corruptions(), 4);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn silence() {
+        let inst = inst(0.01, 0.01);
+        let mut book = OrderBook::new(inst.precision.clone(), 10)
+            .with_max_silence(Some(Duration::from_secs(5)));
+        let delta = |first: u64, last: u64| {
+            MDResponse::Delta(Delta::new(
+                inst.clone(),
+                vec![],
+                vec![],
+                Id(first),
+                Id(last),
+                Id(first - 1),
+                0,
+            ))
+        };
+        let snapshot = |last: u64| {
+            MDResponse::Snapshot(Snapshot::new(
+                inst.clone(),
+                vec![Level::from_float_pair(10., 1.)],
+                vec![Level::from_float_pair(10.02, 1.)],
+                Id(last),
+                0,
+            ))
+        };
+        // never got an update
+        tokio::time::advance(Duration::from_secs(5)).await;
+        assert!(!book.is_silent(Instant::now()));
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert!(book.is_silent(Instant::now()));
+
+        let _ = book.apply(delta(200, 210));
+        assert!(book.apply(snapshot(205)).is_ok());
+        tokio::time::advance(Duration::from_secs(4)).await;
+        // receive time of the frame counts, not the time it's applied
+        let late = MDResponse::Delta(Delta {
+            received: Some(Instant::now() - Duration::from_secs(2)),
+            ..Delta::new(inst.clone(), vec![], vec![], Id(211), Id(220), Id(210), 0)
+        });
+        assert!(book.apply(late).is_ok());
+        tokio::time::advance(Duration::from_secs(3)).await;
+        assert!(!book.is_silent(Instant::now()));
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert!(book.is_silent(Instant::now()));
+
+        let since = Instant::now();
+        assert!(book.mark_stale(since));
+        assert!(book.is_stale());
+        assert_eq!(book.stale_since(), Some(since));
+        assert!(!book.is_synced());
+        assert!(!book.is_silent(Instant::now()));
+        // retried with backoff until the stream resumes
+        tokio::time::advance(Duration::from_secs(5)).await;
+        assert!(book.is_silent(Instant::now()));
+        assert!(!book.mark_stale(Instant::now()));
+        assert!(!book.is_silent(Instant::now() + Duration::from_secs(2)));
+        assert!(book.is_silent(Instant::now() + Duration::from_secs(10)));
+        assert_eq!(book.stale_since(), Some(since));
+        // levels are kept until the book is synced again
+        compare(
+            &book,
+            vec![Level::from_float_pair(10., 1.)],
+            vec![Level::from_float_pair(10.02, 1.)],
+        );
+        assert_eq!(
+            book.apply(delta(300, 310)).err(),
+            Some(DepthUpdateError::DepthStale)
+        );
+        assert!(book.is_stale());
+        assert!(!book.is_silent(Instant::now() + Duration::from_secs(5)));
+        assert!(book.apply(snapshot(305)).is_ok());
+        assert!(book.is_synced());
+        assert!(!book.is_stale());
+    }
 }
@@ -1,20 +1,72 @@
+use crate::config::BooksConfig;
 use crate::lob::order_book::DepthUpdateError::UnknownInstrument;
 use crate::lob::order_book::{DepthUpdateError, OrderBook};
 use crate::structure::{Instrument, MDResponse};
 use std::collections::HashMap;
+use std::time::Duration;
+use tokio::time::Instant;
 
 pub struct DepthBookManager {
     books: HashMap<Instrument, OrderBook>,
+    cfg: BooksConfig,
 }
 
 impl DepthBookManager {
-    pub fn new(insts: &Vec<Instrument>) -> DepthBookManager {
-        DepthBookManager {
-            books: insts
-                .iter()
-                .map(|inst| (inst.clone(), OrderBook::new(inst.precision.clone(), 20)))
-                .collect(),
-        }
+    pub fn new(insts: &[Instrument], cfg: BooksConfig) -> DepthBookManager {
+        let mut manager = DepthBookManager {
+            books: HashMap::new(),
+            cfg,
+        };
+        insts.iter().for_each(|inst| manager.add(inst));
+        manager
+    }
+
+    fn new_book(cfg: &BooksConfig, inst: &Instrument) -> OrderBook {
+        let cfg = cfg.get(inst.to_raw_string());
+        OrderBook::new(inst.precision.clone(), cfg.depth_limit)
+            .with_skip_limit(cfg.skip_limit)
+            .with_max_silence(cfg.max_silence_ms.map(Duration::from_millis))
+    }
+
+    /// Empty book of the instrument with its settings.
+    pub fn empty_book(&self, inst: &Instrument) -> OrderBook {
+        Self::new_book(&self.cfg, inst)
+    }
+
+    /// Start tracking the instrument, existing book is kept as is.
+    pub fn add(&mut self, inst: &Instrument) {
+        self.books
+            .entry(inst.clone())
+            .or_insert_with(|| Self::new_book(&self.cfg, inst));
+    }
+
+    /// Returns false if the instrument wasn't tracked.
+    pub fn remove(&mut self, inst: &Instrument) -> bool {
+        self.books.remove(inst).is_some()
+    }
+
+    pub fn get(&self, inst: &Instrument) -> Option<&OrderBook> {
+        self.books.get(inst)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&Instrument, &OrderBook)> {
+        self.books.iter()
+    }
+
+    /// Books, which streams should be resubscribed by `now`, see `OrderBook::is_silent`.
+    pub fn silent(&self, now: Instant) -> Vec<Instrument> {
+        self.books
+            .iter()
+            .filter(|(_, book)| book.is_silent(now))
+            .map(|(inst, _)| inst.clone())
+            .collect()
+    }
+
+    /// Drop synchronization state of the silent book, see `OrderBook::mark_stale`.
+    /// Returns the book only if it has just become stale.
+    pub fn mark_stale(&mut self, inst: &Instrument, now: Instant) -> Option<&OrderBook> {
+        let book = self.books.get_mut(inst)?;
+        book.mark_stale(now).then_some(book)
     }
 
     pub fn update(
@@ -1,25 +1,51 @@
-extern crate core;
+use clap::error::ErrorKind;
+use clap::{CommandFactory, Parser, ValueEnum};
+use market_data::config::{MDConfig, StreamSpeed};
+use market_data::output::OutputFormat;
+use market_data::sink::{SinkFilter, StdoutSink, SINK_QUEUE_SIZE};
+use market_data::FeedBuilder;
 
-mod common;
-mod config;
-mod connection;
-mod lob;
-mod runner;
-mod scheme;
-mod structure;
+/// Update interval of the depth stream
+#[derive(ValueEnum, Debug, Clone, Copy)]
+enum Speed {
+    #[value(name = "100ms")]
+    Ms100,
+    #[value(name = "250ms")]
+    Ms250,
+    #[value(name = "500ms")]
+    Ms500,
+}
+
+impl From<Speed> for StreamSpeed {
+    fn from(speed: Speed) -> Self {
+        match speed {
+            Speed::Ms100 => StreamSpeed::Ms100,
+            Speed::Ms250 => StreamSpeed::Ms250,
+            Speed::Ms500 => StreamSpeed::Ms500,
+        }
+    }
+}
 
-use crate::config::MDConfig;
-use crate::lob::orderbooks::DepthBookManager;
-use crate::runner::Runner;
-use crate::scheme::connector::{HTTPApi, MarketQueries};
-use crate::structure::{Exchange, Instrument};
-use clap::Parser;
-use futures_util::future;
-use futures_util::future::join_all;
-use std::collections::HashMap;
-use std::sync::Arc;
-use tokio::sync::mpsc;
-use tokio::task::JoinHandle;
+/// Format of stdout output
+#[derive(ValueEnum, Debug, Clone, Copy)]
+enum Output {
+    /// Human readable ladder
+    Text,
+    /// JSON line per update with all levels
+    Json,
+    /// CSV line per update with the best levels only
+    Csv,
+}
+
+impl From<Output> for OutputFormat {
+    fn from(output: Output) -> Self {
+        match output {
+            Output::Text => OutputFormat::Text,
+            Output::Json => OutputFormat::Json,
+            Output::Csv => OutputFormat::Csv,
+        }
+    }
+}
 
 /// Translator from assembly to binary
 #[derive(Parser, Debug)]
@@ -33,10 +59,34 @@ struct Args {
     config_path: String,
     #[arg(
         long,
-        default_value = "100",
-        help = "Maximum number of missed ids, after which request snapshot"
+        help = "Maximum number of missed ids, after which request snapshot [default: 100]"
+    )]
+    delay_limit: Option<u64>,
+    #[arg(
+        long,
+        help = "Number of levels kept on every side of a book [default: 20]"
+    )]
+    depth_limit: Option<usize>,
+    #[arg(
+        long,
+        help = "Number of levels requested in REST snapshot, one of 5, 10, 20, 50, 100, 500, 1000 [default: 500]"
+    )]
+    snapshot_limit: Option<u32>,
+    #[arg(
+        long,
+        value_enum,
+        help = "Update interval of depth stream [default: 250ms]"
     )]
-    delay_limit: u32,
+    speed: Option<Speed>,
+    #[arg(long, help = "Unix socket for control commands, disabled if not set")]
+    control_socket: Option<String>,
+    #[arg(
+        long,
+        value_enum,
+        default_value = "text",
+        help = "Format of stdout output, used if no sinks are configured"
+    )]
+    output: Output,
 }
 
 #[tokio::main]
@@ -50,70 +100,41 @@ async fn main() {
         args.instruments,
         args.num_conn
     );
-    let cfg = MDConfig::new(args.config_path).expect("Failed to parse");
-    let binance_cfg = cfg.get(Exchange::BINANCE).expect("Expected binance config");
-
-    let (tx, rx) = mpsc::channel(100);
-    let wss_exchanges: Arc<Vec<Arc<dyn MarketQueries + Send + Sync>>> = Arc::new(vec![Arc::new(
-        scheme::binance::Api::new(binance_cfg.clone()),
-    )]);
-
-    // todo: replace vec with HashMap. It's not easy due to async trait
-    let http_exchanges: Vec<(Exchange, Box<dyn HTTPApi + Send + Sync>)> = vec![(
-        Exchange::BINANCE,
-        Box::new(scheme::binance::Api::new(binance_cfg.clone())),
-    )];
-
-    let available: Arc<Vec<Instrument>> = Arc::new(
-        future::join_all(
-            http_exchanges
-                .iter()
-                .map(|(_, exch)| async {
-                    exch.instrument_info()
-                        .await
-                        .into_iter()
-                        .filter(|inst| args.instruments.contains(inst.to_raw_string()))
-                        .into_iter()
-                        .collect::<Vec<Instrument>>()
-                })
-                .collect::<Vec<_>>(),
-        )
-        .await
-        .into_iter()
-        .flatten()
-        .collect(),
-    );
-    log::debug!("{:?}", &available);
-
-    let mut handles: Vec<JoinHandle<()>> = vec![];
-
-    let insts_map: Arc<HashMap<String, Instrument>> = Arc::new(
-        available
-            .iter()
-            .map(|x| (x.to_raw_string().clone(), x.clone()))
-            .collect(),
-    );
-
-    for sz in 0..wss_exchanges.len() {
-        for _ in 0..args.num_conn {
-            let shared_vec_clone = Arc::clone(&wss_exchanges);
-            let exch = shared_vec_clone[sz].clone();
-
-            handles.push(Runner::create_connection(
-                exch,
-                tx.clone(),
-                available.clone(),
-                insts_map.clone(),
-            ));
-        }
+    let mut cfg = MDConfig::new(args.config_path.clone()).expect("Failed to parse");
+    // overrides global settings, the ones of instruments are kept
+    let book = &mut cfg.book.global;
+    book.skip_limit = args.delay_limit.unwrap_or(book.skip_limit);
+    book.depth_limit = args.depth_limit.unwrap_or(book.depth_limit);
+    book.snapshot_limit = args.snapshot_limit.unwrap_or(book.snapshot_limit);
+    book.speed = args.speed.map_or(book.speed, StreamSpeed::from);
+    if let Err(err) = cfg.book.validate() {
+        Args::command()
+            .error(ErrorKind::ValueValidation, err)
+            .exit();
     }
 
-    handles.push(Runner::spawn_main_loop(
-        http_exchanges,
-        tx.clone(),
-        rx,
-        DepthBookManager::new(available.as_ref()),
-    ));
-
-    join_all(handles).await;
+    let no_sinks = cfg.sinks().is_empty();
+    let mut builder = FeedBuilder::new(cfg)
+        .instruments(args.instruments)
+        .connections(args.num_conn as usize);
+    if no_sinks {
+        let stdout = StdoutSink::stdout(args.output.into());
+        builder = builder.sink(
+            "stdout",
+            Box::new(stdout),
+            SinkFilter::default(),
+            SINK_QUEUE_SIZE,
+        );
+    }
+    // instruments could be subscribed later through the control socket
+    let control = args.control_socket.is_some();
+    if let Some(path) = args.control_socket {
+        builder = builder.control_socket(path);
+    }
+    let feed = builder.start().await.expect("Failed to start feed");
+    if feed.subscribed().is_empty() && !control {
+        log::error!("None of requested instruments is available");
+        return;
+    }
+    feed.join().await;
 }
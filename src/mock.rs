@@ -0,0 +1,316 @@
+use crate::config::MDConfig;
+use config::{Config, File, FileFormat};
+use futures_util::{SinkExt, StreamExt};
+use log::{debug, warn};
+use serde_json::Value;
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+use tokio_tungstenite::accept_async;
+use tungstenite::Message;
+
+/// Action of a scripted WebSocket connection.
+#[derive(Debug, Clone)]
+pub enum Step {
+    /// Text frame sent as is
+    Frame(String),
+    Ping,
+    /// Close the connection, the rest of the script is dropped
+    Disconnect,
+    Sleep(Duration),
+}
+
+fn levels(levels: &[(&str, &str)]) -> String {
+    Value::from(
+        levels
+            .iter()
+            .map(|(price, qty)| Value::from(vec![*price, *qty]))
+            .collect::<Vec<_>>(),
+    )
+    .to_string()
+}
+
+impl Step {
+    /// Depth update with ids `first..=last` following the update `first - 1`,
+    /// gaps, duplicates and reordering are made by ids and order of steps.
+    pub fn depth(
+        symbol: &str,
+        (first, last): (u64, u64),
+        bids: &[(&str, &str)],
+        asks: &[(&str, &str)],
+    ) -> Step {
+        // fields are ordered as Binance sends them, parser relies on the event type position
+        Step::Frame(format!(
+            r#"{{"e":"depthUpdate","E":{last},"T":{last},"s":"{symbol}","U":{first},"u":{last},"pu":{},"b":{},"a":{}}}"#,
+            first - 1,
+            levels(bids),
+            levels(asks)
+        ))
+    }
+
+    pub fn trade(
+        symbol: &str,
+        id: u64,
+        (price, qty): (&str, &str),
+        time: u64,
+        is_mm: bool,
+    ) -> Step {
+        Step::Frame(format!(
+            r#"{{"e":"aggTrade","E":{time},"s":"{symbol}","a":{id},"p":"{price}","q":"{qty}","f":{id},"l":{id},"T":{time},"m":{is_mm}}}"#
+        ))
+    }
+}
+
+#[derive(Default)]
+struct State {
+    symbols: Vec<String>,
+    /// Served in order, the last one is repeated
+    snapshots: HashMap<String, VecDeque<String>>,
+    /// Script of every next connection
+    scripts: VecDeque<Vec<Step>>,
+    /// Text frames received from clients
+    received: Vec<String>,
+    connections: usize,
+}
+
+/// Local Binance futures exchange speaking the subset of REST and WebSocket API
+/// used by the feed handler. Every WebSocket connection plays its script once
+/// the first subscription is acknowledged.
+pub struct MockExchange {
+    http_addr: SocketAddr,
+    ws_addr: SocketAddr,
+    state: Arc<Mutex<State>>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl MockExchange {
+    /// Listen on random localhost ports, panics if they can't be bound.
+    pub async fn start(symbols: &[&str]) -> MockExchange {
+        let state = Arc::new(Mutex::new(State {
+            symbols: symbols.iter().map(|s| s.to_string()).collect(),
+            ..State::default()
+        }));
+        let http = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let ws = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (http_addr, ws_addr) = (http.local_addr().unwrap(), ws.local_addr().unwrap());
+        let tasks = vec![
+            Self::listen(http, state.clone(), |stream, state| {
+                tokio::spawn(Self::serve_http(stream, state));
+            }),
+            Self::listen(ws, state.clone(), |stream, state| {
+                tokio::spawn(Self::serve_ws(stream, state));
+            }),
+        ];
+        MockExchange {
+            http_addr,
+            ws_addr,
+            state,
+            tasks,
+        }
+    }
+
+    /// Config of the feed handler pointing to the exchange.
+    pub fn config(&self) -> MDConfig {
+        let toml = format!(
+            r#"
+            [[endpoint]]
+            exchange = "BINANCE"
+            http_api = "http://{}/fapi/v1"
+            exchange_info = "/exchangeInfo"
+            snapshot = "/depth"
+            wss_api = "ws://{}/ws"
+            "#,
+            self.http_addr, self.ws_addr
+        );
+        Config::builder()
+            .add_source(File::from_str(&toml, FileFormat::Toml))
+            .build()
+            .and_then(Config::try_deserialize)
+            .expect("Valid mock config")
+    }
+
+    /// Queue depth snapshot of the symbol.
+    pub fn snapshot(
+        &self,
+        symbol: &str,
+        last_id: u64,
+        bids: &[(&str, &str)],
+        asks: &[(&str, &str)],
+    ) {
+        let body = format!(
+            r#"{{"lastUpdateId":{last_id},"E":{last_id},"T":{last_id},"bids":{},"asks":{}}}"#,
+            levels(bids),
+            levels(asks)
+        );
+        let mut state = self.state.lock().unwrap();
+        state
+            .snapshots
+            .entry(symbol.to_string())
+            .or_default()
+            .push_back(body);
+    }
+
+    /// Script of the next WebSocket connection, connections without a script stay silent.
+    pub fn connection(&self, steps: Vec<Step>) {
+        self.state.lock().unwrap().scripts.push_back(steps);
+    }
+
+    /// Text frames received from clients, e.g. subscriptions and pongs.
+    pub fn received(&self) -> Vec<String> {
+        self.state.lock().unwrap().received.clone()
+    }
+
+    /// Number of accepted WebSocket connections
+    pub fn connections(&self) -> usize {
+        self.state.lock().unwrap().connections
+    }
+
+    fn listen<F>(listener: TcpListener, state: Arc<Mutex<State>>, serve: F) -> JoinHandle<()>
+    where
+        F: Fn(TcpStream, Arc<Mutex<State>>) + Send + 'static,
+    {
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                serve(stream, state.clone());
+            }
+        })
+    }
+
+    fn exchange_info(state: &State) -> String {
+        let symbols: Vec<String> = state
+            .symbols
+            .iter()
+            .map(|symbol| {
+                let base = symbol.trim_end_matches("USDT");
+                format!(
+                    r#"{{"symbol":"{symbol}","baseAsset":"{base}","quoteAsset":"USDT","contractType":"PERPETUAL","deliveryDate":4133404800000,"filters":[{{"filterType":"PRICE_FILTER","tickSize":"0.10"}},{{"filterType":"LOT_SIZE","stepSize":"0.001"}}]}}"#
+                )
+            })
+            .collect();
+        format!(r#"{{"symbols":[{}]}}"#, symbols.join(","))
+    }
+
+    fn route(state: &Mutex<State>, target: &str) -> (u16, String) {
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        let mut state = state.lock().unwrap();
+        if path.ends_with("/exchangeInfo") {
+            return (200, Self::exchange_info(&state));
+        }
+        if !path.ends_with("/depth") {
+            return (404, String::new());
+        }
+        let symbol = query
+            .split('&')
+            .find_map(|param| param.strip_prefix("symbol="))
+            .unwrap_or_default();
+        let snapshot = state
+            .snapshots
+            .get_mut(symbol)
+            .and_then(|bodies| match bodies.len() {
+                0 => None,
+                1 => bodies.front().cloned(),
+                _ => bodies.pop_front(),
+            });
+        match snapshot {
+            Some(body) => (200, body),
+            None => (400, r#"{"code":-1121,"msg":"Invalid symbol."}"#.into()),
+        }
+    }
+
+    async fn serve_http(mut stream: TcpStream, state: Arc<Mutex<State>>) {
+        let mut request = vec![];
+        let mut buf = [0; 1024];
+        while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+            match stream.read(&mut buf).await {
+                Ok(0) | Err(_) => return,
+                Ok(n) => request.extend_from_slice(&buf[..n]),
+            }
+        }
+        let request = String::from_utf8_lossy(&request);
+        let target = request.split(' ').nth(1).unwrap_or_default();
+        debug!("Mock request {}", target);
+        let (status, body) = Self::route(&state, target);
+        let reason = match status {
+            200 => "OK",
+            400 => "Bad Request",
+            _ => "Not Found",
+        };
+        let response = format!(
+            "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            reason,
+            body.len(),
+            body
+        );
+        let _ = stream.write_all(response.as_bytes()).await;
+        let _ = stream.shutdown().await;
+    }
+
+    /// Records client request and returns acknowledgement of subscription changes.
+    fn on_request(state: &Mutex<State>, text: &str) -> Option<(bool, String)> {
+        state.lock().unwrap().received.push(text.to_string());
+        let request: Value = serde_json::from_str(text).ok()?;
+        let id = request.get("id")?.as_u64()?;
+        let subscribe = request.get("method")?.as_str()? == "SUBSCRIBE";
+        Some((subscribe, format!(r#"{{"result":null,"id":{}}}"#, id)))
+    }
+
+    async fn serve_ws(stream: TcpStream, state: Arc<Mutex<State>>) {
+        let Ok(mut ws) = accept_async(stream).await else {
+            return;
+        };
+        let mut steps: VecDeque<Step> = {
+            let mut state = state.lock().unwrap();
+            state.connections += 1;
+            state.scripts.pop_front().unwrap_or_default().into()
+        };
+        let mut subscribed = false;
+        loop {
+            tokio::select! {
+                biased;
+                msg = ws.next() => match msg {
+                    Some(Ok(Message::Text(text))) => {
+                        if let Some((subscribe, ack)) = Self::on_request(&state, &text) {
+                            subscribed |= subscribe;
+                            if ws.send(Message::Text(ack)).await.is_err() {
+                                return;
+                            }
+                        }
+                    }
+                    Some(Ok(_)) => {}
+                    Some(Err(_)) | None => return,
+                },
+                _ = std::future::ready(()), if subscribed && !steps.is_empty() => {
+                    let res = match steps.pop_front().expect("step is available") {
+                        Step::Frame(text) => ws.send(Message::Text(text)).await,
+                        Step::Ping => ws.send(Message::Text("ping".into())).await,
+                        Step::Sleep(delay) => {
+                            tokio::time::sleep(delay).await;
+                            Ok(())
+                        }
+                        Step::Disconnect => {
+                            let _ = ws.close(None).await;
+                            return;
+                        }
+                    };
+                    if let Err(err) = res {
+                        warn!("Mock connection failed: {}", err);
+                        return;
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Drop for MockExchange {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
@@ -0,0 +1,231 @@
+use crate::common::{Id, Level};
+use crate::lob::analytics::Analytics;
+use crate::lob::order_book::OrderBook;
+use crate::structure::Instrument;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::fmt::{Display, Formatter};
+
+/// Format of order books written to sinks.
+#[derive(Deserialize, Default, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    /// Human readable ladder
+    #[default]
+    Text,
+    /// JSON line per update with all levels
+    Json,
+    /// CSV line per update with the best levels only
+    Csv,
+}
+
+const CSV_HEADER: &str =
+    "instrument,exchange,last_update_id,event_time,bid_price,bid_qty,ask_price,ask_qty";
+
+/// State of a book after update, detached from the book to be passed to sinks.
+#[derive(Debug, Clone)]
+pub struct BookUpdate {
+    pub inst: Instrument,
+    pub last_update_id: Id,
+    /// Transaction time of the last update
+    pub time: u64,
+    /// Event time of the last update
+    pub event_time: u64,
+    pub trade_adjusted: bool,
+    /// Stream of the book is silent, levels are the last known ones
+    pub stale: bool,
+    /// Levels from the best to the worst
+    pub bids: Vec<Level>,
+    pub asks: Vec<Level>,
+    /// Set for sinks configured to emit analytics
+    pub analytics: Option<Analytics>,
+}
+
+impl BookUpdate {
+    pub fn new(inst: &Instrument, book: &OrderBook) -> BookUpdate {
+        BookUpdate {
+            inst: inst.clone(),
+            last_update_id: book.last_applied().clone(),
+            time: book.last_time(),
+            event_time: book.last_event_time(),
+            trade_adjusted: book.is_trade_adjusted(),
+            stale: book.is_stale(),
+            bids: book.buy().iter().cloned().collect(),
+            asks: book.sell().iter().cloned().collect(),
+            analytics: None,
+        }
+    }
+
+    /// Copy with at most `depth` levels on each side.
+    pub fn truncated(&self, depth: usize) -> BookUpdate {
+        BookUpdate {
+            bids: self.bids.iter().take(depth).cloned().collect(),
+            asks: self.asks.iter().take(depth).cloned().collect(),
+            ..self.clone()
+        }
+    }
+
+    /// Prices and quantities are decimal strings to keep them exact, as exchange does.
+    pub fn to_json(&self) -> Value {
+        let levels = |lvls: &[Level]| -> Vec<Value> {
+            lvls.iter()
+                .map(|lvl| json!([lvl.price.to_string(), lvl.qty.to_string()]))
+                .collect()
+        };
+        let mut value = json!({
+            "instrument": self.inst.to_raw_string(),
+            "exchange": format!("{:?}", self.inst.exchange),
+            "last_update_id": self.last_update_id.0,
+            "event_time": self.event_time,
+            "trade_adjusted": self.trade_adjusted,
+            "stale": self.stale,
+            "bids": levels(&self.bids),
+            "asks": levels(&self.asks),
+        });
+        if let Some(analytics) = &self.analytics {
+            value["analytics"] = analytics.to_json();
+        }
+        value
+    }
+}
+
+impl Display for BookUpdate {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if self.stale {
+            writeln!(f, "depthbook stale: ")?;
+        } else if self.trade_adjusted {
+            writeln!(f, "depthbook updated (trade adjusted): ")?;
+        } else {
+            writeln!(f, "depthbook updated: ")?;
+        }
+        for lvl in self.asks.iter().rev() {
+            writeln!(f, "{} - {}", lvl.price, lvl.qty)?;
+        }
+        writeln!(f, "=======================")?;
+        for lvl in self.bids.iter() {
+            writeln!(f, "{} - {}", lvl.price, lvl.qty)?;
+        }
+        if let Some(analytics) = &self.analytics {
+            writeln!(f, "{}", analytics)?;
+        }
+        Ok(())
+    }
+}
+
+fn csv_level(lvl: Option<&Level>) -> String {
+    match lvl {
+        Some(lvl) => format!("{},{}", lvl.price, lvl.qty),
+        None => ",".to_string(),
+    }
+}
+
+impl OutputFormat {
+    /// First line of the output, if the format requires it.
+    /// Columns depend on whether `update` carries analytics.
+    pub fn header(&self, update: &BookUpdate) -> Option<String> {
+        match (self, &update.analytics) {
+            (OutputFormat::Csv, None) => Some(CSV_HEADER.to_string()),
+            (OutputFormat::Csv, Some(_)) => {
+                Some(format!("{},{}", CSV_HEADER, Analytics::CSV_COLUMNS))
+            }
+            (OutputFormat::Text | OutputFormat::Json, _) => None,
+        }
+    }
+
+    pub fn format(&self, update: &BookUpdate) -> String {
+        match self {
+            OutputFormat::Text => update.to_string(),
+            OutputFormat::Json => update.to_json().to_string(),
+            OutputFormat::Csv => {
+                let line = format!(
+                    "{},{:?},{},{},{},{}",
+                    update.inst.to_raw_string(),
+                    update.inst.exchange,
+                    update.last_update_id.0,
+                    update.event_time,
+                    csv_level(update.bids.first()),
+                    csv_level(update.asks.first()),
+                );
+                match &update.analytics {
+                    Some(analytics) => format!("{},{}", line, analytics.to_csv()),
+                    None => line,
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::common::Level;
+    use crate::lob::analytics::{Analytics, AnalyticsConfig};
+    use crate::output::{BookUpdate, OutputFormat};
+    use crate::test_support::{inst, synced_book};
+    use serde_json::{json, Value};
+
+    #[test]
+    fn output_schema() {
+        let inst = inst(0.01, 0.1);
+        let bids = vec![
+            Level::from_float_pair(10.01, 1.),
+            Level::from_float_pair(10., 2.5),
+        ];
+        let state = synced_book(
+            &inst,
+            10,
+            bids.clone(),
+            vec![Level::from_float_pair(10.02, 3.)],
+        );
+        let book = BookUpdate::new(&inst, &state);
+        let line = OutputFormat::Json.format(&book);
+        assert_eq!(
+            serde_json::from_str::<Value>(&line).unwrap(),
+            json!({
+                "instrument": "BTCUSDT",
+                "exchange": "BINANCE",
+                "last_update_id": 210,
+                "event_time": 8,
+                "trade_adjusted": false,
+                "stale": false,
+                "bids": [["10.01", "1"], ["10", "2.5"]],
+                "asks": [["10.02", "3"]],
+            })
+        );
+        assert_eq!(book.truncated(1).bids.len(), 1);
+
+        assert_eq!(
+            OutputFormat::Csv.format(&book),
+            "BTCUSDT,BINANCE,210,8,10.01,1,10.02,3"
+        );
+        let one_sided = BookUpdate::new(&inst, &synced_book(&inst, 10, bids, vec![]));
+        assert_eq!(
+            OutputFormat::Csv.format(&one_sided),
+            "BTCUSDT,BINANCE,210,8,10.01,1,,"
+        );
+        assert_eq!(
+            OutputFormat::Csv
+                .header(&one_sided)
+                .unwrap()
+                .split(',')
+                .count(),
+            OutputFormat::Csv.format(&one_sided).split(',').count()
+        );
+
+        let with_analytics = BookUpdate {
+            analytics: Some(Analytics::new(&state, &AnalyticsConfig::default())),
+            ..book.clone()
+        };
+        let line = OutputFormat::Json.format(&with_analytics);
+        let analytics = &serde_json::from_str::<Value>(&line).unwrap()["analytics"];
+        assert_eq!(analytics["mid"], "10.015");
+        assert_eq!(analytics["spread_ticks"], 1);
+        assert_eq!(
+            OutputFormat::Csv
+                .header(&with_analytics)
+                .unwrap()
+                .split(',')
+                .count(),
+            OutputFormat::Csv.format(&with_analytics).split(',').count()
+        );
+    }
+}
@@ -0,0 +1,396 @@
+use crate::arbiter::{Arbiter, ConnId};
+use crate::capture::{Record, Source};
+use crate::config::MDConfig;
+use crate::error::{Error, Result};
+use crate::hub::BookHub;
+use crate::lob::orderbooks::DepthBookManager;
+use crate::runner::Runner;
+use crate::scheme::binance::Api;
+use crate::scheme::connector::{AliasInstrument, HTTPApi, MarketQueries};
+use crate::sink::{BookSink, SinkFilter, Sinks};
+use crate::snapshot::HTTPExchanges;
+use crate::structure::{Exchange, Instrument, MDResponse, Snapshot};
+use crate::subscription::SubscriptionControl;
+use async_trait::async_trait;
+use log::{info, warn};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io;
+use std::io::{BufRead, BufReader};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{mpsc, Notify};
+use tokio::time::Instant;
+
+/// Virtual time given to the pipeline to process the last records before it's stopped.
+const DRAIN_TIME: Duration = Duration::from_secs(1);
+
+/// Serves recorded REST responses, a snapshot request waits until the replay
+/// reaches the time the snapshot was received.
+#[derive(Default)]
+pub struct ReplayApi {
+    exchange_info: Mutex<Option<String>>,
+    snapshots: Mutex<HashMap<String, VecDeque<String>>>,
+    released: Notify,
+    finished: AtomicBool,
+}
+
+impl ReplayApi {
+    /// Pass the snapshot to the pending or the next request for the symbol.
+    fn release(&self, symbol: &str, body: String) {
+        self.snapshots
+            .lock()
+            .unwrap()
+            .entry(symbol.to_string())
+            .or_default()
+            .push_back(body);
+        self.released.notify_waiters();
+    }
+
+    /// Requests, which can't be served by the rest of the capture, fail.
+    fn finish(&self) {
+        self.finished.store(true, Ordering::Relaxed);
+        self.released.notify_waiters();
+    }
+
+    fn not_recorded(what: String) -> Error {
+        Error::Status {
+            status: 404,
+            body: format!("{} is not recorded", what),
+        }
+    }
+}
+
+#[async_trait]
+impl HTTPApi for ReplayApi {
+    async fn instrument_info(&self) -> Result<Vec<Instrument>> {
+        let body = self.exchange_info.lock().unwrap().clone();
+        Api::parse_exchange_info(&body.ok_or_else(|| Self::not_recorded("exchangeInfo".into()))?)
+    }
+
+    async fn request_depth_shapshot(&self, inst: Instrument) -> Result<Snapshot> {
+        loop {
+            let released = self.released.notified();
+            let body = self
+                .snapshots
+                .lock()
+                .unwrap()
+                .get_mut(inst.to_raw_string())
+                .and_then(|bodies| bodies.pop_front());
+            if let Some(body) = body {
+                return Api::parse_snapshot(&body, inst);
+            }
+            if self.finished.load(Ordering::Relaxed) {
+                return Err(Self::not_recorded(format!("snapshot of {:?}", inst)));
+            }
+            released.await;
+        }
+    }
+}
+
+/// Records of capture files ordered by receive time.
+pub fn read_capture(paths: &[String]) -> io::Result<Vec<Record>> {
+    let mut records = vec![];
+    for path in paths {
+        let reader = BufReader::new(std::fs::File::open(path)?);
+        for (num, line) in reader.lines().enumerate() {
+            match Record::parse(&line?) {
+                Some(record) => records.push(record),
+                None => warn!("Skip malformed line {} of {}", num + 1, path),
+            }
+        }
+    }
+    // stable, so records with the same time keep the order of files
+    records.sort_by_key(|record| record.time_us);
+    Ok(records)
+}
+
+#[derive(Default, Debug, PartialEq)]
+pub struct ReplayStats {
+    pub frames: u64,
+    pub snapshots: u64,
+    /// Records dropped by the recorder, the output may differ from the live one then
+    pub lost: u64,
+    pub resyncs: u64,
+}
+
+/// Record prepared for the pipeline.
+enum Event {
+    Ws(ConnId, MDResponse),
+    Snapshot(String, String),
+    Gap(u64),
+    Resync(Instrument),
+}
+
+/// Feeds captured frames and snapshots to the live pipeline: frames pass the arbiter
+/// and the main loop as if they came from connections, snapshots are served by
+/// `ReplayApi` at the moment they were received and books are resynced as they were live.
+/// The clock is paused and advanced to the receive time of every record, so that the output
+/// is the same on every run.
+pub struct Replay<'a> {
+    exch: &'a (dyn MarketQueries + Sync),
+    cfg: MDConfig,
+    sinks: Sinks,
+}
+
+impl<'a> Replay<'a> {
+    pub fn new(exch: &'a (dyn MarketQueries + Sync), cfg: MDConfig) -> Replay<'a> {
+        Replay {
+            exch,
+            cfg,
+            sinks: Sinks::default(),
+        }
+    }
+
+    /// Additional sink, see `Sinks::add`.
+    pub fn sink(
+        mut self,
+        name: &str,
+        sink: Box<dyn BookSink>,
+        filter: SinkFilter,
+        queue_size: usize,
+    ) -> Replay<'a> {
+        self.sinks.add(name, sink, filter, queue_size);
+        self
+    }
+
+    /// Parse frames with instruments known by the time they were received.
+    fn prepare(
+        &self,
+        api: &ReplayApi,
+        records: Vec<Record>,
+        stats: &mut ReplayStats,
+    ) -> (Vec<(u64, Event)>, Vec<Instrument>, Vec<Exchange>) {
+        let mut insts_map = AliasInstrument::new();
+        let (mut insts, mut exchanges) = (HashSet::new(), vec![]);
+        let mut events = vec![];
+        for record in records {
+            let event = match record.source {
+                Source::ExchangeInfo(exchange) => {
+                    *api.exchange_info.lock().unwrap() = Some(record.payload.clone());
+                    match Api::parse_exchange_info(&record.payload) {
+                        Ok(parsed) => insts_map.extend(
+                            parsed
+                                .into_iter()
+                                .map(|inst| (inst.to_raw_string().clone(), inst)),
+                        ),
+                        Err(err) => {
+                            warn!("Failed to replay instruments of {:?}: {}", exchange, err)
+                        }
+                    }
+                    if !exchanges.contains(&exchange) {
+                        exchanges.push(exchange);
+                    }
+                    continue;
+                }
+                Source::Gap => Event::Gap(record.payload.parse().unwrap_or(0)),
+                Source::Resync(symbol) => match insts_map.get(&symbol) {
+                    Some(inst) => {
+                        stats.resyncs += 1;
+                        Event::Resync(inst.clone())
+                    }
+                    None => {
+                        warn!("Resync of unknown instrument {}", symbol);
+                        continue;
+                    }
+                },
+                Source::Snapshot(symbol) => match insts_map.get(&symbol) {
+                    Some(inst) => {
+                        stats.snapshots += 1;
+                        insts.insert(inst.clone());
+                        Event::Snapshot(symbol, record.payload)
+                    }
+                    None => {
+                        warn!("Snapshot of unknown instrument {}", symbol);
+                        continue;
+                    }
+                },
+                Source::Ws(conn, _) => {
+                    stats.frames += 1;
+                    match self.exch.handle_response(&record.payload, &insts_map) {
+                        None | Some(MDResponse::Ping) | Some(MDResponse::Ack(_)) => continue,
+                        Some(resp) => {
+                            insts.extend(resp.get_inst());
+                            Event::Ws(conn, resp)
+                        }
+                    }
+                }
+            };
+            events.push((record.time_us, event));
+        }
+        (events, insts.into_iter().collect(), exchanges)
+    }
+
+    /// Must be called on a current thread runtime, which clock is paused by the replay.
+    pub async fn run(mut self, records: Vec<Record>) -> Result<ReplayStats> {
+        tokio::time::pause();
+        let api = Arc::new(ReplayApi::default());
+        let mut stats = ReplayStats::default();
+        let (events, insts, exchanges) = self.prepare(&api, records, &mut stats);
+        let http: HTTPExchanges = exchanges
+            .into_iter()
+            .map(|exchange| (exchange, api.clone() as Arc<dyn HTTPApi + Send + Sync>))
+            .collect();
+        for sink_cfg in self.cfg.sinks() {
+            self.sinks.open(sink_cfg).await?;
+        }
+
+        let (tx, rx) = mpsc::channel(100);
+        let (conn_tx, conn_rx) = mpsc::channel(100);
+        let arbiter = Runner::spawn_arbiter(conn_rx, tx.clone(), Arbiter::new(0));
+        let main_loop = Runner::spawn_main_loop(
+            http,
+            tx.clone(),
+            rx,
+            DepthBookManager::new(&insts, self.cfg.book.clone()),
+            SubscriptionControl::default(),
+            mpsc::channel(1).1,
+            // recorded snapshots are kept for book resyncs, audits are replayed as their resyncs
+            (self.sinks, BookHub::default(), None, None),
+        );
+
+        let (start, first_us) = (Instant::now(), events.first().map_or(0, |(time, _)| *time));
+        for (time_us, event) in events {
+            let at = start + Duration::from_micros(time_us.saturating_sub(first_us));
+            if at > Instant::now() {
+                tokio::time::sleep_until(at).await;
+            }
+            match event {
+                Event::Ws(conn, mut resp) => {
+                    if let MDResponse::Delta(delta) = &mut resp {
+                        delta.received = Some(Instant::now());
+                    }
+                    if conn_tx.send((conn, resp)).await.is_err() {
+                        break;
+                    }
+                }
+                Event::Snapshot(symbol, body) => api.release(&symbol, body),
+                // already resynced books just keep waiting for the snapshot
+                Event::Resync(inst) => {
+                    if tx.send(MDResponse::Resync(inst)).await.is_err() {
+                        break;
+                    }
+                }
+                Event::Gap(lost) => {
+                    warn!(
+                        "{} records are lost by the capture before {}",
+                        lost, time_us
+                    );
+                    stats.lost += lost;
+                }
+            }
+            tokio::task::yield_now().await;
+        }
+        api.finish();
+        drop((conn_tx, tx));
+        let _ = arbiter.await;
+        tokio::time::sleep(DRAIN_TIME).await;
+        main_loop.abort();
+        info!("Replayed {:?}", stats);
+        Ok(stats)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::capture::{Record, Source};
+    use crate::config::MDConfig;
+    use crate::output::OutputFormat;
+    use crate::replay::{Replay, ReplayStats};
+    use crate::scheme::binance::Api;
+    use crate::sink::{CallbackSink, SinkFilter, SINK_QUEUE_SIZE};
+    use crate::structure::Exchange;
+    use std::sync::{Arc, Mutex};
+
+    const EXCHANGE_INFO: &str = r#"{"symbols":[{"symbol":"BTCUSDT","baseAsset":"BTC",
+        "quoteAsset":"USDT","contractType":"PERPETUAL","deliveryDate":4133404800000,
+        "filters":[{"filterType":"PRICE_FILTER","tickSize":"0.10"},
+        {"filterType":"LOT_SIZE","stepSize":"0.001"}]}]}"#;
+
+    fn depth(first: u64, last: u64, bids: &str, asks: &str) -> String {
+        format!(
+            r#"{{"e":"depthUpdate","E":{last},"T":{last},"s":"BTCUSDT","U":{first},"u":{last},"pu":{},"b":{bids},"a":{asks}}}"#,
+            first - 1
+        )
+    }
+
+    fn records() -> Vec<Record> {
+        let at = |time_us: u64, source: Source, payload: &str| Record {
+            time_us,
+            ..Record::new(source, payload)
+        };
+        let ws = |time_us, conn, payload: String| at(time_us, Source::Ws(conn, 0), &payload);
+        vec![
+            at(0, Source::ExchangeInfo(Exchange::BINANCE), EXCHANGE_INFO),
+            ws(1_000, 0, r#"{"result":null,"id":1}"#.into()),
+            ws(2_000, 0, depth(1100, 1110, r#"[["10.0","1"]]"#, "[]")),
+            ws(2_500, 1, depth(1100, 1110, r#"[["10.0","1"]]"#, "[]")),
+            ws(3_000, 0, depth(1111, 1120, "[]", r#"[["10.2","3"]]"#)),
+            at(
+                50_000,
+                Source::Snapshot("BTCUSDT".into()),
+                r#"{"lastUpdateId":1105,"E":1,"T":1,"bids":[["10.0","2"],["9.9","1"]],"asks":[["10.3","1"]]}"#,
+            ),
+            // gap is filled by the slower connection, both deltas are applied at once
+            ws(60_000, 0, depth(1131, 1140, r#"[["9.9","0"]]"#, "[]")),
+            ws(61_000, 1, depth(1111, 1120, "[]", r#"[["10.2","3"]]"#)),
+            ws(62_000, 1, depth(1121, 1130, r#"[["10.1","1"]]"#, "[]")),
+            at(70_000, Source::Resync("BTCUSDT".into()), "audit"),
+            ws(75_000, 0, depth(1141, 1150, r#"[["10.1","2"]]"#, "[]")),
+            at(
+                80_000,
+                Source::Snapshot("BTCUSDT".into()),
+                r#"{"lastUpdateId":1145,"E":2,"T":2,"bids":[["10.0","5"]],"asks":[["10.3","1"]]}"#,
+            ),
+        ]
+    }
+
+    async fn replay() -> (ReplayStats, Vec<String>) {
+        let cfg = MDConfig::new("src/endpoints.toml".into()).unwrap();
+        let api = Api::new(cfg.get(Exchange::BINANCE).unwrap().clone()).unwrap();
+        let output = Arc::new(Mutex::new(vec![]));
+        let output_cl = output.clone();
+        let sink = CallbackSink::new(move |update| {
+            output_cl
+                .lock()
+                .unwrap()
+                .push(OutputFormat::Csv.format(update))
+        });
+        let stats = Replay::new(&api, cfg)
+            .sink(
+                "test",
+                Box::new(sink),
+                SinkFilter::default(),
+                SINK_QUEUE_SIZE,
+            )
+            .run(records())
+            .await
+            .unwrap();
+        let output = output.lock().unwrap().clone();
+        (stats, output)
+    }
+
+    #[test]
+    fn deterministic_replay() {
+        let run = || {
+            tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap()
+                .block_on(replay())
+        };
+        let (stats, output) = run();
+        assert_eq!((stats.frames, stats.snapshots, stats.lost), (8, 2, 0));
+        assert_eq!(stats.resyncs, 1);
+        assert_eq!(
+            output,
+            vec![
+                "BTCUSDT,BINANCE,1120,1120,10,1,10.2,3",
+                "BTCUSDT,BINANCE,1140,1140,10.1,1,10.2,3",
+                // rebuilt from the snapshot requested on resync
+                "BTCUSDT,BINANCE,1150,1150,10.1,2,10.3,1",
+            ]
+        );
+        assert_eq!(run(), (stats, output));
+    }
+}
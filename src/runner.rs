@@ -1,100 +1,250 @@
+use crate::arbiter::{Arbiter, ConnId};
+use crate::audit::Auditor;
+use crate::backoff::Backoff;
+use crate::capture::{Recorder, Source};
+use crate::control;
+use crate::control::Query;
+use crate::error::Result;
+use crate::hub::BookHub;
 use crate::lob::order_book::DepthUpdateError;
 use crate::lob::orderbooks::DepthBookManager;
-use crate::scheme::connector::{HTTPApi, MarketQueries, WssStream};
-use crate::structure::{Exchange, Instrument, MDResponse, Snapshot};
+use crate::scheme::connector::HTTPApi;
+use crate::sink::Sinks;
+use crate::snapshot::{HTTPExchanges, SnapshotFetcher, MAX_CONCURRENT_SNAPSHOTS};
+use crate::structure::{Instrument, MDResponse, Snapshot};
+use crate::subscription::{Command, SubscriptionControl};
+use futures_util::future;
 use log::{debug, error, info, warn};
-use std::collections::HashMap;
-use std::sync::Arc;
+use std::fmt::Debug;
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
 use tokio::sync::mpsc::error::TrySendError;
 use tokio::sync::mpsc::{Receiver, Sender};
 use tokio::task::JoinHandle;
+use tokio::time::Instant;
+
+const INSTRUMENT_INFO_ATTEMPTS: u32 = 5;
+const ARBITER_REPORT_INTERVAL: Duration = Duration::from_secs(60);
+/// How often books are checked for silence of their streams.
+const SILENCE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
 
 pub struct Runner;
 
 impl Runner {
-    pub async fn request_snapshot(exch: &(dyn HTTPApi + Sync), inst: &Instrument) -> Snapshot {
+    pub async fn request_snapshot(
+        exch: &(dyn HTTPApi + Sync),
+        inst: &Instrument,
+    ) -> Result<Snapshot> {
         let raw = inst.to_raw_string();
 
         info!("Request depthbook for {}", raw);
         let resp = exch.request_depth_shapshot(inst.clone()).await;
-        info!("Got response for {} {:?}", raw, &resp);
+        match &resp {
+            Ok(snapshot) => info!("Got response for {} {:?}", raw, snapshot),
+            Err(err) => error!("Failed to get depthbook for {}: {}", raw, err),
+        }
         resp
     }
 
-    fn get_streams() -> Vec<WssStream> {
-        vec![WssStream::Depth, WssStream::Trade]
+    /// Request instruments, transient failures are retried with backoff.
+    pub async fn instrument_info(exch: &(dyn HTTPApi + Sync)) -> Result<Vec<Instrument>> {
+        let mut backoff = Backoff::default();
+        let mut attempt = 1;
+        loop {
+            match exch.instrument_info().await {
+                Err(err) if err.is_transient() && attempt < INSTRUMENT_INFO_ATTEMPTS => {
+                    let delay = backoff.next_delay();
+                    warn!("Failed to get instruments: {}, retry in {:?}", err, delay);
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                res => return res,
+            }
+        }
+    }
+
+    /// Pass message further, returns false if receiver is closed.
+    /// Messages are dropped if the queue is full, unless `wait` is set.
+    pub(crate) async fn forward<T: Debug>(sender: &Sender<T>, msg: T, wait: bool) -> bool {
+        if wait {
+            return sender.send(msg).await.is_ok();
+        }
+        match sender.try_send(msg) {
+            Err(TrySendError::Closed(_)) => false,
+            Err(TrySendError::Full(el)) => {
+                warn!("Queue overflow, drop {:?}", el);
+                true
+            }
+            Ok(_) => true,
+        }
     }
 
-    pub fn create_connection(
-        exch: Arc<dyn MarketQueries + Send + Sync>,
+    /// Forwards the first arrival of every update from redundant connections.
+    pub fn spawn_arbiter(
+        mut rx: Receiver<(ConnId, MDResponse)>,
         sender: Sender<MDResponse>,
-        insts: Arc<Vec<Instrument>>,
-        insts_map: Arc<HashMap<String, Instrument>>,
+        mut arbiter: Arbiter,
     ) -> JoinHandle<()> {
         tokio::spawn(async move {
-            let mut client = crate::connection::WsClient::connect_to(&exch.connect_uri()).await;
-            client
-                .send((&exch).subscribe(insts.as_ref(), &Self::get_streams()))
-                .await;
+            let mut report = tokio::time::interval(ARBITER_REPORT_INTERVAL);
             loop {
-                let data = client.wait().await;
-                if data.is_none() {
+                let (conn, resp) = tokio::select! {
+                    _ = report.tick() => {
+                        arbiter.log_stats();
+                        continue;
+                    }
+                    msg = rx.recv() => match msg {
+                        None => break,
+                        Some(msg) => msg,
+                    }
+                };
+                if !arbiter.accept(conn, &resp) {
                     continue;
                 }
-                let res = data.unwrap();
-                // debug!("Receive: {:?}", res);
-                let opt_result = exch.handle_response(&res, insts_map.as_ref());
-                match opt_result {
-                    None => info!("Couldn't parse {}", res),
-                    Some(MDResponse::Ping) => client.send(exch.pong().into()).await,
-                    Some(MDResponse::Trade(..)) => {}
-                    Some(MDResponse::Snapshot(..)) | Some(MDResponse::Delta(..)) => {
-                        match sender.try_send(opt_result.unwrap()) {
-                            Err(TrySendError::Closed(_)) => break,
-                            Err(TrySendError::Full(el)) => {
-                                warn!("Queue overflow, drop {:?}", el)
-                            }
-                            _ => {}
-                        };
-                    }
+                let wait = matches!(resp, MDResponse::Resync(..));
+                if !Self::forward(&sender, resp, wait).await {
+                    break;
                 }
             }
         })
     }
 
+    pub(crate) async fn sleep_until(deadline: Option<Instant>) {
+        match deadline {
+            Some(deadline) => tokio::time::sleep_until(deadline).await,
+            None => future::pending().await,
+        }
+    }
+
+    /// Mark the resync in the capture, so that the replay repeats it.
+    pub(crate) fn record_resync(recorder: &Option<Recorder>, inst: &Instrument, reason: &str) {
+        if let Some(recorder) = recorder {
+            recorder.record(Source::Resync(inst.to_raw_string().clone()), reason);
+        }
+    }
+
+    /// Rebuild the book from a new snapshot.
+    fn resync(
+        depthbooks: &mut DepthBookManager,
+        snapshots: &mut SnapshotFetcher,
+        recorder: &Option<Recorder>,
+        inst: &Instrument,
+    ) {
+        warn!("Resync {} after failed audit", inst.to_raw_string());
+        Self::record_resync(recorder, inst, "audit");
+        if let Err(DepthUpdateError::DepthStale) =
+            depthbooks.update(inst, MDResponse::Resync(inst.clone()))
+        {
+            snapshots.request(inst)
+        }
+    }
+
     pub fn spawn_main_loop(
-        exch: Vec<(Exchange, Box<dyn HTTPApi + Send + Sync>)>,
+        exch: HTTPExchanges,
         sender: Sender<MDResponse>,
         mut rx: Receiver<MDResponse>,
         mut depthbooks: DepthBookManager,
+        control: SubscriptionControl,
+        mut queries: Receiver<Query>,
+        (mut sinks, hub, mut auditor, recorder): (
+            Sinks,
+            BookHub,
+            Option<Auditor>,
+            Option<Recorder>,
+        ),
     ) -> JoinHandle<()> {
+        let mut commands = control.listen();
         tokio::spawn(async move {
+            let mut snapshots = SnapshotFetcher::new(exch, sender, MAX_CONCURRENT_SNAPSHOTS);
+            let mut silence_check = tokio::time::interval(SILENCE_CHECK_INTERVAL);
+            let (mut commands_open, mut queries_open) = (true, true);
             loop {
-                let msg = rx.recv().await;
-                if msg.is_none() {
-                    continue;
-                }
-                let val = msg.unwrap();
+                let val = tokio::select! {
+                    query = queries.recv(), if queries_open => {
+                        match query {
+                            Some(query) => control::serve(query, &depthbooks, &mut snapshots, &recorder),
+                            None => queries_open = false,
+                        }
+                        continue;
+                    }
+                    cmd = commands.recv(), if commands_open => {
+                        match cmd {
+                            Ok(Command::Subscribe(insts)) => {
+                                insts.iter().for_each(|inst| depthbooks.add(inst))
+                            }
+                            Ok(Command::Unsubscribe(insts)) => {
+                                for inst in insts {
+                                    depthbooks.remove(&inst);
+                                }
+                            }
+                            Ok(Command::Resubscribe(_)) => {}
+                            Err(RecvError::Lagged(num)) => {
+                                error!("{} subscription commands are lost", num)
+                            }
+                            Err(RecvError::Closed) => commands_open = false,
+                        }
+                        continue;
+                    }
+                    _ = Self::sleep_until(sinks.next_flush()) => {
+                        sinks.flush(Instant::now());
+                        continue;
+                    }
+                    _ = silence_check.tick() => {
+                        let now = Instant::now();
+                        let silent = depthbooks.silent(now);
+                        for inst in &silent {
+                            warn!("No depth updates for {}, resubscribe", inst.to_raw_string());
+                            if let Some(book) = depthbooks.mark_stale(inst, now) {
+                                hub.publish(inst, book);
+                                sinks.publish(inst, book);
+                            }
+                        }
+                        if !silent.is_empty() {
+                            control.resubscribe(silent);
+                        }
+                        continue;
+                    }
+                    event = async { auditor.as_mut().expect("auditor is set").wait().await },
+                        if auditor.is_some() =>
+                    {
+                        let auditor = auditor.as_mut().expect("auditor is set");
+                        if let Some(inst) = auditor.handle(event, &depthbooks) {
+                            Self::resync(&mut depthbooks, &mut snapshots, &recorder, &inst);
+                        }
+                        continue;
+                    }
+                    msg = rx.recv() => match msg {
+                        None => break,
+                        Some(msg) => msg,
+                    }
+                };
                 let inst = val
                     .get_inst()
                     .expect("instrument should be available for all types of updates coming here");
+                if let MDResponse::Snapshot(..) = val {
+                    snapshots.received(&inst);
+                }
+                if let Some(auditor) = &mut auditor {
+                    auditor.feed(&inst, &val);
+                }
                 match depthbooks.update(&inst, val) {
-                    Ok(depth) => println!("{}", depth),
-                    Err(DepthUpdateError::DepthStale) => {
-                        let http_api = exch.iter().find(|(e, _)| &inst.exchange == e);
-                        match http_api {
-                            None => {}
-                            Some((_exchange, api)) => {
-                                match sender.try_send(MDResponse::Snapshot(
-                                    Self::request_snapshot(api.as_ref(), &inst).await,
-                                )) {
-                                    Ok(_) => {}
-                                    Err(TrySendError::Closed(_)) => break,
-                                    Err(TrySendError::Full(_)) => warn!("Full queue"),
-                                };
-                            }
+                    Ok(depth) => {
+                        hub.publish(&inst, depth);
+                        sinks.publish(&inst, depth);
+                        let audited = auditor.as_mut().and_then(|a| a.check(&inst, depth));
+                        if let Some(inst) = audited {
+                            Self::resync(&mut depthbooks, &mut snapshots, &recorder, &inst);
                         }
                     }
+                    Err(DepthUpdateError::DepthStale) => snapshots.request(&inst),
+                    Err(DepthUpdateError::Corrupted(corruption)) => {
+                        warn!(
+                            "Book of {} is {:?}, resync",
+                            inst.to_raw_string(),
+                            corruption
+                        );
+                        snapshots.request(&inst)
+                    }
                     Err(DepthUpdateError::MissedUpdate) => {
                         info!("Missed update for {}", inst.to_raw_string())
                     }
@@ -104,7 +254,15 @@ impl Runner {
                     Err(DepthUpdateError::UnknownInstrument) => {
                         error!("Unexpected instrument update {}", inst.to_raw_string())
                     }
-                    Err(DepthUpdateError::WaitSnapshot) => {}
+                    Err(DepthUpdateError::OffTickTrade) => {
+                        warn!("Off tick trade of {} is ignored", inst.to_raw_string())
+                    }
+                    Err(DepthUpdateError::WaitSnapshot) => {
+                        // previous request could fail, then request again
+                        if !snapshots.is_in_flight(&inst) {
+                            snapshots.request(&inst)
+                        }
+                    }
                 }
             }
         })
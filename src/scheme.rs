@@ -1,3 +1,7 @@
+#[cfg(feature = "binance")]
 pub mod binance;
 pub mod connector;
+#[cfg(feature = "binance")]
 mod http_client;
+#[cfg(feature = "binance")]
+mod rate_limit;
@@ -1,15 +1,20 @@
-use crate::common::{Level, Precision, Price, Qty};
-use crate::config::ExchangeConfig;
-use crate::scheme::connector::{AliasInstrument, HTTPApi, MarketQueries, Streams, WssStream};
+use crate::capture::{Recorder, Source};
+use crate::common::{Level, ParseDecimalError, Precision, Price, Qty};
+use crate::config::{BooksConfig, ExchangeConfig, StreamSpeed};
+use crate::error::{Error, Result};
+use crate::scheme::connector::{
+    AliasInstrument, HTTPApi, Instruments, MarketQueries, Streams, WssStream,
+};
 use crate::scheme::http_client::HTTPClient;
 use crate::structure::{Coin, Exchange, Feed, Instrument, MDResponse, Side};
 use crate::{common, structure};
 use async_trait::async_trait;
+use log::warn;
 use reqwest::Url;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use std::string::ToString;
-use std::time::SystemTime;
+use std::time::Duration;
 
 #[derive(Serialize)]
 pub struct Connect {
@@ -45,70 +50,107 @@ impl Symbol {
         None
     }
 
-    pub fn get_precision(&self) -> Precision {
-        let price =
-            Self::find_filter(&self.filters, "PRICE_FILTER").expect("Price filter not found");
-        let qty = Self::find_filter(&self.filters, "LOT_SIZE").expect("Qty filter not found");
-        Precision::new(
-            Price::new(
-                price
-                    .as_object()
-                    .expect("Filter expected to be object")
-                    .get("tickSize")
-                    .expect("tickSize not found")
-                    .as_str()
-                    .expect("Failed to convert tickSize to string")
-                    .parse::<f32>()
-                    .expect("Expected float in tickSize"),
-            ),
-            Qty::new(
-                qty.as_object()
-                    .expect("Filter expected to be object")
-                    .get("stepSize")
-                    .expect("stepSize not found")
-                    .as_str()
-                    .expect("Failed to convert stepSize to string")
-                    .parse::<f32>()
-                    .expect("Expected float in stepSize"),
-            ),
-        )
+    fn get_filter_value(&self, filter: &str, field: &str) -> Result<String> {
+        let missing = || Error::MissingFilter {
+            symbol: self.symbol.clone(),
+            filter: filter.to_string(),
+        };
+        let value = Self::find_filter(&self.filters, filter).ok_or_else(missing)?;
+        Ok(value
+            .get(field)
+            .and_then(|x| x.as_str())
+            .ok_or_else(|| {
+                Error::Decode(format!(
+                    "{} not found in {} of {}",
+                    field, filter, self.symbol
+                ))
+            })?
+            .to_string())
+    }
+
+    pub fn get_precision(&self) -> Result<Precision> {
+        let decode = |err: ParseDecimalError| Error::Decode(format!("{} for {}", err, self.symbol));
+        let tick = self
+            .get_filter_value("PRICE_FILTER", "tickSize")?
+            .parse::<Price>()
+            .map_err(decode)?;
+        let step = self
+            .get_filter_value("LOT_SIZE", "stepSize")?
+            .parse::<Qty>()
+            .map_err(decode)?;
+        if tick <= Price(0) || step <= Qty(0) {
+            return Err(Error::Decode(format!(
+                "tickSize {} and stepSize {} must be positive for {}",
+                tick, step, self.symbol
+            )));
+        }
+        Ok(Precision::new(tick, step))
     }
 }
 
 impl Connect {
-    const STREAM: &'static str = "SUBSCRIBE";
+    const SUBSCRIBE: &'static str = "SUBSCRIBE";
+    const UNSUBSCRIBE: &'static str = "UNSUBSCRIBE";
 
-    fn get_sub(inst: &Instrument, streams: &Streams) -> Vec<String> {
+    fn get_sub(inst: &Instrument, streams: &Streams, speed: StreamSpeed) -> Vec<String> {
         streams
             .iter()
             .map(|stream| {
-                let str = match stream {
-                    WssStream::Trade => "aggTrade",
-                    WssStream::Depth => "depth",
+                let str = match (stream, speed) {
+                    (WssStream::Trade, _) => "aggTrade",
+                    (WssStream::Depth, StreamSpeed::Ms100) => "depth@100ms",
+                    (WssStream::Depth, StreamSpeed::Ms250) => "depth",
+                    (WssStream::Depth, StreamSpeed::Ms500) => "depth@500ms",
                 };
                 format!("{}@{}", inst.to_raw_string().to_lowercase(), str)
             })
             .collect()
     }
 
-    pub fn new(id: u64, insts: &Vec<Instrument>, stream: &Streams) -> Connect {
+    pub fn new(
+        method: &str,
+        id: u64,
+        insts: &[Instrument],
+        stream: &Streams,
+        books: &BooksConfig,
+    ) -> Connect {
         Connect {
-            method: Self::STREAM.to_string(),
+            method: method.to_string(),
             id,
             params: insts
                 .iter()
-                .map(|inst| Self::get_sub(&inst, &stream))
-                .flatten()
+                .flat_map(|inst| Self::get_sub(inst, stream, books.get(inst.to_raw_string()).speed))
                 .collect(),
         }
     }
 
-    pub fn new_single(id: u64, inst: &Instrument, stream: &Streams) -> Connect {
-        Connect {
-            method: "SUBSCRIBE".parse().unwrap(),
-            id,
-            params: Self::get_sub(inst, &stream),
-        }
+    fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("Failed to serialize")
+    }
+}
+
+/// Error is reported either in place of result or nested
+#[derive(Deserialize)]
+struct AckError {
+    code: i64,
+    msg: String,
+}
+
+#[derive(Deserialize)]
+struct Ack {
+    id: u64,
+    #[serde(flatten)]
+    flat_error: Option<AckError>,
+    error: Option<AckError>,
+}
+
+impl Ack {
+    fn into_regular(self) -> structure::Ack {
+        let error = self.error.or(self.flat_error);
+        structure::Ack::new(
+            self.id,
+            error.map(|err| format!("{} (code {})", err.msg, err.code)),
+        )
     }
 }
 
@@ -126,20 +168,19 @@ struct Trade {
     last_id: u64,
     #[serde(alias = "m")]
     is_mm: bool,
+    #[serde(alias = "T")]
+    time: u64,
 }
 
 impl Trade {
-    fn to_regular(self, insts_map: &AliasInstrument) -> Option<structure::Trade> {
+    fn into_regular(self, insts_map: &AliasInstrument) -> Option<structure::Trade> {
         Some(structure::Trade::new(
             insts_map.get(&self.symbol)?.clone(),
             Level::new(self.price.parse().ok()?, self.qty.parse().ok()?),
-            if self.is_mm == true {
-                Side::Sell
-            } else {
-                Side::Buy
-            },
+            if self.is_mm { Side::Sell } else { Side::Buy },
             common::Id(self.first_id),
             common::Id(self.last_id),
+            self.time,
         ))
     }
 }
@@ -158,12 +199,19 @@ struct Delta {
     buy: Vec<(String, String)>,
     #[serde(alias = "a")]
     sell: Vec<(String, String)>,
+    #[serde(alias = "T")]
+    time: u64,
+    #[serde(alias = "E")]
+    event_time: u64,
 }
 
 #[derive(Deserialize)]
 struct Snapshot {
     #[serde(alias = "E")]
     message_time: u64,
+    /// Not sent by every market, event time is used then
+    #[serde(alias = "T")]
+    time: Option<u64>,
     #[serde(alias = "lastUpdateId")]
     last_id: u64,
     #[serde(alias = "bids")]
@@ -172,40 +220,81 @@ struct Snapshot {
     sell: Vec<(String, String)>,
 }
 
-fn pair_to_levels<'a, I>(pairs: I) -> Vec<Level>
+/// Any level, which can't be parsed exactly, rejects the whole message,
+/// as applying the rest of levels would corrupt the book.
+fn pair_to_levels<'a, I>(pairs: I) -> std::result::Result<Vec<Level>, ParseDecimalError>
 where
     I: Iterator<Item = &'a (String, String)>,
 {
-    pairs.map(|v| Level::from_str_pair(v)).flatten().collect()
+    pairs.map(Level::from_str_pair).collect()
 }
 
 impl Delta {
-    fn to_regular(self, insts_map: &AliasInstrument) -> Option<structure::Delta> {
-        Some(structure::Delta::new(
-            insts_map.get(&self.symbol)?.clone(),
-            pair_to_levels(self.buy.iter().rev()),
-            pair_to_levels(self.sell.iter()),
-            common::Id(self.first_id),
-            common::Id(self.last_id),
-            common::Id(self.last_stream),
-        ))
+    /// Rejected delta is missed by the book, which then resyncs.
+    fn into_regular(self, insts_map: &AliasInstrument) -> Option<structure::Delta> {
+        let levels = pair_to_levels(self.buy.iter().rev())
+            .and_then(|buy| Ok((buy, pair_to_levels(self.sell.iter())?)));
+        let (buy, sell) = match levels {
+            Ok(levels) => levels,
+            Err(err) => {
+                warn!(
+                    "Reject depth update {} of {}: {}",
+                    self.last_id, self.symbol, err
+                );
+                return None;
+            }
+        };
+        Some(structure::Delta {
+            event_time: self.event_time,
+            ..structure::Delta::new(
+                insts_map.get(&self.symbol)?.clone(),
+                buy,
+                sell,
+                common::Id(self.first_id),
+                common::Id(self.last_id),
+                common::Id(self.last_stream),
+                self.time,
+            )
+        })
     }
 }
 
 impl Snapshot {
-    fn to_regular(self, inst: Instrument) -> structure::Snapshot {
-        structure::Snapshot::new(
-            inst,
-            pair_to_levels(self.buy.iter().rev()),
-            pair_to_levels(self.sell.iter()),
-            common::Id(self.last_id),
-            self.message_time,
-        )
+    fn into_regular(self, inst: Instrument) -> Result<structure::Snapshot> {
+        let decode =
+            |err: ParseDecimalError| Error::Decode(format!("{} in snapshot of {:?}", err, inst));
+        let buy = pair_to_levels(self.buy.iter().rev()).map_err(decode)?;
+        let sell = pair_to_levels(self.sell.iter()).map_err(decode)?;
+        Ok(structure::Snapshot {
+            event_time: self.message_time,
+            ..structure::Snapshot::new(
+                inst,
+                buy,
+                sell,
+                common::Id(self.last_id),
+                self.time.unwrap_or(self.message_time),
+            )
+        })
+    }
+}
+
+const EXCHANGE_INFO_WEIGHT: u32 = 1;
+
+/// Request weight of depth snapshot with the given number of levels
+fn depth_weight(limit: u32) -> u32 {
+    match limit {
+        0..=50 => 2,
+        51..=100 => 5,
+        101..=500 => 10,
+        _ => 20,
     }
 }
 
 pub struct Api {
     cfg: ExchangeConfig,
+    http: HTTPClient,
+    recorder: Option<Recorder>,
+    books: BooksConfig,
 }
 
 impl Api {
@@ -213,53 +302,90 @@ impl Api {
         self.cfg.http_api.to_owned() + s
     }
 
-    pub(crate) fn new(cfg: ExchangeConfig) -> Api {
-        Api { cfg }
+    pub fn new(cfg: ExchangeConfig) -> Result<Api> {
+        let http = HTTPClient::new(&cfg.http)?;
+        Ok(Api {
+            cfg,
+            http,
+            recorder: None,
+            books: BooksConfig::default(),
+        })
     }
 
-    fn get_sub_id() -> u64 {
-        match SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
-            Ok(n) => n.as_secs(),
-            Err(_) => rand::random(),
-        }
+    /// Snapshot depth and stream speed of instruments.
+    pub fn with_books(mut self, books: BooksConfig) -> Api {
+        self.books = books;
+        self
     }
-}
 
-#[async_trait]
-impl HTTPApi for Api {
-    async fn instrument_info(&self) -> Vec<Instrument> {
-        HTTPClient::get::<ExchangeInfo>(self.get_api_url(self.cfg.exchange_info.as_ref()).as_ref())
-            .await
-            .expect("Failed to get exchange info")
+    /// Capture raw REST responses.
+    pub fn with_recorder(mut self, recorder: Recorder) -> Api {
+        self.recorder = Some(recorder);
+        self
+    }
+
+    /// Instruments of exchangeInfo response, the ones with invalid precision are skipped.
+    pub(crate) fn parse_exchange_info(body: &str) -> Result<Vec<Instrument>> {
+        Ok(serde_json::from_str::<ExchangeInfo>(body)?
             .symbols
             .iter()
-            .map(|symb| {
+            .filter_map(|symb| {
                 let feed = Feed::from_raw(&symb.contractType, symb.deliveryDate)?;
+                let precision = symb
+                    .get_precision()
+                    .map_err(|err| warn!("Skip instrument {}: {}", symb.symbol, err))
+                    .ok()?;
                 Some(Instrument::new(
-                    Coin((&symb.baseAsset).clone()),
-                    Coin((&symb.quoteAsset).clone()),
+                    Coin(symb.baseAsset.clone()),
+                    Coin(symb.quoteAsset.clone()),
                     feed,
                     Exchange::BINANCE,
-                    symb.get_precision(),
-                    (&symb.symbol).clone(),
+                    precision,
+                    symb.symbol.clone(),
                 ))
             })
-            .flatten()
-            .collect()
+            .collect())
+    }
+
+    pub(crate) fn parse_snapshot(body: &str, inst: Instrument) -> Result<structure::Snapshot> {
+        serde_json::from_str::<Snapshot>(body)?.into_regular(inst)
     }
+}
 
-    async fn request_depth_shapshot(&self, inst: Instrument) -> structure::Snapshot {
-        HTTPClient::get::<Snapshot>(
-            Url::parse_with_params(
-                &self.get_api_url(self.cfg.snapshot.as_ref()),
-                &[("symbol", inst.to_raw_string())],
+#[async_trait]
+impl HTTPApi for Api {
+    async fn instrument_info(&self) -> Result<Vec<Instrument>> {
+        let body = self
+            .http
+            .get_text(
+                self.get_api_url(self.cfg.exchange_info.as_ref()).as_ref(),
+                EXCHANGE_INFO_WEIGHT,
             )
-            .expect("Failed to get snapshot url")
-            .as_str(),
+            .await?;
+        if let Some(recorder) = &self.recorder {
+            recorder.record(Source::ExchangeInfo(Exchange::BINANCE), &body);
+        }
+        Self::parse_exchange_info(&body)
+    }
+
+    async fn request_depth_shapshot(&self, inst: Instrument) -> Result<structure::Snapshot> {
+        let limit = self.books.get(inst.to_raw_string()).snapshot_limit;
+        let url = Url::parse_with_params(
+            &self.get_api_url(self.cfg.snapshot.as_ref()),
+            &[
+                ("symbol", inst.to_raw_string().as_str()),
+                ("limit", limit.to_string().as_str()),
+            ],
         )
-        .await
-        .expect("Failed to get snapshot from Binance")
-        .to_regular(inst)
+        .map_err(|err| Error::InvalidUrl(err.to_string()))?;
+        let body = self
+            .http
+            .get_text(url.as_str(), depth_weight(limit))
+            .await?;
+        if let Some(recorder) = &self.recorder {
+            recorder.record(Source::Snapshot(inst.to_raw_string().clone()), &body);
+        }
+        Self::parse_snapshot(&body, inst)
     }
 }
 
@@ -268,48 +394,160 @@ impl MarketQueries for Api {
         &self.cfg.wss_api
     }
 
+    fn connection_lifetime(&self) -> Option<Duration> {
+        Some(Duration::from_secs(24 * 60 * 60))
+    }
+
     fn pong(&self) -> &'static str {
         "pong"
     }
 
-    fn subscribe(&self, inst: &Vec<Instrument>, stream: &Streams) -> String {
-        serde_json::to_string(&Connect::new(Self::get_sub_id(), &inst, &stream))
-            .expect("Failed to serialize")
+    fn subscribe(&self, id: u64, inst: &Instruments, stream: &Streams) -> String {
+        Connect::new(Connect::SUBSCRIBE, id, inst, stream, &self.books).to_json()
     }
 
-    fn subscribe_single(&self, inst: &Instrument, stream: &Streams) -> String {
-        serde_json::to_string(&Connect::new_single(Self::get_sub_id(), &inst, stream))
-            .expect("Failed to serialize")
+    fn subscribe_single(&self, id: u64, inst: &Instrument, stream: &Streams) -> String {
+        Connect::new(
+            Connect::SUBSCRIBE,
+            id,
+            std::slice::from_ref(inst),
+            stream,
+            &self.books,
+        )
+        .to_json()
     }
 
-    fn unsubscribe(&self, _instrument: &Vec<Instrument>, _stream: &Streams) -> String {
-        todo!()
+    fn unsubscribe(&self, id: u64, inst: &Instruments, stream: &Streams) -> String {
+        Connect::new(Connect::UNSUBSCRIBE, id, inst, stream, &self.books).to_json()
     }
 
-    fn unsubscribe_single(&self, _instrument: &Instrument, _stream: &Streams) -> String {
-        todo!()
+    fn unsubscribe_single(&self, id: u64, inst: &Instrument, stream: &Streams) -> String {
+        Connect::new(
+            Connect::UNSUBSCRIBE,
+            id,
+            std::slice::from_ref(inst),
+            stream,
+            &self.books,
+        )
+        .to_json()
     }
 
-    fn handle_response(&self, resp: &String, insts_map: &AliasInstrument) -> Option<MDResponse> {
-        Some(match resp.as_bytes()[6] {
+    fn handle_response(&self, resp: &str, insts_map: &AliasInstrument) -> Option<MDResponse> {
+        Some(match resp.as_bytes().get(6) {
             // just an optimization to avoid extra deserialization
-            97 => MDResponse::Trade(
+            Some(97) => MDResponse::Trade(
                 serde_json::from_str::<Trade>(resp)
                     .ok()?
-                    .to_regular(insts_map)?,
+                    .into_regular(insts_map)?,
             ),
-            100 => MDResponse::Delta(
+            Some(100) => MDResponse::Delta(
                 serde_json::from_str::<Delta>(resp)
                     .ok()?
-                    .to_regular(insts_map)?,
+                    .into_regular(insts_map)?,
             ),
             _ => {
                 if resp == "ping" {
                     MDResponse::Ping
                 } else {
-                    return None;
+                    MDResponse::Ack(serde_json::from_str::<Ack>(resp).ok()?.into_regular())
                 }
             }
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+
+    use crate::config::{BookOverride, BooksConfig, MDConfig, StreamSpeed};
+    use crate::error::Error;
+    use crate::scheme::binance::Api;
+    use crate::scheme::connector::{MarketQueries, WssStream};
+    use crate::structure::{Exchange, MDResponse};
+    use crate::test_support::inst;
+    use std::collections::HashMap;
+
+    fn api() -> Api {
+        let cfg = MDConfig::new("src/endpoints.toml".into()).unwrap();
+        Api::new(cfg.get(Exchange::BINANCE).unwrap().clone()).unwrap()
+    }
+
+    #[test]
+    fn subscription_requests() {
+        let inst = inst(0.01, 0.1);
+        let streams = vec![WssStream::Depth, WssStream::Trade];
+        assert_eq!(
+            api().unsubscribe_single(7, &inst, &streams),
+            r#"{"method":"UNSUBSCRIBE","params":["btcusdt@depth","btcusdt@aggTrade"],"id":7}"#
+        );
+        let mut books = BooksConfig::default();
+        books.instrument.insert(
+            "BTCUSDT".into(),
+            BookOverride {
+                speed: Some(StreamSpeed::Ms100),
+                ..Default::default()
+            },
+        );
+        assert_eq!(
+            api()
+                .with_books(books)
+                .subscribe_single(8, &inst, &vec![WssStream::Depth]),
+            r#"{"method":"SUBSCRIBE","params":["btcusdt@depth@100ms"],"id":8}"#
+        );
+
+        let map = HashMap::new();
+        let Some(MDResponse::Ack(ack)) = api().handle_response(r#"{"result":null,"id":7}"#, &map)
+        else {
+            panic!("ack expected");
+        };
+        assert_eq!((ack.id, ack.error), (7, None));
+        let Some(MDResponse::Ack(ack)) = api().handle_response(
+            r#"{"code":2,"msg":"Invalid request: unknown variant","id":8}"#,
+            &map,
+        ) else {
+            panic!("ack expected");
+        };
+        assert_eq!(ack.id, 8);
+        assert!(ack.error.is_some());
+    }
+
+    #[test]
+    fn event_and_transaction_time() {
+        let inst = inst(0.01, 0.1);
+        let map = HashMap::from([("BTCUSDT".to_string(), inst.clone())]);
+        let Some(MDResponse::Delta(delta)) = api().handle_response(
+            r#"{"e":"depthUpdate","E":12,"T":10,"s":"BTCUSDT","U":1,"u":2,"pu":0,"b":[],"a":[]}"#,
+            &map,
+        ) else {
+            panic!("delta expected");
+        };
+        assert_eq!((delta.time, delta.event_time), (10, 12));
+
+        let snapshot = Api::parse_snapshot(
+            r#"{"lastUpdateId":5,"E":22,"T":20,"bids":[],"asks":[]}"#,
+            inst.clone(),
+        )
+        .unwrap();
+        assert_eq!((snapshot.time, snapshot.event_time), (20, 22));
+        let snapshot =
+            Api::parse_snapshot(r#"{"lastUpdateId":5,"E":22,"bids":[],"asks":[]}"#, inst).unwrap();
+        assert_eq!((snapshot.time, snapshot.event_time), (22, 22));
+    }
+
+    #[test]
+    fn reject_inexact_levels() {
+        let inst = inst(0.01, 0.1);
+        let map = HashMap::from([("BTCUSDT".to_string(), inst.clone())]);
+        // 9 decimals can't be represented exactly
+        let delta = api().handle_response(
+            r#"{"e":"depthUpdate","E":12,"T":10,"s":"BTCUSDT","U":1,"u":2,"pu":0,"b":[["10.01","1"]],"a":[["10.020000001","1"]]}"#,
+            &map,
+        );
+        assert!(delta.is_none());
+        let snapshot = Api::parse_snapshot(
+            r#"{"lastUpdateId":5,"E":22,"bids":[["10.01","0.123456789"]],"asks":[]}"#,
+            inst,
+        );
+        assert!(matches!(snapshot, Err(Error::Decode(_))));
+    }
+}
@@ -1,7 +1,9 @@
+use crate::error::Result;
 use crate::structure;
 use crate::structure::{Instrument, MDResponse};
 use async_trait::async_trait;
 use std::collections::HashMap;
+use std::time::Duration;
 
 #[derive(Clone)]
 pub enum WssStream {
@@ -12,8 +14,8 @@ pub enum WssStream {
 #[async_trait]
 pub trait HTTPApi {
     // todo: generalize http calls with this trait
-    async fn instrument_info(&self) -> Vec<Instrument>;
-    async fn request_depth_shapshot(&self, inst: Instrument) -> structure::Snapshot;
+    async fn instrument_info(&self) -> Result<Vec<Instrument>>;
+    async fn request_depth_shapshot(&self, inst: Instrument) -> Result<structure::Snapshot>;
 }
 
 pub type Streams = Vec<WssStream>;
@@ -21,10 +23,13 @@ pub type Instruments = Vec<Instrument>;
 pub type AliasInstrument = HashMap<String, Instrument>;
 pub trait MarketQueries {
     fn connect_uri(&self) -> &String;
+    /// Time after which the exchange drops WebSocket connection
+    fn connection_lifetime(&self) -> Option<Duration>;
     fn pong(&self) -> &'static str;
-    fn subscribe(&self, inst: &Instruments, stream: &Streams) -> String;
-    fn subscribe_single(&self, inst: &Instrument, stream: &Streams) -> String;
-    fn unsubscribe(&self, instrument: &Instruments, stream: &Streams) -> String;
-    fn unsubscribe_single(&self, instrument: &Instrument, stream: &Streams) -> String;
-    fn handle_response(&self, resp: &String, inst_map: &AliasInstrument) -> Option<MDResponse>;
+    /// Subscription requests, `id` is returned back in `MDResponse::Ack`
+    fn subscribe(&self, id: u64, inst: &Instruments, stream: &Streams) -> String;
+    fn subscribe_single(&self, id: u64, inst: &Instrument, stream: &Streams) -> String;
+    fn unsubscribe(&self, id: u64, instrument: &Instruments, stream: &Streams) -> String;
+    fn unsubscribe_single(&self, id: u64, instrument: &Instrument, stream: &Streams) -> String;
+    fn handle_response(&self, resp: &str, inst_map: &AliasInstrument) -> Option<MDResponse>;
 }
@@ -1,31 +1,146 @@
-use reqwest_middleware::{ClientBuilder, ClientWithMiddleware};
-use reqwest_retry::{policies::ExponentialBackoff, RetryTransientMiddleware};
+use crate::config::HttpConfig;
+use crate::error::{Error, Result};
+use crate::scheme::rate_limit::WeightLimiter;
+use http::Extensions;
+use reqwest::header::RETRY_AFTER;
+use reqwest::{Request, Response, StatusCode};
+use reqwest_middleware::{ClientBuilder, ClientWithMiddleware, Middleware, Next};
+use reqwest_retry::{
+    default_on_request_failure, default_on_request_success, policies::ExponentialBackoff,
+    RetryTransientMiddleware, Retryable, RetryableStrategy,
+};
+use serde::Deserialize;
 use serde_json::from_str;
-use std::fmt::format;
-use tungstenite::client;
+use std::sync::Arc;
+use std::time::Duration;
 
-pub struct HTTPClient;
+/// Error payload returned along with unsuccessful status
+#[derive(Deserialize)]
+struct ErrorPayload {
+    code: i64,
+    msg: String,
+}
+
+/// Exchange asks to back off on 429 and bans IP on 418, so
+/// these are handled by rate limiter rather than retried right away.
+struct RateLimitStrategy;
+
+impl RetryableStrategy for RateLimitStrategy {
+    fn handle(
+        &self,
+        res: &std::result::Result<reqwest::Response, reqwest_middleware::Error>,
+    ) -> Option<Retryable> {
+        match res {
+            Ok(res) if is_rate_limited(res.status()) => Some(Retryable::Fatal),
+            Ok(res) => default_on_request_success(res),
+            Err(err) => default_on_request_failure(err),
+        }
+    }
+}
+
+fn is_rate_limited(status: StatusCode) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS || status == StatusCode::IM_A_TEAPOT
+}
+
+/// Used when exchange doesn't send Retry-After along with 429/418
+const DEFAULT_RETRY_AFTER: Duration = Duration::from_secs(60);
+
+/// Weight of a request, which is spent by every its attempt
+#[derive(Clone, Copy)]
+struct Weight(u32);
+
+/// Passes every attempt of a request, retries included, through the weight limiter
+/// and accounts the weight and bans reported in responses.
+struct LimiterMiddleware {
+    limiter: Arc<WeightLimiter>,
+}
+
+#[async_trait::async_trait]
+impl Middleware for LimiterMiddleware {
+    async fn handle(
+        &self,
+        req: Request,
+        extensions: &mut Extensions,
+        next: Next<'_>,
+    ) -> reqwest_middleware::Result<Response> {
+        let weight = extensions.get::<Weight>().map_or(1, |weight| weight.0);
+        self.limiter.acquire(weight).await;
+        let res = next.run(req, extensions).await?;
+        self.limiter.update(res.headers()).await;
+        if is_rate_limited(res.status()) {
+            let retry_after = res
+                .headers()
+                .get(RETRY_AFTER)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.parse::<u64>().ok())
+                .map_or(DEFAULT_RETRY_AFTER, Duration::from_secs);
+            self.limiter.ban(retry_after).await;
+        }
+        Ok(res)
+    }
+}
+
+/// HTTP client with retries of transient failures, it's reused between requests
+/// to keep connections and TLS sessions alive.
+/// All requests pass through the weight limiter shared by the client users.
+pub struct HTTPClient {
+    client: ClientWithMiddleware,
+}
 
 impl HTTPClient {
-    pub async fn get<T>(url: &str) -> serde_json::Result<T>
-    where
-        for<'a> T: serde::Deserialize<'a>,
-    {
-        let retry_policy = ExponentialBackoff::builder().build_with_max_retries(3);
-        let client = ClientBuilder::new(reqwest::Client::new())
-            .with(RetryTransientMiddleware::new_with_policy(retry_policy))
+    pub fn new(cfg: &HttpConfig) -> Result<HTTPClient> {
+        let mut builder = reqwest::Client::builder()
+            .connect_timeout(Duration::from_millis(cfg.connect_timeout_ms))
+            .timeout(Duration::from_millis(cfg.timeout_ms));
+        if let Some(user_agent) = &cfg.user_agent {
+            builder = builder.user_agent(user_agent);
+        }
+        if let Some(proxy) = &cfg.proxy {
+            builder = builder.proxy(reqwest::Proxy::all(proxy)?);
+        }
+        let retry_policy = ExponentialBackoff::builder()
+            .retry_bounds(
+                Duration::from_millis(cfg.min_backoff_ms),
+                Duration::from_millis(cfg.max_backoff_ms),
+            )
+            .build_with_max_retries(cfg.retries);
+        // the limiter is inner, so that it sees every retry
+        let client = ClientBuilder::new(builder.build()?)
+            .with(RetryTransientMiddleware::new_with_policy_and_strategy(
+                retry_policy,
+                RateLimitStrategy,
+            ))
+            .with(LimiterMiddleware {
+                limiter: Arc::new(WeightLimiter::new(cfg.weight_limit)),
+            })
             .build();
+        Ok(HTTPClient { client })
+    }
 
-        let res = client
+    /// Raw body of successful response, every attempt of the request is sent
+    /// once the limiter allows to spend `weight`.
+    pub async fn get_text(&self, url: &str, weight: u32) -> Result<String> {
+        let res = self
+            .client
             .get(url)
+            .with_extension(Weight(weight))
             .send()
-            .await
-            .expect(format!("HTTP request failed {url}").as_str());
-        let body = res
-            .text()
-            .await
-            .expect("Conversion of response to text failed")
-            .to_string();
-        from_str::<T>(body.as_str())
+            .await?;
+        let status = res.status();
+        let body = res.text().await?;
+        if !status.is_success() {
+            return Err(match from_str::<ErrorPayload>(body.as_str()) {
+                Ok(payload) => Error::Exchange {
+                    status: status.as_u16(),
+                    code: payload.code,
+                    msg: payload.msg,
+                },
+                Err(_) => Error::Status {
+                    status: status.as_u16(),
+                    body,
+                },
+            });
+        }
+        Ok(body)
     }
 }
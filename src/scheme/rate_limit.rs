@@ -0,0 +1,146 @@
+use log::warn;
+use reqwest::header::HeaderMap;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tokio::time::Instant;
+
+const WINDOW: Duration = Duration::from_secs(60);
+pub const USED_WEIGHT_HEADER: &str = "x-mbx-used-weight-1m";
+
+struct State {
+    window_start: Instant,
+    used: u32,
+    banned_until: Option<Instant>,
+}
+
+/// Limiter of request weight per minute. Requests are queued until there's
+/// enough weight left in the current window instead of being rejected by exchange.
+pub struct WeightLimiter {
+    limit: u32,
+    state: Mutex<State>,
+}
+
+impl WeightLimiter {
+    /// Zero `limit` disables limiting, but bans reported by exchange are still honoured.
+    pub fn new(limit: u32) -> WeightLimiter {
+        WeightLimiter {
+            limit,
+            state: Mutex::new(State {
+                window_start: Instant::now(),
+                used: 0,
+                banned_until: None,
+            }),
+        }
+    }
+
+    /// Wait until request with `weight` can be sent and reserve the weight.
+    /// The lock isn't held while waiting, so that bans and weight reported by
+    /// exchange in the meantime are taken into account once the waiter wakes up.
+    pub async fn acquire(&self, weight: u32) {
+        loop {
+            let deadline = {
+                let mut state = self.state.lock().await;
+                match self.reserve(&mut state, weight) {
+                    None => return,
+                    Some(deadline) => deadline,
+                }
+            };
+            tokio::time::sleep_until(deadline).await;
+        }
+    }
+
+    /// Reserve `weight` if possible, otherwise return the time to check again.
+    fn reserve(&self, state: &mut State, weight: u32) -> Option<Instant> {
+        let now = Instant::now();
+        if let Some(until) = state.banned_until {
+            if until > now {
+                return Some(until);
+            }
+            state.banned_until = None;
+        }
+        if now.duration_since(state.window_start) >= WINDOW {
+            state.window_start = now;
+            state.used = 0;
+        }
+        // single request heavier than the limit is sent in a fresh window
+        if self.limit == 0 || state.used == 0 || state.used + weight <= self.limit {
+            state.used += weight;
+            return None;
+        }
+        Some(state.window_start + WINDOW)
+    }
+
+    /// Account weight reported by exchange, e.g. consumed by other processes with the same IP.
+    pub async fn update(&self, headers: &HeaderMap) {
+        let used = headers
+            .get(USED_WEIGHT_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<u32>().ok());
+        if let Some(used) = used {
+            let mut state = self.state.lock().await;
+            state.used = state.used.max(used);
+        }
+    }
+
+    /// Stop sending requests for the time requested by exchange.
+    pub async fn ban(&self, retry_after: Duration) {
+        warn!("Requests are rate limited for {:?}", retry_after);
+        let mut state = self.state.lock().await;
+        let until = Instant::now() + retry_after;
+        state.banned_until = Some(state.banned_until.map_or(until, |x| x.max(until)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::scheme::rate_limit::{WeightLimiter, USED_WEIGHT_HEADER, WINDOW};
+    use reqwest::header::HeaderMap;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::time::Instant;
+
+    #[tokio::test(start_paused = true)]
+    async fn queue_until_next_window() {
+        let limiter = WeightLimiter::new(20);
+        let start = Instant::now();
+        limiter.acquire(10).await;
+        limiter.acquire(10).await;
+        assert_eq!(Instant::now(), start);
+
+        limiter.acquire(5).await;
+        assert_eq!(Instant::now(), start + WINDOW);
+
+        let mut headers = HeaderMap::new();
+        headers.insert(USED_WEIGHT_HEADER, "20".parse().unwrap());
+        limiter.update(&headers).await;
+        limiter.acquire(1).await;
+        assert_eq!(Instant::now(), start + WINDOW * 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retry_after() {
+        let limiter = WeightLimiter::new(0);
+        let start = Instant::now();
+        limiter.ban(Duration::from_secs(5)).await;
+        limiter.acquire(100).await;
+        assert_eq!(Instant::now(), start + Duration::from_secs(5));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn ban_while_waiting() {
+        let limiter = Arc::new(WeightLimiter::new(10));
+        let start = Instant::now();
+        limiter.acquire(10).await;
+        let waiter = tokio::spawn({
+            let limiter = limiter.clone();
+            async move { limiter.acquire(5).await }
+        });
+        tokio::task::yield_now().await;
+
+        // recorded right away, not after the waiter is sent
+        limiter.ban(Duration::from_secs(120)).await;
+        assert_eq!(Instant::now(), start);
+        waiter.await.unwrap();
+        assert_eq!(Instant::now(), start + Duration::from_secs(120));
+    }
+}
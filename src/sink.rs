@@ -0,0 +1,459 @@
+#[cfg(feature = "file-sink")]
+pub mod file;
+#[cfg(feature = "net-sink")]
+pub mod net;
+
+use crate::config::{SinkConfig, SinkKind};
+use crate::lob::analytics::{Analytics, AnalyticsConfig};
+use crate::lob::order_book::OrderBook;
+use crate::output::{BookUpdate, OutputFormat};
+#[cfg(feature = "file-sink")]
+use crate::sink::file::FileSink;
+#[cfg(feature = "net-sink")]
+use crate::sink::net::{TcpSink, UdpSink};
+use crate::structure::Instrument;
+use async_trait::async_trait;
+use log::{error, info, warn};
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncWriteExt, Stdout};
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+
+/// Number of updates, which may wait in the queue of a sink by default.
+pub const SINK_QUEUE_SIZE: usize = 1024;
+
+/// Consumer of book updates. Every sink is driven by its own task,
+/// so a slow sink delays only its own updates.
+#[async_trait]
+pub trait BookSink: Send {
+    async fn write(&mut self, update: &BookUpdate) -> io::Result<()>;
+}
+
+/// Which updates are passed to a sink.
+#[derive(Debug, Clone, Default)]
+pub struct SinkFilter {
+    /// Raw symbols, all instruments if not set
+    pub instruments: Option<HashSet<String>>,
+    /// Maximum number of levels on each side
+    pub depth: Option<usize>,
+    /// Minimum interval between updates of the same instrument,
+    /// the latest update within the interval is sent once it expires
+    pub throttle: Option<Duration>,
+    /// Attach analytics to updates
+    pub analytics: Option<AnalyticsConfig>,
+}
+
+impl From<&SinkConfig> for SinkFilter {
+    fn from(cfg: &SinkConfig) -> Self {
+        SinkFilter {
+            instruments: cfg
+                .instruments
+                .as_ref()
+                .map(|insts| insts.iter().map(|s| s.to_uppercase()).collect()),
+            depth: cfg.depth,
+            throttle: cfg.throttle_ms.map(Duration::from_millis),
+            analytics: cfg.analytics.clone(),
+        }
+    }
+}
+
+/// Writes formatted updates line by line.
+pub struct WriterSink<W> {
+    writer: W,
+    format: OutputFormat,
+    header_written: bool,
+}
+
+impl<W> WriterSink<W> {
+    pub fn new(writer: W, format: OutputFormat) -> WriterSink<W> {
+        WriterSink {
+            writer,
+            format,
+            header_written: false,
+        }
+    }
+}
+
+pub type StdoutSink = WriterSink<Stdout>;
+
+impl StdoutSink {
+    pub fn stdout(format: OutputFormat) -> StdoutSink {
+        WriterSink::new(tokio::io::stdout(), format)
+    }
+}
+
+#[async_trait]
+impl<W: tokio::io::AsyncWrite + Unpin + Send> BookSink for WriterSink<W> {
+    async fn write(&mut self, update: &BookUpdate) -> io::Result<()> {
+        if !self.header_written {
+            if let Some(header) = self.format.header(update) {
+                self.writer
+                    .write_all(format!("{}\n", header).as_bytes())
+                    .await?;
+            }
+            self.header_written = true;
+        }
+        let line = format!("{}\n", self.format.format(update));
+        self.writer.write_all(line.as_bytes()).await?;
+        self.writer.flush().await
+    }
+}
+
+/// Calls a function for every update, e.g. to feed a strategy in the same process.
+pub struct CallbackSink<F> {
+    callback: F,
+}
+
+impl<F: FnMut(&BookUpdate) + Send> CallbackSink<F> {
+    pub fn new(callback: F) -> CallbackSink<F> {
+        CallbackSink { callback }
+    }
+}
+
+#[async_trait]
+impl<F: FnMut(&BookUpdate) + Send> BookSink for CallbackSink<F> {
+    async fn write(&mut self, update: &BookUpdate) -> io::Result<()> {
+        (self.callback)(update);
+        Ok(())
+    }
+}
+
+/// Sink registered in `Sinks` along with its queue and filter.
+struct SinkHandle {
+    name: String,
+    filter: SinkFilter,
+    last_sent: HashMap<Instrument, Instant>,
+    /// The latest update of an instrument held back by throttling
+    pending: HashMap<Instrument, Arc<BookUpdate>>,
+    sender: mpsc::Sender<Arc<BookUpdate>>,
+    dropped: u64,
+    task: JoinHandle<()>,
+}
+
+impl SinkHandle {
+    fn accepts(&self, inst: &Instrument) -> bool {
+        match &self.filter.instruments {
+            Some(insts) => insts.contains(inst.to_raw_string()),
+            None => true,
+        }
+    }
+
+    /// Whether the throttling interval of the instrument expired by `now`.
+    fn is_due(&self, inst: &Instrument, now: Instant) -> bool {
+        match (self.filter.throttle, self.last_sent.get(inst)) {
+            (Some(throttle), Some(last)) => now.duration_since(*last) >= throttle,
+            _ => true,
+        }
+    }
+
+    fn publish(&mut self, inst: &Instrument, update: &Arc<BookUpdate>, book: &OrderBook) {
+        let mut update = match self.filter.depth {
+            Some(depth) if update.bids.len() > depth || update.asks.len() > depth => {
+                Arc::new(update.truncated(depth))
+            }
+            _ => update.clone(),
+        };
+        if let Some(cfg) = &self.filter.analytics {
+            // computed over the whole book, not only the levels passed to the sink
+            Arc::make_mut(&mut update).analytics = Some(Analytics::new(book, cfg));
+        }
+        if self.is_due(inst, Instant::now()) {
+            self.pending.remove(inst);
+            self.send(inst, update);
+        } else {
+            self.pending.insert(inst.clone(), update);
+        }
+    }
+
+    /// Time when the earliest held back update may be sent.
+    fn next_flush(&self) -> Option<Instant> {
+        let throttle = self.filter.throttle?;
+        self.pending
+            .keys()
+            .filter_map(|inst| self.last_sent.get(inst))
+            .min()
+            .map(|last| *last + throttle)
+    }
+
+    fn flush(&mut self, now: Instant) {
+        let due: Vec<Instrument> = self
+            .pending
+            .keys()
+            .filter(|inst| self.is_due(inst, now))
+            .cloned()
+            .collect();
+        for inst in due {
+            let update = self.pending.remove(&inst).expect("update is pending");
+            self.send(&inst, update);
+        }
+    }
+
+    fn send(&mut self, inst: &Instrument, update: Arc<BookUpdate>) {
+        match self.sender.try_send(update) {
+            Ok(()) => {
+                if self.filter.throttle.is_some() {
+                    self.last_sent.insert(inst.clone(), Instant::now());
+                }
+                if self.dropped > 0 {
+                    info!(
+                        "Sink {} recovered, {} updates dropped",
+                        self.name, self.dropped
+                    );
+                    self.dropped = 0;
+                }
+            }
+            Err(TrySendError::Full(_)) => {
+                if self.dropped == 0 {
+                    warn!("Sink {} is too slow, drop updates", self.name);
+                }
+                self.dropped += 1;
+            }
+            Err(TrySendError::Closed(_)) => {}
+        }
+    }
+}
+
+/// All sinks receiving book updates from the main loop.
+#[derive(Default)]
+pub struct Sinks {
+    sinks: Vec<SinkHandle>,
+}
+
+impl Sinks {
+    /// Register sink, which is fed through a queue of `queue_size` updates.
+    /// Updates are dropped for this sink only once its queue is full.
+    pub fn add(
+        &mut self,
+        name: &str,
+        mut sink: Box<dyn BookSink>,
+        filter: SinkFilter,
+        queue_size: usize,
+    ) {
+        let (sender, mut receiver) = mpsc::channel::<Arc<BookUpdate>>(queue_size);
+        let sink_name = name.to_string();
+        let task = tokio::spawn(async move {
+            while let Some(update) = receiver.recv().await {
+                if let Err(err) = sink.write(&update).await {
+                    error!("Sink {} failed to write: {}", sink_name, err);
+                }
+            }
+        });
+        self.sinks.push(SinkHandle {
+            name: name.to_string(),
+            filter,
+            last_sent: HashMap::new(),
+            pending: HashMap::new(),
+            sender,
+            dropped: 0,
+            task,
+        });
+    }
+
+    /// Open sink described in config, fails if the kind of sink isn't enabled by features.
+    pub async fn open(&mut self, cfg: &SinkConfig) -> io::Result<()> {
+        let (name, sink): (String, Box<dyn BookSink>) = match &cfg.kind {
+            SinkKind::Stdout => ("stdout".into(), Box::new(StdoutSink::stdout(cfg.format))),
+            #[cfg(feature = "file-sink")]
+            SinkKind::File {
+                path,
+                max_bytes,
+                keep,
+            } => (
+                format!("file {}", path),
+                Box::new(FileSink::open(path, cfg.format, *max_bytes, *keep).await?),
+            ),
+            #[cfg(feature = "net-sink")]
+            SinkKind::Tcp { addr } => (
+                format!("tcp {}", addr),
+                Box::new(TcpSink::bind(addr, cfg.format).await?),
+            ),
+            #[cfg(feature = "net-sink")]
+            SinkKind::Udp { addr } => (
+                format!("udp {}", addr),
+                Box::new(UdpSink::connect(addr, cfg.format).await?),
+            ),
+            #[allow(unreachable_patterns)]
+            kind => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    format!("{:?} sink is not enabled", kind),
+                ))
+            }
+        };
+        info!("Write book updates to {}", name);
+        self.add(&name, sink, cfg.into(), cfg.queue_size);
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sinks.is_empty()
+    }
+
+    /// Pass the state of the book to interested sinks without waiting for them.
+    pub fn publish(&mut self, inst: &Instrument, book: &OrderBook) {
+        let mut update: Option<Arc<BookUpdate>> = None;
+        for sink in self.sinks.iter_mut() {
+            if !sink.accepts(inst) {
+                continue;
+            }
+            let update = update.get_or_insert_with(|| Arc::new(BookUpdate::new(inst, book)));
+            sink.publish(inst, update, book);
+        }
+    }
+
+    /// Time when `flush` should be called to send updates held back by throttling.
+    pub fn next_flush(&self) -> Option<Instant> {
+        self.sinks.iter().filter_map(SinkHandle::next_flush).min()
+    }
+
+    /// Send held back updates, which throttling interval expired by `now`.
+    pub fn flush(&mut self, now: Instant) {
+        for sink in self.sinks.iter_mut() {
+            sink.flush(now);
+        }
+    }
+}
+
+impl Drop for Sinks {
+    fn drop(&mut self) {
+        for sink in &self.sinks {
+            sink.task.abort();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::common::{Id, Level};
+    use crate::lob::analytics::AnalyticsConfig;
+    use crate::lob::order_book::OrderBook;
+    use crate::output::BookUpdate;
+    use crate::sink::{BookSink, CallbackSink, SinkFilter, Sinks};
+    use crate::structure::{Instrument, MDResponse, Snapshot};
+    use crate::test_support::{inst, symbol, synced_book};
+    use async_trait::async_trait;
+    use std::io;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use tokio::time::Instant;
+
+    fn book(inst: &Instrument) -> OrderBook {
+        let buy = vec![
+            Level::from_float_pair(10.01, 1.),
+            Level::from_float_pair(10., 2.5),
+        ];
+        synced_book(inst, 10, buy, vec![])
+    }
+
+    /// Never completes a write
+    struct StuckSink;
+
+    #[async_trait]
+    impl BookSink for StuckSink {
+        async fn write(&mut self, _update: &BookUpdate) -> io::Result<()> {
+            std::future::pending().await
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn filters_and_backpressure() {
+        let (btc, eth) = (inst(0.01, 0.1), symbol("ETHUSDT", 0.01, 0.1));
+        let received = Arc::new(Mutex::new(vec![]));
+        let received_cl = received.clone();
+        let mut sinks = Sinks::default();
+        sinks.add("stuck", Box::new(StuckSink), SinkFilter::default(), 1);
+        let filter = SinkFilter {
+            instruments: Some(["BTCUSDT".to_string()].into()),
+            depth: Some(1),
+            throttle: Some(Duration::from_secs(1)),
+            analytics: Some(AnalyticsConfig::default()),
+        };
+        let callback = CallbackSink::new(move |update: &BookUpdate| {
+            received_cl.lock().unwrap().push(update.clone());
+        });
+        sinks.add("callback", Box::new(callback), filter, 10);
+
+        let (btc_book, eth_book) = (book(&btc), book(&eth));
+        sinks.publish(&btc, &btc_book);
+        sinks.publish(&eth, &eth_book);
+        // throttled
+        sinks.publish(&btc, &btc_book);
+        assert_eq!(sinks.sinks[0].dropped, 2);
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        sinks.publish(&btc, &btc_book);
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        let received = received.lock().unwrap();
+        assert_eq!(received.len(), 2);
+        assert!(received.iter().all(|u| u.inst == btc && u.bids.len() == 1));
+        // computed over all levels, though only the best one is passed
+        let analytics = received[0].analytics.as_ref().unwrap();
+        assert_eq!(analytics.imbalance, Some(1.));
+        assert_eq!(analytics.bid_depth, None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn throttle_conflates() {
+        let btc = inst(0.01, 0.1);
+        let received = Arc::new(Mutex::new(vec![]));
+        let received_cl = received.clone();
+        let mut sinks = Sinks::default();
+        let filter = SinkFilter {
+            throttle: Some(Duration::from_secs(1)),
+            ..Default::default()
+        };
+        let callback = CallbackSink::new(move |update: &BookUpdate| {
+            received_cl.lock().unwrap().push(update.time);
+        });
+        sinks.add("callback", Box::new(callback), filter, 10);
+        let book_at = |time: u64| {
+            let mut book = OrderBook::new(btc.precision.clone(), 10);
+            let snapshot = Snapshot::new(btc.clone(), vec![], vec![], Id(205), time);
+            let _ = book.apply(MDResponse::Snapshot(snapshot));
+            book
+        };
+
+        let start = Instant::now();
+        sinks.publish(&btc, &book_at(2));
+        assert_eq!(sinks.next_flush(), None);
+        sinks.publish(&btc, &book_at(3));
+        sinks.publish(&btc, &book_at(4));
+        assert_eq!(sinks.next_flush(), Some(start + Duration::from_secs(1)));
+        sinks.flush(start + Duration::from_millis(500));
+        tokio::time::sleep_until(sinks.next_flush().unwrap()).await;
+        sinks.flush(Instant::now());
+        assert_eq!(sinks.next_flush(), None);
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        // the last state before the quiet period is delivered
+        assert_eq!(*received.lock().unwrap(), vec![2, 4]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn throttle_after_dropped_update() {
+        let btc = inst(0.01, 0.1);
+        let mut sinks = Sinks::default();
+        let filter = SinkFilter {
+            throttle: Some(Duration::from_secs(1)),
+            ..Default::default()
+        };
+        sinks.add("stuck", Box::new(StuckSink), filter, 1);
+        let book = book(&btc);
+        // the first update is taken by the sink, the second one fills the queue
+        sinks.publish(&btc, &book);
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        sinks.publish(&btc, &book);
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        sinks.publish(&btc, &book);
+        assert_eq!(sinks.sinks[0].dropped, 1);
+        // dropped update doesn't start the interval
+        sinks.publish(&btc, &book);
+        assert_eq!(sinks.sinks[0].dropped, 2);
+        assert_eq!(sinks.next_flush(), None);
+    }
+}
@@ -0,0 +1,130 @@
+use crate::output::{BookUpdate, OutputFormat};
+use crate::sink::BookSink;
+use async_trait::async_trait;
+use std::io;
+use tokio::fs::{File, OpenOptions};
+use tokio::io::AsyncWriteExt;
+
+/// Appends formatted updates to a file. Once the file grows over `max_bytes`,
+/// it's renamed to `<path>.1` (older files are shifted up to `<path>.<keep>`)
+/// and a new file is started.
+pub struct FileSink {
+    path: String,
+    format: OutputFormat,
+    max_bytes: Option<u64>,
+    keep: usize,
+    file: File,
+    written: u64,
+}
+
+impl FileSink {
+    pub async fn open(
+        path: &str,
+        format: OutputFormat,
+        max_bytes: Option<u64>,
+        keep: usize,
+    ) -> io::Result<FileSink> {
+        let (file, written) = Self::open_file(path).await?;
+        Ok(FileSink {
+            path: path.to_string(),
+            format,
+            max_bytes,
+            keep,
+            file,
+            written,
+        })
+    }
+
+    async fn open_file(path: &str) -> io::Result<(File, u64)> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await?;
+        let written = file.metadata().await?.len();
+        Ok((file, written))
+    }
+
+    fn rotated(&self, num: usize) -> String {
+        format!("{}.{}", self.path, num)
+    }
+
+    async fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush().await?;
+        if self.keep == 0 {
+            tokio::fs::remove_file(&self.path).await?;
+        } else {
+            for num in (1..self.keep).rev() {
+                if tokio::fs::try_exists(self.rotated(num)).await? {
+                    tokio::fs::rename(self.rotated(num), self.rotated(num + 1)).await?;
+                }
+            }
+            tokio::fs::rename(&self.path, self.rotated(1)).await?;
+        }
+        (self.file, self.written) = Self::open_file(&self.path).await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl BookSink for FileSink {
+    async fn write(&mut self, update: &BookUpdate) -> io::Result<()> {
+        if self.max_bytes.is_some_and(|max| self.written >= max) {
+            self.rotate().await?;
+        }
+        let mut data = String::new();
+        if self.written == 0 {
+            if let Some(header) = self.format.header(update) {
+                data = format!("{}\n", header);
+            }
+        }
+        data += &self.format.format(update);
+        data.push('\n');
+        self.file.write_all(data.as_bytes()).await?;
+        self.written += data.len() as u64;
+        self.file.flush().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::common::Id;
+    use crate::output::{BookUpdate, OutputFormat};
+    use crate::sink::file::FileSink;
+    use crate::sink::BookSink;
+
+    use crate::test_support::inst;
+
+    #[tokio::test]
+    async fn rotation() {
+        let dir = std::env::temp_dir().join(format!("market_data_sink_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("books.csv").to_string_lossy().to_string();
+        let inst = inst(0.01, 0.1);
+        let update = BookUpdate {
+            inst,
+            last_update_id: Id(1),
+            time: 0,
+            event_time: 0,
+            trade_adjusted: false,
+            stale: false,
+            bids: vec![],
+            asks: vec![],
+            analytics: None,
+        };
+
+        let mut sink = FileSink::open(&path, OutputFormat::Csv, Some(100), 1)
+            .await
+            .unwrap();
+        for _ in 0..4 {
+            sink.write(&update).await.unwrap();
+        }
+        let current = std::fs::read_to_string(&path).unwrap();
+        let rotated = std::fs::read_to_string(format!("{}.1", path)).unwrap();
+        // header with a line exceed the limit
+        assert_eq!(rotated.lines().count(), 2);
+        assert!(current.starts_with("instrument,"));
+        assert!(!std::path::Path::new(&format!("{}.2", path)).exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
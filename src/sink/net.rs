@@ -0,0 +1,206 @@
+use crate::output::{BookUpdate, OutputFormat};
+use crate::sink::BookSink;
+use async_trait::async_trait;
+use log::{info, warn};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::task::JoinHandle;
+
+/// Number of lines, which may wait to be written to a single TCP client.
+pub const CLIENT_QUEUE_SIZE: usize = 1024;
+
+/// Connected client with own writer task, so that a slow client doesn't delay the others.
+struct Client {
+    lines: mpsc::Sender<Arc<str>>,
+    dropped: u64,
+}
+
+impl Client {
+    fn spawn(mut stream: TcpStream) -> Client {
+        let (lines, mut receiver) = mpsc::channel::<Arc<str>>(CLIENT_QUEUE_SIZE);
+        tokio::spawn(async move {
+            while let Some(line) = receiver.recv().await {
+                if let Err(err) = stream.write_all(line.as_bytes()).await {
+                    warn!("Disconnect sink client: {}", err);
+                    return;
+                }
+            }
+        });
+        Client { lines, dropped: 0 }
+    }
+
+    /// Queue the line, returns false if the client is disconnected.
+    fn send(&mut self, line: Arc<str>) -> bool {
+        match self.lines.try_send(line) {
+            Ok(()) => {
+                if self.dropped > 0 {
+                    info!("Sink client recovered, {} updates dropped", self.dropped);
+                    self.dropped = 0;
+                }
+                true
+            }
+            Err(TrySendError::Full(_)) => {
+                if self.dropped == 0 {
+                    warn!("Sink client is too slow, drop updates");
+                }
+                self.dropped += 1;
+                true
+            }
+            Err(TrySendError::Closed(_)) => false,
+        }
+    }
+}
+
+/// Broadcasts formatted updates to all clients connected to the address.
+/// Updates are dropped for a client, which can't keep up, and
+/// the client is disconnected once writing to it fails.
+pub struct TcpSink {
+    format: OutputFormat,
+    clients: Vec<Client>,
+    accepted: mpsc::UnboundedReceiver<TcpStream>,
+    local_addr: SocketAddr,
+    listener: JoinHandle<()>,
+}
+
+impl TcpSink {
+    pub async fn bind(addr: &str, format: OutputFormat) -> io::Result<TcpSink> {
+        let listener = TcpListener::bind(addr).await?;
+        let local_addr = listener.local_addr()?;
+        let (tx, accepted) = mpsc::unbounded_channel();
+        let listener = tokio::spawn(async move {
+            loop {
+                match listener.accept().await {
+                    Ok((stream, peer)) => {
+                        info!("Sink client {} connected", peer);
+                        if tx.send(stream).is_err() {
+                            return;
+                        }
+                    }
+                    Err(err) => warn!("Failed to accept sink client: {}", err),
+                }
+            }
+        });
+        Ok(TcpSink {
+            format,
+            clients: vec![],
+            accepted,
+            local_addr,
+            listener,
+        })
+    }
+
+    /// Address the sink listens on, e.g. to find the port bound for ":0".
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+}
+
+#[async_trait]
+impl BookSink for TcpSink {
+    async fn write(&mut self, update: &BookUpdate) -> io::Result<()> {
+        while let Ok(stream) = self.accepted.try_recv() {
+            let mut client = Client::spawn(stream);
+            if let Some(header) = self.format.header(update) {
+                client.send(format!("{}\n", header).into());
+            }
+            self.clients.push(client);
+        }
+        let line: Arc<str> = format!("{}\n", self.format.format(update)).into();
+        self.clients.retain_mut(|client| client.send(line.clone()));
+        Ok(())
+    }
+}
+
+impl Drop for TcpSink {
+    fn drop(&mut self) {
+        self.listener.abort();
+    }
+}
+
+/// Sends every formatted update as a datagram, e.g. to a broadcast address.
+pub struct UdpSink {
+    format: OutputFormat,
+    socket: UdpSocket,
+}
+
+impl UdpSink {
+    pub async fn connect(addr: &str, format: OutputFormat) -> io::Result<UdpSink> {
+        let socket = UdpSocket::bind("0.0.0.0:0").await?;
+        socket.set_broadcast(true)?;
+        socket.connect(addr).await?;
+        Ok(UdpSink { format, socket })
+    }
+}
+
+#[async_trait]
+impl BookSink for UdpSink {
+    async fn write(&mut self, update: &BookUpdate) -> io::Result<()> {
+        self.socket
+            .send(self.format.format(update).as_bytes())
+            .await
+            .map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::common::{Id, Level, Price, Qty};
+    use crate::output::{BookUpdate, OutputFormat};
+    use crate::sink::net::TcpSink;
+    use crate::sink::BookSink;
+
+    use crate::test_support::inst;
+    use std::time::Duration;
+    use tokio::io::{AsyncBufReadExt, BufReader};
+    use tokio::net::{TcpSocket, TcpStream};
+
+    #[tokio::test]
+    async fn slow_client() {
+        let mut sink = TcpSink::bind("127.0.0.1:0", OutputFormat::Json)
+            .await
+            .unwrap();
+        // never reads, so its socket buffers fill up
+        let slow = TcpSocket::new_v4().unwrap();
+        slow.set_recv_buffer_size(4096).unwrap();
+        let _slow = slow.connect(sink.local_addr()).await.unwrap();
+        let fast = TcpStream::connect(sink.local_addr()).await.unwrap();
+        while sink.accepted.len() < 2 {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        let levels: Vec<Level> = (1..1000)
+            .map(|i| Level::new(Price(i * 1_000_000), Qty(1)))
+            .collect();
+        let update = BookUpdate {
+            inst: inst(0.01, 0.1),
+            last_update_id: Id(1),
+            time: 0,
+            event_time: 0,
+            trade_adjusted: false,
+            stale: false,
+            bids: levels.clone(),
+            asks: levels,
+            analytics: None,
+        };
+
+        const UPDATES: usize = 300;
+        let reader = tokio::spawn(async move {
+            let mut lines = BufReader::new(fast).lines();
+            let mut count = 0;
+            while count < UPDATES && lines.next_line().await.unwrap().is_some() {
+                count += 1;
+            }
+            count
+        });
+        for _ in 0..UPDATES {
+            sink.write(&update).await.unwrap();
+            tokio::task::yield_now().await;
+        }
+        let received = tokio::time::timeout(Duration::from_secs(10), reader).await;
+        assert_eq!(received.unwrap().unwrap(), UPDATES);
+    }
+}
@@ -0,0 +1,227 @@
+use crate::error::Result;
+use crate::runner::Runner;
+use crate::scheme::connector::HTTPApi;
+use crate::structure::{Exchange, Instrument, MDResponse};
+use futures_util::FutureExt;
+use log::{debug, error, warn};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc::Sender;
+use tokio::sync::{Mutex, Semaphore};
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+
+/// Maximum number of snapshot requests executed at the same time.
+pub const MAX_CONCURRENT_SNAPSHOTS: usize = 4;
+/// Minimum delay between starts of two snapshot requests, so that resync
+/// of many instruments at once doesn't exceed exchange rate limits.
+const SNAPSHOT_INTERVAL: Duration = Duration::from_millis(200);
+/// Unhealthy instrument isn't requested again until this time passes after a failure.
+const UNHEALTHY_COOLDOWN: Duration = Duration::from_secs(30);
+
+pub type HTTPExchanges = Vec<(Exchange, Arc<dyn HTTPApi + Send + Sync>)>;
+
+enum Request {
+    Running(JoinHandle<Result<()>>),
+    /// Snapshot is in the channel, but not yet processed
+    Sent,
+}
+
+/// Requests depth snapshots in background tasks and feeds them back through the channel,
+/// so that HTTP round trip doesn't block updates of other instruments.
+/// At most one request per instrument is in flight.
+pub struct SnapshotFetcher {
+    exch: HTTPExchanges,
+    sender: Sender<MDResponse>,
+    permits: Arc<Semaphore>,
+    next_start: Arc<Mutex<Instant>>,
+    in_flight: HashMap<Instrument, Request>,
+    /// Instruments with failed last request and time of the failure
+    unhealthy: HashMap<Instrument, Instant>,
+}
+
+impl SnapshotFetcher {
+    pub fn new(exch: HTTPExchanges, sender: Sender<MDResponse>, max_concurrent: usize) -> Self {
+        SnapshotFetcher {
+            exch,
+            sender,
+            permits: Arc::new(Semaphore::new(max_concurrent)),
+            next_start: Arc::new(Mutex::new(Instant::now())),
+            in_flight: HashMap::new(),
+            unhealthy: HashMap::new(),
+        }
+    }
+
+    /// Whether snapshot is being requested or already on the way to the main loop.
+    pub fn is_in_flight(&mut self, inst: &Instrument) -> bool {
+        let Some(request) = self.in_flight.get_mut(inst) else {
+            return false;
+        };
+        let handle = match request {
+            Request::Sent => return true,
+            Request::Running(handle) if !handle.is_finished() => return true,
+            Request::Running(handle) => handle,
+        };
+        match handle.now_or_never() {
+            Some(Ok(Ok(()))) => {
+                *request = Request::Sent;
+                true
+            }
+            Some(Ok(Err(err))) => {
+                self.mark_unhealthy(inst, err.to_string());
+                false
+            }
+            Some(Err(err)) => {
+                self.mark_unhealthy(inst, err.to_string());
+                false
+            }
+            None => true,
+        }
+    }
+
+    fn mark_unhealthy(&mut self, inst: &Instrument, reason: String) {
+        error!(
+            "Snapshot request for {} failed, mark unhealthy: {}",
+            inst.to_raw_string(),
+            reason
+        );
+        self.in_flight.remove(inst);
+        self.unhealthy.insert(inst.clone(), Instant::now());
+    }
+
+    /// Whether the last snapshot request for the instrument succeeded.
+    #[cfg(test)]
+    pub fn is_healthy(&self, inst: &Instrument) -> bool {
+        !self.unhealthy.contains_key(inst)
+    }
+
+    /// Forget the failure of the last request, so that the next one isn't delayed.
+    pub fn reset(&mut self, inst: &Instrument) {
+        self.unhealthy.remove(inst);
+    }
+
+    /// Request snapshot unless there's one in flight for the instrument.
+    pub fn request(&mut self, inst: &Instrument) {
+        if self.is_in_flight(inst) {
+            debug!("Snapshot for {} is already requested", inst.to_raw_string());
+            return;
+        }
+        if let Some(failed) = self.unhealthy.get(inst) {
+            if failed.elapsed() < UNHEALTHY_COOLDOWN {
+                return;
+            }
+        }
+        let Some((_, api)) = self.exch.iter().find(|(e, _)| &inst.exchange == e) else {
+            warn!("No HTTP api to request snapshot for {:?}", inst);
+            return;
+        };
+        let (api, sender, inst_cl) = (api.clone(), self.sender.clone(), inst.clone());
+        let (permits, next_start) = (self.permits.clone(), self.next_start.clone());
+        let handle = tokio::spawn(async move {
+            let _permit = permits.acquire_owned().await;
+            {
+                let mut next = next_start.lock().await;
+                tokio::time::sleep_until(*next).await;
+                *next = Instant::now() + SNAPSHOT_INTERVAL;
+            }
+            let snapshot = Runner::request_snapshot(api.as_ref(), &inst_cl).await?;
+            if sender.send(MDResponse::Snapshot(snapshot)).await.is_err() {
+                warn!("Snapshot for {} dropped", inst_cl.to_raw_string());
+            }
+            Ok(())
+        });
+        self.in_flight
+            .insert(inst.clone(), Request::Running(handle));
+    }
+
+    /// Snapshot for the instrument reached the main loop.
+    pub fn received(&mut self, inst: &Instrument) {
+        self.in_flight.remove(inst);
+        self.unhealthy.remove(inst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::common::Id;
+    use crate::error::{Error, Result};
+    use crate::scheme::connector::HTTPApi;
+    use crate::snapshot::SnapshotFetcher;
+    use crate::structure::{Exchange, Instrument, MDResponse, Snapshot};
+    use crate::test_support::{inst, symbol};
+    use async_trait::async_trait;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::Arc;
+    use tokio::sync::mpsc;
+
+    #[derive(Default)]
+    struct Api {
+        calls: AtomicU64,
+    }
+
+    #[async_trait]
+    impl HTTPApi for Api {
+        async fn instrument_info(&self) -> Result<Vec<Instrument>> {
+            Ok(vec![])
+        }
+
+        async fn request_depth_shapshot(&self, inst: Instrument) -> Result<Snapshot> {
+            let id = self.calls.fetch_add(1, Ordering::SeqCst);
+            if inst.to_raw_string() == "UNKNOWN" {
+                return Err(Error::Exchange {
+                    status: 400,
+                    code: -1121,
+                    msg: "Invalid symbol.".into(),
+                });
+            }
+            Ok(Snapshot::new(inst, vec![], vec![], Id(id), 0))
+        }
+    }
+
+    #[tokio::test]
+    async fn single_request_in_flight() {
+        let api = Arc::new(Api::default());
+        let (tx, mut rx) = mpsc::channel(10);
+        let mut fetcher = SnapshotFetcher::new(vec![(Exchange::BINANCE, api.clone())], tx, 1);
+        let (btc, eth) = (inst(0.01, 0.1), symbol("ETHUSDT", 0.01, 0.1));
+
+        fetcher.request(&btc);
+        fetcher.request(&btc);
+        fetcher.request(&eth);
+        let mut received = vec![];
+        for _ in 0..2 {
+            let Some(MDResponse::Snapshot(snapshot)) = rx.recv().await else {
+                panic!("snapshot expected");
+            };
+            assert!(fetcher.is_in_flight(&snapshot.inst));
+            fetcher.received(&snapshot.inst);
+            received.push(snapshot.inst.to_raw_string().clone());
+        }
+        received.sort();
+        assert_eq!(received, vec!["BTCUSDT", "ETHUSDT"]);
+        assert_eq!(api.calls.load(Ordering::SeqCst), 2);
+
+        fetcher.request(&btc);
+        assert!(rx.recv().await.is_some());
+        assert_eq!(api.calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn failed_request() {
+        let api = Arc::new(Api::default());
+        let (tx, _rx) = mpsc::channel(10);
+        let mut fetcher = SnapshotFetcher::new(vec![(Exchange::BINANCE, api.clone())], tx, 1);
+        let unknown = symbol("UNKNOWN", 0.01, 0.1);
+
+        fetcher.request(&unknown);
+        while fetcher.is_in_flight(&unknown) {
+            tokio::task::yield_now().await;
+        }
+        assert!(!fetcher.is_healthy(&unknown));
+        // not requested again during cooldown
+        fetcher.request(&unknown);
+        assert!(!fetcher.is_in_flight(&unknown));
+        assert_eq!(api.calls.load(Ordering::SeqCst), 1);
+    }
+}
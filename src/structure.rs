@@ -3,6 +3,7 @@ use derive_new::new;
 use serde::Deserialize;
 use std::fmt;
 use std::hash::{Hash, Hasher};
+use tokio::time::Instant;
 
 #[derive(Clone, Eq, Hash, PartialEq)]
 pub struct Coin(pub String);
@@ -13,6 +14,7 @@ pub enum Side {
     Sell,
 }
 
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Clone, Eq, Hash, PartialEq)]
 pub enum Feed {
     FUTURE(u64),
@@ -32,7 +34,8 @@ impl Feed {
     }
 }
 
-#[derive(Clone, Eq, Hash, PartialEq, Deserialize)]
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, Eq, Hash, PartialEq, Deserialize)]
 pub enum Exchange {
     BINANCE,
 }
@@ -75,16 +78,18 @@ impl Instrument {
     }
 }
 
-#[derive(new, Debug)]
+#[derive(new, Debug, Clone)]
 pub struct Trade {
-    inst: Instrument,
-    info: Level,
-    pub(crate) side: Side,
-    first: Id,
-    last: Id,
+    pub inst: Instrument,
+    pub info: Level,
+    /// Side of the taker
+    pub side: Side,
+    pub first: Id,
+    pub last: Id,
+    pub time: u64,
 }
 
-#[derive(new, Debug)]
+#[derive(new, Debug, Clone)]
 pub struct Delta {
     pub inst: Instrument,
     pub buy: Vec<Level>,
@@ -92,6 +97,14 @@ pub struct Delta {
     pub first: Id,
     pub last: Id,
     pub last_stream: Id,
+    /// Transaction time `T`
+    pub time: u64,
+    /// Event time `E`, when exchange sent the update
+    #[new(default)]
+    pub event_time: u64,
+    /// Local time the frame was received
+    #[new(default)]
+    pub received: Option<Instant>,
 }
 
 #[derive(Debug, new)]
@@ -100,7 +113,18 @@ pub struct Snapshot {
     pub buy: Vec<Level>,
     pub sell: Vec<Level>,
     pub last: Id,
+    /// Transaction time `T`
     pub time: u64,
+    /// Event time `E`, when exchange sent the snapshot
+    #[new(default)]
+    pub event_time: u64,
+}
+
+/// Response to subscription request with the same id
+#[derive(Debug, new)]
+pub struct Ack {
+    pub id: u64,
+    pub error: Option<String>,
 }
 
 #[derive(Debug)]
@@ -109,15 +133,20 @@ pub enum MDResponse {
     Snapshot(Snapshot),
     Delta(Delta),
     Ping,
+    /// Updates for the instrument could be lost, e.g. after reconnection,
+    /// so the book should be rebuilt from a fresh snapshot.
+    Resync(Instrument),
+    Ack(Ack),
 }
 
 impl MDResponse {
     pub fn get_inst(&self) -> Option<Instrument> {
         Some(match self {
-            MDResponse::Ping => return None,
+            MDResponse::Ping | MDResponse::Ack(_) => return None,
             MDResponse::Delta(d) => d.inst.clone(),
             MDResponse::Snapshot(d) => d.inst.clone(),
             MDResponse::Trade(d) => d.inst.clone(),
+            MDResponse::Resync(inst) => inst.clone(),
         })
     }
 }
@@ -0,0 +1,71 @@
+use crate::structure::Instrument;
+use log::warn;
+use tokio::sync::broadcast;
+
+/// Number of commands, which may wait for processing in every connection.
+pub(crate) const COMMAND_QUEUE_SIZE: usize = 64;
+
+/// Change of the instrument set, applied to all connections and order books.
+#[derive(Debug, Clone)]
+pub enum Command {
+    Subscribe(Vec<Instrument>),
+    Unsubscribe(Vec<Instrument>),
+    /// Subscribe again the instruments, which streams stopped without disconnection
+    Resubscribe(Vec<Instrument>),
+}
+
+impl Command {
+    /// Update the instrument set according to the command.
+    pub fn apply(&self, insts: &mut Vec<Instrument>) {
+        match self {
+            Command::Subscribe(added) => {
+                for inst in added {
+                    if !insts.contains(inst) {
+                        insts.push(inst.clone());
+                    }
+                }
+            }
+            Command::Unsubscribe(removed) => insts.retain(|inst| !removed.contains(inst)),
+            Command::Resubscribe(_) => {}
+        }
+    }
+}
+
+/// Handle to add or remove instruments at runtime.
+#[derive(Clone)]
+pub struct SubscriptionControl {
+    sender: broadcast::Sender<Command>,
+}
+
+impl Default for SubscriptionControl {
+    fn default() -> Self {
+        SubscriptionControl {
+            sender: broadcast::channel(COMMAND_QUEUE_SIZE).0,
+        }
+    }
+}
+
+impl SubscriptionControl {
+    /// Receiver of commands sent after this call.
+    pub fn listen(&self) -> broadcast::Receiver<Command> {
+        self.sender.subscribe()
+    }
+
+    fn send(&self, cmd: Command) {
+        if self.sender.send(cmd).is_err() {
+            warn!("Nobody listens to subscription commands");
+        }
+    }
+
+    pub fn subscribe(&self, insts: Vec<Instrument>) {
+        self.send(Command::Subscribe(insts))
+    }
+
+    pub fn unsubscribe(&self, insts: Vec<Instrument>) {
+        self.send(Command::Unsubscribe(insts))
+    }
+
+    pub fn resubscribe(&self, insts: Vec<Instrument>) {
+        self.send(Command::Resubscribe(insts))
+    }
+}
@@ -0,0 +1,37 @@
+use crate::common::{Id, Level, Precision, Price, Qty};
+use crate::lob::order_book::OrderBook;
+use crate::structure::{Coin, Delta, Exchange, Feed, Instrument, MDResponse, Snapshot};
+
+/// Binance perpetual `raw` quoted in USDT.
+pub(crate) fn symbol(raw: &str, tick: f64, step: f64) -> Instrument {
+    Instrument::new(
+        Coin(raw.trim_end_matches("USDT").into()),
+        Coin("USDT".into()),
+        Feed::PERP,
+        Exchange::BINANCE,
+        Precision::new(Price::from_f64(tick), Qty::from_f64(step)),
+        raw.into(),
+    )
+}
+
+pub(crate) fn inst(tick: f64, step: f64) -> Instrument {
+    symbol("BTCUSDT", tick, step)
+}
+
+/// Book synced by a snapshot `205` over the buffered delta `200..=210` with event time 8.
+pub(crate) fn synced_book(
+    inst: &Instrument,
+    depth_limit: usize,
+    buy: Vec<Level>,
+    sell: Vec<Level>,
+) -> OrderBook {
+    let mut book = OrderBook::new(inst.precision.clone(), depth_limit);
+    let delta = Delta {
+        event_time: 8,
+        ..Delta::new(inst.clone(), vec![], vec![], Id(200), Id(210), Id(199), 7)
+    };
+    assert!(book.apply(MDResponse::Delta(delta)).is_err());
+    let snapshot = Snapshot::new(inst.clone(), buy, sell, Id(205), 5);
+    assert!(book.apply(MDResponse::Snapshot(snapshot)).is_ok());
+    book
+}
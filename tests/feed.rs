@@ -0,0 +1,113 @@
+use futures_util::StreamExt;
+use market_data::common::{Id, Level};
+use market_data::mock::{MockExchange, Step};
+use market_data::{BookStream, BookUpdate, FeedBuilder};
+use std::time::Duration;
+
+async fn stream(mock: &MockExchange) -> BookStream {
+    FeedBuilder::new(mock.config())
+        .instruments(["BTCUSDT"])
+        .stream()
+        .await
+        .unwrap()
+}
+
+/// The first update matching the condition
+async fn wait_for<F: Fn(&BookUpdate) -> bool>(stream: &mut BookStream, cond: F) -> BookUpdate {
+    let wait = async {
+        loop {
+            let update = stream.next().await.expect("feed is running");
+            if cond(&update) {
+                return update;
+            }
+        }
+    };
+    tokio::time::timeout(Duration::from_secs(10), wait)
+        .await
+        .expect("update is applied in time")
+}
+
+fn sides(update: &BookUpdate) -> (Vec<String>, Vec<String>) {
+    let side = |lvls: &[Level]| {
+        lvls.iter()
+            .map(|lvl| format!("{}@{}", lvl.qty, lvl.price))
+            .collect()
+    };
+    (side(&update.bids), side(&update.asks))
+}
+
+#[tokio::test]
+async fn sync_with_duplicates_and_reordering() {
+    let mock = MockExchange::start(&["BTCUSDT"]).await;
+    mock.snapshot(
+        "BTCUSDT",
+        1005,
+        &[("10.0", "1"), ("9.9", "2")],
+        &[("10.2", "1")],
+    );
+    let dup = Step::depth("BTCUSDT", (1011, 1020), &[], &[("10.3", "1")]);
+    mock.connection(vec![
+        Step::depth("BTCUSDT", (1000, 1010), &[("10.0", "3")], &[]),
+        dup.clone(),
+        Step::Ping,
+        dup,
+        Step::depth("BTCUSDT", (1031, 1040), &[], &[("10.2", "0")]),
+        Step::depth("BTCUSDT", (1021, 1030), &[("9.9", "0")], &[]),
+        // trades are ignored until the book is built from the snapshot
+        Step::Sleep(Duration::from_millis(500)),
+        Step::trade("BTCUSDT", 1, ("10.3", "0.4"), 1041, false),
+    ]);
+    let mut stream = stream(&mock).await;
+
+    let update = wait_for(&mut stream, |u| u.last_update_id == Id(1040)).await;
+    assert_eq!(sides(&update), (vec!["3@10".into()], vec!["1@10.3".into()]));
+    let update = wait_for(&mut stream, |u| u.trade_adjusted).await;
+    assert_eq!(
+        sides(&update),
+        (vec!["3@10".into()], vec!["0.6@10.3".into()])
+    );
+    let received = mock.received();
+    assert_eq!(
+        received[0],
+        r#"{"method":"SUBSCRIBE","params":["btcusdt@depth","btcusdt@aggTrade"],"id":1}"#
+    );
+    assert!(received.contains(&"pong".to_string()));
+}
+
+#[tokio::test]
+async fn resync_after_gap_and_disconnect() {
+    let mock = MockExchange::start(&["BTCUSDT"]).await;
+    mock.snapshot("BTCUSDT", 1005, &[("10.0", "1")], &[("10.2", "1")]);
+    mock.snapshot("BTCUSDT", 1205, &[("10.0", "2")], &[("10.2", "2")]);
+    mock.snapshot("BTCUSDT", 1305, &[("10.0", "3")], &[("10.2", "3")]);
+    mock.connection(vec![
+        Step::depth("BTCUSDT", (1000, 1010), &[], &[]),
+        // too far to wait for the missed updates
+        Step::depth("BTCUSDT", (1200, 1210), &[], &[]),
+        Step::Sleep(Duration::from_millis(100)),
+        Step::Disconnect,
+    ]);
+    mock.connection(vec![
+        Step::depth("BTCUSDT", (1300, 1310), &[("10.1", "1")], &[]),
+        Step::depth(
+            "BTCUSDT",
+            (1311, 1320),
+            &[],
+            &[("10.2", "0"), ("10.4", "1")],
+        ),
+    ]);
+    let mut stream = stream(&mock).await;
+
+    let update = wait_for(&mut stream, |u| u.last_update_id == Id(1320)).await;
+    assert_eq!(
+        sides(&update),
+        (vec!["1@10.1".into(), "3@10".into()], vec!["1@10.4".into()])
+    );
+    assert_eq!(mock.connections(), 2);
+    let subscriptions = mock
+        .received()
+        .iter()
+        .filter(|r| r.contains("SUBSCRIBE"))
+        .count();
+    assert_eq!(subscriptions, 2);
+}
@@ -0,0 +1,129 @@
+use market_data::config::{CaptureConfig, MDConfig};
+use market_data::mock::{MockExchange, Step};
+use market_data::output::OutputFormat;
+use market_data::replay::{read_capture, Replay};
+use market_data::scheme::binance::Api;
+use market_data::sink::{CallbackSink, SinkFilter, SINK_QUEUE_SIZE};
+use market_data::structure::Exchange;
+use market_data::FeedBuilder;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::runtime::Builder;
+
+type Output = Arc<Mutex<Vec<String>>>;
+
+fn csv_sink(output: &Output) -> Box<CallbackSink<impl FnMut(&market_data::BookUpdate)>> {
+    let output = output.clone();
+    Box::new(CallbackSink::new(move |update| {
+        output
+            .lock()
+            .unwrap()
+            .push(OutputFormat::Csv.format(update))
+    }))
+}
+
+/// Output of the feed connected to a scripted exchange, raw messages are captured to `dir`.
+async fn live(dir: &str) -> (MDConfig, Vec<String>) {
+    let mock = MockExchange::start(&["BTCUSDT"]).await;
+    mock.snapshot("BTCUSDT", 1005, &[("10.0", "1")], &[("10.2", "1")]);
+    mock.snapshot("BTCUSDT", 1025, &[("10.0", "2")], &[("10.2", "2")]);
+    mock.connection(vec![
+        Step::depth("BTCUSDT", (1000, 1010), &[("10.0", "3")], &[]),
+        Step::Sleep(Duration::from_millis(200)),
+        // crosses the book, which is rebuilt from the next snapshot
+        Step::depth("BTCUSDT", (1011, 1020), &[("10.3", "1")], &[]),
+        Step::Sleep(Duration::from_millis(200)),
+        Step::depth("BTCUSDT", (1021, 1030), &[("10.1", "1")], &[]),
+        Step::depth(
+            "BTCUSDT",
+            (1031, 1040),
+            &[],
+            &[("10.2", "0"), ("10.4", "1")],
+        ),
+    ]);
+    // replay doesn't connect to the exchange, only its settings are used
+    let replay_cfg = mock.config();
+    let mut cfg = mock.config();
+    cfg.capture = Some(CaptureConfig {
+        dir: dir.into(),
+        max_bytes: None,
+        rotate_secs: None,
+    });
+
+    let output = Output::default();
+    let feed = FeedBuilder::new(cfg)
+        .instruments(["BTCUSDT"])
+        .sink(
+            "test",
+            csv_sink(&output),
+            SinkFilter::default(),
+            SINK_QUEUE_SIZE,
+        )
+        .start()
+        .await
+        .unwrap();
+    let done = async {
+        while !output.lock().unwrap().iter().any(|l| l.contains(",1040,")) {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    };
+    tokio::time::timeout(Duration::from_secs(10), done)
+        .await
+        .expect("book is rebuilt in time");
+    // the capture is written in background
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    drop(feed);
+    let output = output.lock().unwrap().clone();
+    (replay_cfg, output)
+}
+
+async fn replay(cfg: MDConfig, captures: &[String]) -> Vec<String> {
+    let records = read_capture(captures).unwrap();
+    let api = Api::new(cfg.get(Exchange::BINANCE).unwrap().clone()).unwrap();
+    let output = Output::default();
+    Replay::new(&api, cfg)
+        .sink(
+            "test",
+            csv_sink(&output),
+            SinkFilter::default(),
+            SINK_QUEUE_SIZE,
+        )
+        .run(records)
+        .await
+        .unwrap();
+    let output = output.lock().unwrap().clone();
+    output
+}
+
+#[test]
+fn replay_corruption_as_live() {
+    let dir = std::env::temp_dir().join(format!("market_data_replay_{}", std::process::id()));
+    let dir = dir.to_str().unwrap().to_string();
+    let _ = std::fs::remove_dir_all(&dir);
+    let (cfg, live) = Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap()
+        .block_on(live(&dir));
+    // the crossing bid is gone with the resync
+    assert_eq!(
+        live.last().unwrap(),
+        "BTCUSDT,BINANCE,1040,1040,10.1,1,10.4,1"
+    );
+
+    let captures: Vec<String> = std::fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path().to_str().unwrap().to_string())
+        .collect();
+    // the replay detects the corruption by itself
+    for capture in &captures {
+        assert!(!std::fs::read_to_string(capture).unwrap().contains("resync"));
+    }
+    let replayed = Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap()
+        .block_on(replay(cfg, &captures));
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(replayed, live);
+}